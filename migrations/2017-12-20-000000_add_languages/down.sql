@@ -0,0 +1,2 @@
+DROP TABLE users;
+ALTER TABLE chats DROP COLUMN language;
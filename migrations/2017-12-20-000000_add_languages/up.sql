@@ -0,0 +1,9 @@
+ALTER TABLE chats ADD COLUMN language VARCHAR;
+
+CREATE TABLE users (
+    id BIGINT PRIMARY KEY,
+    language VARCHAR,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+SELECT diesel_manage_updated_at('users');
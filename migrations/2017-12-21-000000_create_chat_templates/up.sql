@@ -0,0 +1,9 @@
+CREATE TABLE chat_templates (
+    chat_id BIGINT NOT NULL REFERENCES chats (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    body TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (chat_id, name)
+);
+
+SELECT diesel_manage_updated_at('chat_templates');
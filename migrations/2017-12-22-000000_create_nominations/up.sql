@@ -0,0 +1,23 @@
+CREATE TABLE nominations (
+    id SERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL REFERENCES chats (id) ON DELETE CASCADE,
+    nominator_id BIGINT NOT NULL,
+    nominee_id BIGINT,
+    reason VARCHAR NOT NULL DEFAULT '',
+    status VARCHAR NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+SELECT diesel_manage_updated_at('nominations');
+
+CREATE TABLE votes (
+    nomination_id INTEGER NOT NULL REFERENCES nominations (id)
+        ON DELETE CASCADE,
+    user_id BIGINT NOT NULL,
+    approve BOOLEAN NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (nomination_id, user_id)
+);
+
+SELECT diesel_manage_updated_at('votes');
@@ -0,0 +1 @@
+ALTER TABLE nominations DROP COLUMN inline_message_id;
@@ -0,0 +1 @@
+ALTER TABLE nominations ADD COLUMN inline_message_id VARCHAR;
@@ -0,0 +1 @@
+DROP INDEX nominations_inline_message_id_key;
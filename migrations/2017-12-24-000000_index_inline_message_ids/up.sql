@@ -0,0 +1,2 @@
+CREATE UNIQUE INDEX nominations_inline_message_id_key
+    ON nominations (inline_message_id);
@@ -0,0 +1,2 @@
+ALTER TABLE chats DROP COLUMN configured_by;
+ALTER TABLE chats DROP COLUMN active;
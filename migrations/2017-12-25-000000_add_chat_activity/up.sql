@@ -0,0 +1,2 @@
+ALTER TABLE chats ADD COLUMN active BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE chats ADD COLUMN configured_by BIGINT;
@@ -0,0 +1,4 @@
+ALTER TABLE chats DROP COLUMN notify_chat_id;
+ALTER TABLE chats DROP COLUMN welcome;
+ALTER TABLE chats DROP COLUMN nomination_ttl;
+ALTER TABLE chats DROP COLUMN approval_mode;
@@ -0,0 +1,4 @@
+ALTER TABLE chats ADD COLUMN approval_mode VARCHAR NOT NULL DEFAULT 'admin';
+ALTER TABLE chats ADD COLUMN nomination_ttl BIGINT;
+ALTER TABLE chats ADD COLUMN welcome TEXT;
+ALTER TABLE chats ADD COLUMN notify_chat_id BIGINT;
@@ -0,0 +1,24 @@
+CREATE TABLE broadcasts (
+    id SERIAL PRIMARY KEY,
+    body TEXT NOT NULL,
+    language VARCHAR,
+    approval_mode VARCHAR,
+    created_by BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    started_at TIMESTAMPTZ,
+    finished_at TIMESTAMPTZ
+);
+
+CREATE TABLE deliveries (
+    broadcast_id INTEGER NOT NULL REFERENCES broadcasts (id)
+        ON DELETE CASCADE,
+    chat_id BIGINT NOT NULL REFERENCES chats (id) ON DELETE CASCADE,
+    status VARCHAR NOT NULL DEFAULT 'pending',
+    error TEXT,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (broadcast_id, chat_id)
+);
+
+CREATE INDEX deliveries_status_idx ON deliveries (status);
+
+SELECT diesel_manage_updated_at('deliveries');
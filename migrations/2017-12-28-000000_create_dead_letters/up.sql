@@ -0,0 +1,14 @@
+CREATE TABLE dead_letters (
+    id SERIAL PRIMARY KEY,
+    update_id BIGINT NOT NULL,
+    payload TEXT NOT NULL,
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'failed',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX dead_letters_status_idx ON dead_letters (status);
+
+SELECT diesel_manage_updated_at('dead_letters');
@@ -0,0 +1 @@
+ALTER TABLE chats ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,387 @@
+use telebot::objects::Message;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The kinds of value a command can take as an argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// `@username` or a numeric user id.
+    User,
+
+    /// The sender of the message being replied to. Doesn't consume any text.
+    ReplyTarget,
+
+    Integer,
+
+    /// A duration like `90s`, `10m`, `1h30m`, `2d` or `1w`.
+    Duration,
+
+    /// Everything remaining in the message. Must be the last parameter.
+    Text,
+}
+
+/// One parameter in a command's signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Param {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserRef {
+    Id(i64),
+    Username(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    User(UserRef),
+    Integer(i64),
+    Duration(Duration),
+    Text(String),
+}
+
+/// Why a command's arguments couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    Missing(&'static str),
+    Invalid(&'static str),
+}
+
+impl ParseError {
+    pub fn param(&self) -> &'static str {
+        match *self {
+            ParseError::Missing(x) | ParseError::Invalid(x) => x,
+        }
+    }
+}
+
+/// Arguments parsed according to a command's parameters, looked up by name.
+#[derive(Debug, Default)]
+pub struct Args {
+    values: HashMap<&'static str, Value>,
+}
+
+impl Args {
+    pub fn user(&self, name: &str) -> Option<&UserRef> {
+        match self.values.get(name) {
+            Some(&Value::User(ref x)) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn integer(&self, name: &str) -> Option<i64> {
+        match self.values.get(name) {
+            Some(&Value::Integer(x)) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn duration(&self, name: &str) -> Option<Duration> {
+        match self.values.get(name) {
+            Some(&Value::Duration(x)) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn text(&self, name: &str) -> Option<&str> {
+        match self.values.get(name) {
+            Some(&Value::Text(ref x)) => Some(x.as_str()),
+            _ => None,
+        }
+    }
+}
+
+/// Generate a usage line like `/ban <user> [duration]` from a command's
+/// parameters.
+pub fn usage(command: &str, params: &[Param]) -> String {
+    let mut out = command.to_owned();
+
+    for param in params {
+        let text = match (param.kind, param.required) {
+            (Kind::ReplyTarget, _) => format!("(reply to {})", param.name),
+            (Kind::Text, true) => format!("<{}...>", param.name),
+            (Kind::Text, false) => format!("[{}...]", param.name),
+            (_, true) => format!("<{}>", param.name),
+            (_, false) => format!("[{}]", param.name),
+        };
+
+        out.push(' ');
+        out.push_str(&text);
+    }
+
+    out
+}
+
+fn parse_user(token: &str) -> Option<UserRef> {
+    if token.starts_with('@') && token.len() > 1 {
+        Some(UserRef::Username(token[1..].to_owned()))
+    } else {
+        token.parse().ok().map(UserRef::Id)
+    }
+}
+
+/// Parse a duration made of one or more `<number><unit>` parts, where the
+/// unit is one of `s`, `m`, `h`, `d` or `w`.
+pub fn parse_duration(token: &str) -> Option<Duration> {
+    let mut total: u64 = 0;
+    let mut number = String::new();
+
+    for c in token.chars() {
+        if c.is_digit(10) {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+
+        let value: u64 = number.parse().ok()?;
+        total = total.checked_add(value.checked_mul(unit)?)?;
+        number.clear();
+    }
+
+    if !number.is_empty() || total == 0 {
+        return None;
+    }
+
+    Some(Duration::from_secs(total))
+}
+
+/// Format a duration the way `parse_duration` reads it, like `1d2h3m`,
+/// dropping any fraction of a second.
+pub fn format_duration(duration: Duration) -> String {
+    let units = [
+        ('w', 7 * 24 * 60 * 60),
+        ('d', 24 * 60 * 60),
+        ('h', 60 * 60),
+        ('m', 60),
+        ('s', 1),
+    ];
+
+    let mut rest = duration.as_secs();
+    let mut out = String::new();
+
+    for &(unit, secs) in units.iter() {
+        if rest >= secs {
+            out.push_str(&format!("{}{}", rest / secs, unit));
+            rest %= secs;
+        }
+    }
+
+    if out.is_empty() {
+        out.push_str("0s");
+    }
+
+    out
+}
+
+/// Parse the text following a command (with the command itself already
+/// removed) according to `params`.
+pub fn parse(params: &[Param], msg: &Message) -> Result<Args, ParseError> {
+    let text = msg.text.as_ref().map(String::as_str).unwrap_or("").trim();
+    let mut rest = text;
+    let mut args = Args::default();
+
+    for param in params {
+        if param.kind == Kind::ReplyTarget {
+            let target = msg.reply_to_message
+                .as_ref()
+                .and_then(|x| x.from.as_ref())
+                .map(|x| UserRef::Id(x.id));
+
+            match target {
+                Some(x) => {
+                    args.values.insert(param.name, Value::User(x));
+                }
+                None if param.required => {
+                    return Err(ParseError::Missing(param.name))
+                }
+                None => (),
+            }
+
+            continue;
+        }
+
+        if rest.is_empty() {
+            if param.required {
+                return Err(ParseError::Missing(param.name));
+            }
+            continue;
+        }
+
+        let token = if param.kind == Kind::Text {
+            let token = rest;
+            rest = "";
+            token
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let token = &rest[..end];
+            rest = rest[end..].trim_left();
+            token
+        };
+
+        let value = match param.kind {
+            Kind::User => parse_user(token).map(Value::User),
+            Kind::Integer => token.parse().ok().map(Value::Integer),
+            Kind::Duration => parse_duration(token).map(Value::Duration),
+            Kind::Text => Some(Value::Text(token.to_owned())),
+            Kind::ReplyTarget => unreachable!(),
+        };
+
+        match value {
+            Some(x) => {
+                args.values.insert(param.name, x);
+            }
+            None => return Err(ParseError::Invalid(param.name)),
+        }
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    fn message(text: &str, reply_from: Option<i64>) -> Message {
+        let mut msg = json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": 1, "type": "private"},
+            "text": text,
+        });
+
+        if let Some(id) = reply_from {
+            msg["reply_to_message"] = json!({
+                "message_id": 2,
+                "date": 0,
+                "chat": {"id": 1, "type": "private"},
+                "from": {"id": id, "is_bot": false, "first_name": "Ann"},
+            });
+        }
+
+        serde_json::from_value(msg).unwrap()
+    }
+
+    const USER: Param = Param {
+        name: "user",
+        kind: Kind::User,
+        required: true,
+    };
+
+    const DURATION: Param = Param {
+        name: "duration",
+        kind: Kind::Duration,
+        required: false,
+    };
+
+    const REASON: Param = Param {
+        name: "reason",
+        kind: Kind::Text,
+        required: false,
+    };
+
+    #[test]
+    fn parses_durations() {
+        let secs = |x| parse_duration(x).map(|x| x.as_secs());
+
+        assert_eq!(secs("90s"), Some(90));
+        assert_eq!(secs("10m"), Some(600));
+        assert_eq!(secs("1h30m"), Some(5400));
+        assert_eq!(secs("2D"), Some(2 * 24 * 60 * 60));
+        assert_eq!(secs("1w"), Some(7 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for x in &["", "10", "m", "0s", "10x", "1h30", "-5m"] {
+            assert_eq!(parse_duration(x), None, "{}", x);
+        }
+
+        assert_eq!(parse_duration("99999999999999999999w"), None);
+    }
+
+    #[test]
+    fn formats_durations() {
+        let format = |x| format_duration(Duration::from_secs(x));
+
+        assert_eq!(format(0), "0s");
+        assert_eq!(format(90), "1m30s");
+        assert_eq!(format(24 * 60 * 60 + 2 * 60 * 60 + 3 * 60), "1d2h3m");
+        assert_eq!(format(8 * 24 * 60 * 60), "1w1d");
+    }
+
+    #[test]
+    fn formatted_durations_parse_back() {
+        for &x in &[1, 59, 61, 3600, 90061, 694861] {
+            let text = format_duration(Duration::from_secs(x));
+            assert_eq!(parse_duration(&text), Some(Duration::from_secs(x)));
+        }
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let params = [USER, DURATION, REASON];
+        let args = parse(&params, &message("@ann 1h  being rude ", None))
+            .unwrap();
+
+        assert_eq!(
+            args.user("user"),
+            Some(&UserRef::Username("ann".to_owned()))
+        );
+        assert_eq!(args.duration("duration"), Some(Duration::from_secs(3600)));
+        assert_eq!(args.text("reason"), Some("being rude"));
+    }
+
+    #[test]
+    fn skips_missing_optional_arguments() {
+        let args = parse(&[USER, DURATION], &message("42", None)).unwrap();
+
+        assert_eq!(args.user("user"), Some(&UserRef::Id(42)));
+        assert_eq!(args.duration("duration"), None);
+    }
+
+    #[test]
+    fn reports_missing_and_invalid_arguments() {
+        assert_eq!(
+            parse(&[USER], &message("", None)).unwrap_err(),
+            ParseError::Missing("user")
+        );
+        assert_eq!(
+            parse(&[USER, DURATION], &message("42 soon", None)).unwrap_err(),
+            ParseError::Invalid("duration")
+        );
+    }
+
+    #[test]
+    fn takes_the_reply_target() {
+        let target = Param {
+            name: "user",
+            kind: Kind::ReplyTarget,
+            required: true,
+        };
+
+        let args = parse(&[target, DURATION], &message("1d", Some(7))).unwrap();
+        assert_eq!(args.user("user"), Some(&UserRef::Id(7)));
+        assert_eq!(
+            args.duration("duration"),
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+
+        assert_eq!(
+            parse(&[target], &message("", None)).unwrap_err(),
+            ParseError::Missing("user")
+        );
+    }
+}
@@ -0,0 +1,528 @@
+use errors::*;
+use format::Rendered;
+use locale;
+use outbox::{Outbox, Priority};
+use pool::Pool;
+use models::{approval, delivery, Broadcast, NewBroadcast, NewDelivery};
+use settings;
+use templates::{self, Templates};
+
+use chrono::Utc;
+
+use diesel;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+
+use slog;
+
+use tokio_core::reactor::Handle;
+
+use futures::Future;
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Narrows down which chats a broadcast goes to. Given as `key=value` words
+/// at the start of `/broadcast`'s message, as in
+/// `/broadcast language=de approval=auto Hallo!`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Filter {
+    pub language: Option<String>,
+    pub approval_mode: Option<String>,
+}
+
+impl Filter {
+    /// Split any filters off the front of `text`, returning them along with
+    /// the rest of the text. Parsing stops at the first word that isn't a
+    /// filter.
+    pub fn parse(text: &str) -> (Filter, &str) {
+        let mut filter = Filter::default();
+        let mut rest = text.trim_left();
+
+        loop {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (word, after) = rest.split_at(end);
+
+            let mut parts = word.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("language"), Some(x)) => match locale::normalize(x) {
+                    Some(x) => filter.language = Some(x),
+                    None => break,
+                },
+                (Some("approval"), Some(x))
+                    if x == approval::ADMIN || x == approval::AUTO =>
+                {
+                    filter.approval_mode = Some(x.to_owned())
+                }
+                _ => break,
+            }
+
+            rest = after.trim_left();
+        }
+
+        (filter, rest)
+    }
+}
+
+/// The active chats matching `filter`, with their titles.
+fn audience(db: &PgConnection, filter: &Filter) -> Result<Vec<(i64, String)>> {
+    use schema::chats::dsl;
+
+    let mut query = dsl::chats
+        .filter(dsl::active.eq(true))
+        .filter(dsl::disabled.eq(false))
+        .select((dsl::id, dsl::title))
+        .into_boxed();
+
+    if let Some(ref x) = filter.language {
+        // Chats without a language use the default one.
+        query = if x == locale::DEFAULT {
+            query.filter(
+                dsl::language.eq(x.as_str()).or(dsl::language.is_null()),
+            )
+        } else {
+            query.filter(dsl::language.eq(x.as_str()))
+        };
+    }
+
+    if let Some(ref x) = filter.approval_mode {
+        query = query.filter(dsl::approval_mode.eq(x.as_str()));
+    }
+
+    Ok(query.order(dsl::id.asc()).load(db)?)
+}
+
+/// Render a broadcast for one chat.
+pub fn render(
+    tpl: &Templates,
+    body: &str,
+    group: &str,
+    username: Option<&str>,
+) -> Result<Rendered> {
+    tpl.render_source(body, &json!({
+        "group": group,
+        "username": username,
+    }))
+}
+
+/// Save a broadcast for the owner to confirm, returning it along with the
+/// chats it would currently go to. Nothing is sent until it is `start`ed.
+pub fn draft(
+    db: &PgConnection,
+    owner_id: i64,
+    body: &str,
+    filter: &Filter,
+) -> Result<(Broadcast, Vec<(i64, String)>)> {
+    use schema::broadcasts::dsl;
+
+    let new_broadcast = NewBroadcast {
+        body: body,
+        language: filter.language.as_ref().map(String::as_str),
+        approval_mode: filter.approval_mode.as_ref().map(String::as_str),
+        created_by: owner_id,
+    };
+
+    let broadcast = diesel::insert_into(dsl::broadcasts)
+        .values(&new_broadcast)
+        .get_result(db)?;
+
+    Ok((broadcast, audience(db, filter)?))
+}
+
+/// Queue a drafted broadcast for every chat it goes to, returning how many
+/// that is, or `None` if it was already started or cancelled.
+pub fn start(db: &PgConnection, broadcast_id: i32) -> Result<Option<usize>> {
+    use schema::broadcasts::dsl;
+    use schema::deliveries;
+
+    db.transaction(|| {
+        let broadcast: Option<Broadcast> = diesel::update(
+            dsl::broadcasts
+                .filter(dsl::id.eq(broadcast_id))
+                .filter(dsl::started_at.is_null()),
+        ).set(dsl::started_at.eq(Some(Utc::now())))
+            .get_result(db)
+            .optional()?;
+
+        let broadcast = match broadcast {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let filter = Filter {
+            language: broadcast.language,
+            approval_mode: broadcast.approval_mode,
+        };
+
+        let new_deliveries: Vec<NewDelivery> = audience(db, &filter)?
+            .into_iter()
+            .map(|(chat_id, _)| NewDelivery {
+                broadcast_id: broadcast_id,
+                chat_id: chat_id,
+            })
+            .collect();
+
+        if new_deliveries.is_empty() {
+            diesel::update(dsl::broadcasts.find(broadcast_id))
+                .set(dsl::finished_at.eq(Some(Utc::now())))
+                .execute(db)?;
+        } else {
+            diesel::insert_into(deliveries::table)
+                .values(&new_deliveries)
+                .on_conflict_do_nothing()
+                .execute(db)?;
+        }
+
+        Ok(Some(new_deliveries.len()))
+    })
+}
+
+/// Throw away a broadcast that hasn't been started, returning whether there
+/// was one.
+pub fn cancel(db: &PgConnection, broadcast_id: i32) -> Result<bool> {
+    use schema::broadcasts::dsl;
+
+    let deleted = diesel::delete(
+        dsl::broadcasts
+            .filter(dsl::id.eq(broadcast_id))
+            .filter(dsl::started_at.is_null()),
+    ).execute(db)?;
+
+    Ok(deleted > 0)
+}
+
+/// Give up on deliveries that were being sent when the bot last stopped,
+/// returning the broadcasts they belong to.
+///
+/// There's no way to know whether Telegram got them, and sending one twice
+/// is worse than not at all, so they're recorded as failed. Pending
+/// deliveries are picked up again as usual.
+pub fn recover(db: &PgConnection) -> Result<Vec<i32>> {
+    use schema::deliveries::dsl;
+
+    let interrupted = dsl::deliveries.filter(dsl::status.eq(delivery::SENDING));
+
+    db.transaction(|| {
+        let ids: Vec<i32> = interrupted
+            .select(dsl::broadcast_id)
+            .distinct()
+            .order(dsl::broadcast_id)
+            .load(db)?;
+
+        diesel::update(interrupted)
+            .set((
+                dsl::status.eq(delivery::FAILED),
+                dsl::error.eq("interrupted by a restart"),
+            ))
+            .execute(db)?;
+
+        Ok(ids)
+    })
+}
+
+/// How many deliveries are waiting to be sent.
+pub fn pending(db: &PgConnection) -> Result<i64> {
+    use schema::deliveries::dsl;
+
+    let open = vec![delivery::PENDING, delivery::SENDING];
+
+    Ok(dsl::deliveries
+        .filter(dsl::status.eq_any(open))
+        .count()
+        .get_result(db)?)
+}
+
+/// Claim a pending delivery, so no other attempt sends it. Returns `false` if
+/// it was already claimed.
+fn claim(db: &PgConnection, broadcast_id: i32, chat_id: i64) -> Result<bool> {
+    use schema::deliveries::dsl;
+
+    let claimed = diesel::update(
+        dsl::deliveries
+            .find((broadcast_id, chat_id))
+            .filter(dsl::status.eq(delivery::PENDING)),
+    ).set(dsl::status.eq(delivery::SENDING))
+        .execute(db)?;
+
+    Ok(claimed > 0)
+}
+
+fn record(
+    db: &PgConnection,
+    broadcast_id: i32,
+    chat_id: i64,
+    error: Option<String>,
+) -> Result<()> {
+    use schema::deliveries::dsl;
+
+    let state = match error {
+        Some(_) => delivery::FAILED,
+        None => delivery::SENT,
+    };
+
+    diesel::update(dsl::deliveries.find((broadcast_id, chat_id)))
+        .set((dsl::status.eq(state), dsl::error.eq(error)))
+        .execute(db)?;
+
+    Ok(())
+}
+
+/// Mark a broadcast finished once none of its deliveries are left, returning
+/// it along with how many were sent and how many failed. Returns `None` if it
+/// isn't finished, or was already marked.
+fn finish(
+    db: &PgConnection,
+    broadcast_id: i32,
+) -> Result<Option<(Broadcast, i64, i64)>> {
+    use schema::broadcasts::dsl;
+    use schema::deliveries;
+
+    let count = |state: Vec<&str>| -> Result<i64> {
+        Ok(deliveries::table
+            .filter(deliveries::broadcast_id.eq(broadcast_id))
+            .filter(deliveries::status.eq_any(state))
+            .count()
+            .get_result(db)?)
+    };
+
+    if count(vec![delivery::PENDING, delivery::SENDING])? > 0 {
+        return Ok(None);
+    }
+
+    let broadcast: Option<Broadcast> = diesel::update(
+        dsl::broadcasts
+            .filter(dsl::id.eq(broadcast_id))
+            .filter(dsl::finished_at.is_null()),
+    ).set(dsl::finished_at.eq(Some(Utc::now())))
+        .get_result(db)
+        .optional()?;
+
+    match broadcast {
+        Some(x) => Ok(Some((
+            x,
+            count(vec![delivery::SENT])?,
+            count(vec![delivery::FAILED])?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Tell the owner who started a broadcast how it went, if it's done.
+fn report(
+    logger: &slog::Logger,
+    outbox: &Outbox,
+    handle: &Handle,
+    db: &PgConnection,
+    tpl: &Templates,
+    broadcast_id: i32,
+) {
+    let (broadcast, sent, failed) = match finish(db, broadcast_id) {
+        Ok(Some(x)) => x,
+        Ok(None) => return,
+        Err(e) => {
+            error!(logger, "unable to finish broadcast: {}", e);
+            return;
+        }
+    };
+
+    info!(logger, "Broadcast {} finished", broadcast_id;
+          "sent" => sent, "failed" => failed);
+
+    let text = tpl.render_in(
+        templates::REPLY_BROADCAST_DONE,
+        locale::DEFAULT,
+        &json!({ "sent": sent, "failed": failed }),
+    ).unwrap_or_else(|e| tpl.fallback(e));
+
+    let logger = logger.clone();
+    handle.spawn(text.send(outbox, broadcast.created_by).map_err(move |e| {
+        warn!(logger, "unable to report broadcast: {}", e);
+    }));
+}
+
+/// Seconds between batches of deliveries.
+pub const TICK_SECS: u64 = 1;
+
+/// Sends queued broadcasts a batch at a time, staying within the configured
+/// rate limits. Progress is kept in the database, so a restart carries on
+/// where it left off.
+pub struct Broadcaster {
+    logger: slog::Logger,
+    outbox: Outbox,
+    settings: settings::Broadcasts,
+
+    /// When each chat was last sent a broadcast.
+    recent: RefCell<HashMap<i64, Instant>>,
+}
+
+impl Broadcaster {
+    pub fn new(
+        logger: slog::Logger,
+        outbox: Outbox,
+        settings: settings::Broadcasts,
+    ) -> Broadcaster {
+        Broadcaster {
+            logger: logger,
+            outbox: outbox,
+            settings: settings,
+            recent: RefCell::from(HashMap::new()),
+        }
+    }
+
+    /// Whether enough time has passed since `chat_id` was last sent a
+    /// broadcast.
+    fn ready(&self, chat_id: i64) -> bool {
+        let gap = Duration::from_secs(self.settings.chat_interval);
+        let mut recent = self.recent.borrow_mut();
+
+        recent.retain(|_, sent| sent.elapsed() < gap);
+
+        !recent.contains_key(&chat_id)
+    }
+
+    /// Finish broadcasts whose last deliveries were interrupted (see
+    /// `recover`), and tell their owners how they went.
+    pub fn settle(
+        &self,
+        handle: &Handle,
+        db: &PgConnection,
+        tpl: &Templates,
+        broadcast_ids: &[i32],
+    ) {
+        for &id in broadcast_ids {
+            report(&self.logger, &self.outbox, handle, db, tpl, id);
+        }
+    }
+
+    /// Start sending the next batch of deliveries, at most `rate` of them,
+    /// and no more than the outbox has room for.
+    pub fn tick(
+        &self,
+        handle: &Handle,
+        pool: &Pool,
+        tpl: &Rc<Templates>,
+        username: Option<&str>,
+    ) -> Result<()> {
+        use schema::{broadcasts, chats, deliveries};
+
+        // A claimed delivery waiting in the outbox is lost if the bot stops,
+        // so only claim what can be sent soon.
+        let room = self.settings.max_queued.saturating_sub(self.outbox.len());
+        let rate = cmp::min(i64::from(self.settings.rate), room as i64);
+        if rate == 0 {
+            return Ok(());
+        }
+
+        let conn = pool.get()?;
+        let db = &*conn;
+
+        // Fetch more than can be sent, since some chats may have had a
+        // broadcast too recently.
+        let queued: Vec<(i32, i64, String, String)> = deliveries::table
+            .inner_join(broadcasts::table)
+            .inner_join(chats::table)
+            .filter(deliveries::status.eq(delivery::PENDING))
+            .select((
+                deliveries::broadcast_id,
+                deliveries::chat_id,
+                broadcasts::body,
+                chats::title,
+            ))
+            .order((deliveries::broadcast_id, deliveries::chat_id))
+            .limit(rate * 4)
+            .load(db)?;
+
+        let mut sent = 0;
+        for (broadcast_id, chat_id, body, group) in queued {
+            if sent >= rate {
+                break;
+            }
+
+            if !self.ready(chat_id) || !claim(db, broadcast_id, chat_id)? {
+                continue;
+            }
+
+            self.recent.borrow_mut().insert(chat_id, Instant::now());
+            sent += 1;
+
+            let text = match render(tpl, &body, &group, username) {
+                Ok(x) => x,
+                Err(e) => {
+                    record(db, broadcast_id, chat_id, Some(e.to_string()))?;
+                    report(&self.logger, &self.outbox, handle, db, tpl,
+                           broadcast_id);
+                    continue;
+                }
+            };
+
+            let logger = self.logger.clone();
+            let outbox = self.outbox.clone();
+            let handle2 = handle.clone();
+            let pool = pool.clone();
+            let tpl = tpl.clone();
+            let queued = text.queue(&self.outbox, chat_id, Priority::Bulk);
+            handle.spawn(queued.then(move |result| {
+                let error = match result {
+                    Ok(()) => None,
+                    Err(e) => {
+                        warn!(logger, "unable to broadcast to {}: {}",
+                              chat_id, e);
+                        Some(e.to_string())
+                    }
+                };
+
+                let db = match pool.get() {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(logger, "unable to record delivery: {}", e);
+                        return Ok(());
+                    }
+                };
+
+                if let Err(e) = record(&db, broadcast_id, chat_id, error) {
+                    error!(logger, "unable to record delivery: {}", e);
+                }
+
+                report(&logger, &outbox, &handle2, &db, &tpl, broadcast_id);
+                Ok(())
+            }));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_filters() {
+        let (filter, rest) = Filter::parse(" language=de_DE approval=auto Hi!");
+
+        assert_eq!(filter.language, Some("de-de".to_owned()));
+        assert_eq!(filter.approval_mode, Some(approval::AUTO.to_owned()));
+        assert_eq!(rest, "Hi!");
+    }
+
+    #[test]
+    fn parses_text_without_filters() {
+        let (filter, rest) = Filter::parse("Hello everyone");
+
+        assert_eq!(filter, Filter::default());
+        assert_eq!(rest, "Hello everyone");
+    }
+
+    #[test]
+    fn stops_at_the_first_word_that_is_not_a_filter() {
+        let (filter, rest) =
+            Filter::parse("approval=admin language=?? approval=auto");
+
+        assert_eq!(filter.approval_mode, Some(approval::ADMIN.to_owned()));
+        assert_eq!(filter.language, None);
+        assert_eq!(rest, "language=?? approval=auto");
+    }
+}
@@ -0,0 +1,1280 @@
+use broadcast;
+use entice::Context;
+use errors::*;
+use format::{self, Rendered};
+use locale;
+use models::{approval, status, Chat, NewVote, Nomination};
+use nominations;
+use membership;
+use outbox::{Outbox, Priority};
+use permissions::{Level, Permissions};
+use templates::{self, Templates};
+
+use diesel;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+
+use serde_json::{self, Value};
+
+use slog;
+
+use telebot::objects::{CallbackQuery, InlineKeyboardButton,
+                       InlineKeyboardMarkup, User};
+use telebot::functions::*;
+
+use futures::{future, Future};
+
+use std::rc::Rc;
+
+type Dispatched = Box<Future<Item = (), Error = Error>>;
+
+/// The accepted nomination the group was asked to approve, or `None` if it
+/// wasn't open anymore.
+type Requested = Box<Future<Item = Option<Nomination>, Error = Error>>;
+
+/// What pressing a button does, encoded into its `callback_data`.
+///
+/// Telegram limits callback data to 64 bytes, so actions are encoded as short
+/// colon-separated strings like `vote:12:y` rather than JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// The nominee accepting a nomination sent through an inline query.
+    Accept { chat_id: i64, nominator_id: i64 },
+
+    /// A group member voting on an accepted nomination.
+    Vote { nomination_id: i32, approve: bool },
+
+    /// A group administrator approving an accepted nomination.
+    Approve { nomination_id: i32 },
+
+    /// A group administrator rejecting an accepted nomination.
+    Reject { nomination_id: i32 },
+
+    /// Moving to another page of a group's settings menu.
+    Menu { chat_id: i64, page: Page },
+
+    /// The bot's owner sending or cancelling a drafted broadcast.
+    Broadcast { broadcast_id: i32, send: bool },
+}
+
+impl Action {
+    pub fn encode(&self) -> String {
+        match *self {
+            Action::Accept {
+                chat_id,
+                nominator_id,
+            } => format!("acc:{}:{}", chat_id, nominator_id),
+            Action::Vote {
+                nomination_id,
+                approve,
+            } => format!(
+                "vote:{}:{}",
+                nomination_id,
+                if approve { "y" } else { "n" }
+            ),
+            Action::Approve { nomination_id } => {
+                format!("ok:{}", nomination_id)
+            }
+            Action::Reject { nomination_id } => {
+                format!("no:{}", nomination_id)
+            }
+            Action::Menu { chat_id, page } => {
+                format!("menu:{}:{}", chat_id, page.code())
+            }
+            Action::Broadcast { broadcast_id, send } => format!(
+                "bc:{}:{}",
+                broadcast_id,
+                if send { "y" } else { "n" }
+            ),
+        }
+    }
+
+    /// Decode callback data, returning `None` for anything this version of
+    /// the bot didn't produce.
+    pub fn decode(data: &str) -> Option<Action> {
+        let mut parts = data.split(':');
+
+        let action = match parts.next()? {
+            "acc" => Action::Accept {
+                chat_id: parts.next()?.parse().ok()?,
+                nominator_id: parts.next()?.parse().ok()?,
+            },
+            "vote" => Action::Vote {
+                nomination_id: parts.next()?.parse().ok()?,
+                approve: match parts.next()? {
+                    "y" => true,
+                    "n" => false,
+                    _ => return None,
+                },
+            },
+            "ok" => Action::Approve {
+                nomination_id: parts.next()?.parse().ok()?,
+            },
+            "no" => Action::Reject {
+                nomination_id: parts.next()?.parse().ok()?,
+            },
+            "menu" => Action::Menu {
+                chat_id: parts.next()?.parse().ok()?,
+                page: Page::from_code(parts.next()?)?,
+            },
+            "bc" => Action::Broadcast {
+                broadcast_id: parts.next()?.parse().ok()?,
+                send: match parts.next()? {
+                    "y" => true,
+                    "n" => false,
+                    _ => return None,
+                },
+            },
+            _ => return None,
+        };
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(action)
+    }
+
+    /// A button that performs this action.
+    pub fn button(&self, text: String) -> InlineKeyboardButton {
+        InlineKeyboardButton::new(text).callback_data(self.encode())
+    }
+}
+
+/// A page of the settings menu opened by `/settings`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Page {
+    Main,
+    Language,
+    Templates,
+
+    /// Not really a page: removes the menu's buttons.
+    Close,
+}
+
+impl Page {
+    fn code(&self) -> &'static str {
+        match *self {
+            Page::Main => "main",
+            Page::Language => "lang",
+            Page::Templates => "tpl",
+            Page::Close => "close",
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Page> {
+        match code {
+            "main" => Some(Page::Main),
+            "lang" => Some(Page::Language),
+            "tpl" => Some(Page::Templates),
+            "close" => Some(Page::Close),
+            _ => None,
+        }
+    }
+}
+
+/// Render a page of a group's settings menu, along with the buttons to
+/// navigate from it.
+pub fn menu(
+    tpl: &Templates,
+    db: &PgConnection,
+    chat_id: i64,
+    language: &str,
+    page: Page,
+) -> Result<(Rendered, Option<InlineKeyboardMarkup>)> {
+    let label = |name: &str| -> Result<String> {
+        Ok(tpl.render_in(name, language, &json!({}))?.text)
+    };
+    let link = |name: &str, page: Page| -> Result<InlineKeyboardButton> {
+        Ok(Action::Menu {
+            chat_id: chat_id,
+            page: page,
+        }.button(label(name)?))
+    };
+
+    let (text, buttons) = match page {
+        Page::Main => {
+            use schema::chats::dsl;
+
+            let group: Option<String> = dsl::chats
+                .find(chat_id)
+                .select(dsl::title)
+                .first(db)
+                .optional()?;
+
+            let text = tpl.render_in(
+                templates::MENU_MAIN,
+                language,
+                &json!({ "group": group }),
+            )?;
+
+            (text, vec![
+                vec![
+                    link(templates::BUTTON_MENU_LANGUAGE, Page::Language)?,
+                    link(templates::BUTTON_MENU_TEMPLATES, Page::Templates)?,
+                ],
+                vec![link(templates::BUTTON_MENU_CLOSE, Page::Close)?],
+            ])
+        }
+        Page::Language => {
+            use schema::chats::dsl;
+
+            let current: Option<Option<String>> = dsl::chats
+                .find(chat_id)
+                .select(dsl::language)
+                .first(db)
+                .optional()?;
+
+            let text = tpl.render_in(
+                templates::MENU_LANGUAGE,
+                language,
+                &json!({ "language": current.and_then(|x| x) }),
+            )?;
+
+            (text, vec![vec![link(templates::BUTTON_MENU_BACK, Page::Main)?]])
+        }
+        Page::Templates => {
+            use schema::chat_templates::dsl;
+
+            let mut customized: Vec<String> = dsl::chat_templates
+                .filter(dsl::chat_id.eq(chat_id))
+                .select(dsl::name)
+                .load(db)?;
+            customized.sort();
+
+            let text = tpl.render_in(
+                templates::MENU_TEMPLATES,
+                language,
+                &json!({ "customized": customized }),
+            )?;
+
+            (text, vec![vec![link(templates::BUTTON_MENU_BACK, Page::Main)?]])
+        }
+        Page::Close => {
+            let text =
+                tpl.render_in(templates::MENU_CLOSED, language, &json!({}))?;
+            return Ok((text, None));
+        }
+    };
+
+    Ok((text, Some(InlineKeyboardMarkup::new(buttons))))
+}
+
+/// Answer a callback query with a short notification, or with an alert the
+/// user has to dismiss if `alert` is set.
+fn answer(
+    outbox: &Outbox,
+    tpl: &Templates,
+    query_id: String,
+    name: &str,
+    language: &str,
+    data: &Value,
+    alert: bool,
+) -> Dispatched {
+    let text = tpl.render_in(name, language, data)
+        .unwrap_or_else(|e| tpl.fallback(e));
+
+    outbox.push(None, Priority::Interactive, move |tg| {
+        Box::from(
+            tg.answer_callback_query(query_id.clone())
+                .text(text.text.clone())
+                .show_alert(alert)
+                .send()
+                .map(|_| ()),
+        )
+    })
+}
+
+/// Answer a callback query by opening `url`, such as a link to start a
+/// private chat with the bot.
+fn open(outbox: &Outbox, query_id: String, url: String) -> Dispatched {
+    outbox.push(None, Priority::Interactive, move |tg| {
+        Box::from(
+            tg.answer_callback_query(query_id.clone())
+                .url(url.clone())
+                .send()
+                .map(|_| ()),
+        )
+    })
+}
+
+/// Answer a callback query without showing anything.
+fn acknowledge(outbox: &Outbox, query_id: String) -> Dispatched {
+    outbox.push(None, Priority::Interactive, move |tg| {
+        Box::from(
+            tg.answer_callback_query(query_id.clone())
+                .send()
+                .map(|_| ()),
+        )
+    })
+}
+
+/// Tell the user they aren't allowed to press a button.
+fn forbidden(
+    outbox: &Outbox,
+    tpl: &Templates,
+    query_id: String,
+    language: &str,
+    level: Level,
+) -> Dispatched {
+    let mut data = json!({});
+    data[level.name()] = json!(true);
+
+    answer(
+        outbox,
+        tpl,
+        query_id,
+        templates::REPLY_FORBIDDEN,
+        language,
+        &data,
+        true,
+    )
+}
+
+/// Remove the buttons from a message, leaving its text alone.
+fn remove_keyboard(
+    outbox: &Outbox,
+    chat_id: i64,
+    message_id: i64,
+) -> Dispatched {
+    let body = json!({
+        "chat_id": chat_id,
+        "message_id": message_id,
+    }).to_string();
+
+    outbox.push(Some(chat_id), Priority::Interactive, move |tg| {
+        Box::from(
+            tg.inner
+                .fetch_json("editMessageReplyMarkup", &body)
+                .map(|_| ()),
+        )
+    })
+}
+
+/// Update a nomination's inline message to match its status, logging rather
+/// than failing if it can't be edited (for example, because it was deleted).
+fn refresh(
+    logger: &slog::Logger,
+    outbox: &Outbox,
+    tpl: &Templates,
+    db: &PgConnection,
+    nomination: &Nomination,
+) -> Dispatched {
+    let logger = logger.clone();
+    let id = nomination.id;
+
+    Box::from(nominations::refresh(outbox, tpl, db, nomination).or_else(
+        move |e| {
+            warn!(logger, "unable to edit nomination {}: {}", id, e);
+            Ok(())
+        },
+    ))
+}
+
+/// Move an accepted nomination to `new_status`, returning `None` if it isn't
+/// accepted anymore. Only moving on from `accepted` means two administrators
+/// pressing at once can't both decide.
+fn conclude(
+    db: &PgConnection,
+    id: i32,
+    new_status: &str,
+) -> Result<Option<Nomination>> {
+    use schema::nominations::dsl;
+
+    Ok(diesel::update(
+        dsl::nominations
+            .filter(dsl::id.eq(id))
+            .filter(dsl::status.eq(status::ACCEPTED)),
+    ).set(dsl::status.eq(new_status))
+        .get_result(db)
+        .optional()?)
+}
+
+/// Show the outcome of a nomination in its inline message, and let the
+/// nominee know.
+fn announce(
+    logger: &slog::Logger,
+    outbox: &Outbox,
+    tpl: &Rc<Templates>,
+    db: &PgConnection,
+    nomination: &Nomination,
+    approve: bool,
+) -> Dispatched {
+    let edited = refresh(logger, outbox, tpl, db, nomination);
+
+    let logger = logger.clone();
+    let notified = notify(outbox, tpl, db, nomination, approve)
+        .or_else(move |e| {
+            // The nominee may never have started a chat with us.
+            warn!(logger, "unable to notify nominee: {}", e);
+            Ok(())
+        });
+
+    Box::from(edited.join(notified).map(|_| ()))
+}
+
+/// The part of a `ChatInviteLink` the bot needs.
+#[derive(Deserialize)]
+struct InviteLink {
+    invite_link: String,
+}
+
+/// Send the nominee an invite link, or let them know they weren't
+/// approved. Each nominee gets their own link, which stops working once
+/// they've joined, so it can't be passed on to anyone else.
+fn notify(
+    outbox: &Outbox,
+    tpl: &Rc<Templates>,
+    db: &PgConnection,
+    nomination: &Nomination,
+    approve: bool,
+) -> Dispatched {
+    let nominee = match nomination.nominee_id {
+        Some(x) => x,
+        None => return Box::from(future::ok(())),
+    };
+
+    let chat_id = nomination.chat_id;
+    let chat = {
+        use schema::chats::dsl::*;
+        chats
+            .find(chat_id)
+            .select((title, welcome))
+            .first::<(String, Option<String>)>(db)
+    };
+    let (group, welcome) = match chat {
+        Ok(x) => x,
+        Err(e) => return Box::from(future::err(e.into())),
+    };
+
+    let language = locale::resolve(db, None, Some(chat_id))
+        .unwrap_or_else(|_| locale::DEFAULT.to_owned());
+
+    if !approve {
+        return Box::from(
+            tpl.render_in(
+                templates::REPLY_NOMINATION_REJECTED,
+                &language,
+                &json!({ "group": group }),
+            ).unwrap_or_else(|e| tpl.fallback(e))
+                .send(outbox, nominee),
+        );
+    }
+
+    let body = json!({ "chat_id": chat_id, "member_limit": 1 }).to_string();
+    let link = outbox.call(None, Priority::Interactive, move |tg| {
+        Box::from(tg.inner.fetch_json("createChatInviteLink", &body))
+    });
+
+    let outbox = outbox.clone();
+    let tpl = tpl.clone();
+
+    Box::from(
+        link.and_then(|x| Ok(serde_json::from_str::<InviteLink>(&x)?))
+            .and_then(move |link| {
+                tpl.render_in(
+                    templates::REPLY_INVITE,
+                    &language,
+                    &json!({
+                        "group": group,
+                        "link": link.invite_link,
+                        "welcome": welcome,
+                    }),
+                ).unwrap_or_else(|e| tpl.fallback(e))
+                    .send(&outbox, nominee)
+            }),
+    )
+}
+
+fn find_nomination(db: &PgConnection, id: i32) -> Result<Option<Nomination>> {
+    use schema::nominations::dsl::nominations;
+
+    Ok(nominations.find(id).first(db).optional()?)
+}
+
+fn count_votes(db: &PgConnection, id: i32) -> Result<(usize, usize)> {
+    use schema::votes::dsl::*;
+
+    let cast: Vec<bool> = votes
+        .filter(nomination_id.eq(id))
+        .select(approve)
+        .load(db)?;
+
+    let yes = cast.iter().filter(|&&x| x).count();
+    Ok((yes, cast.len() - yes))
+}
+
+/// Routes button presses to the handler for the action they encode.
+pub struct Callbacks {
+    logger: slog::Logger,
+    perms: Rc<Permissions>,
+}
+
+impl Callbacks {
+    pub fn new(
+        logger: slog::Logger,
+        perms: Rc<Permissions>,
+    ) -> Callbacks {
+        Callbacks {
+            logger: logger,
+            perms: perms,
+        }
+    }
+
+    /// Handle a button press. Buttons from older versions of the bot, or
+    /// whose data can't be decoded, get a polite alert instead of an error.
+    pub fn dispatch(&self, ctx: &Context, query: CallbackQuery) -> Dispatched {
+        let chat_id = query.message.as_ref().map(|x| x.chat.id);
+        let language = ctx.db
+            .get()
+            .and_then(|db| locale::resolve(&db, Some(&query.from), chat_id))
+            .unwrap_or_else(|_| locale::DEFAULT.to_owned());
+
+        let action = query.data.as_ref().and_then(|x| Action::decode(x));
+
+        let action = match action {
+            Some(x) => x,
+            None => {
+                debug!(self.logger, "Unknown callback data: {:?}", query.data);
+                return answer(
+                    &ctx.outbox,
+                    &ctx.templates,
+                    query.id,
+                    templates::ANSWER_UNKNOWN,
+                    &language,
+                    &json!({}),
+                    true,
+                );
+            }
+        };
+
+        debug!(self.logger, "Callback {:?} from {}", action, query.from.id);
+
+        match action {
+            Action::Accept {
+                chat_id,
+                nominator_id,
+            } => self.accept(ctx, query, language, chat_id, nominator_id),
+            Action::Vote {
+                nomination_id,
+                approve,
+            } => self.vote(ctx, query, language, nomination_id, approve),
+            Action::Approve { nomination_id } => {
+                self.decide(ctx, query, language, nomination_id, true)
+            }
+            Action::Reject { nomination_id } => {
+                self.decide(ctx, query, language, nomination_id, false)
+            }
+            Action::Menu { chat_id, page } => {
+                self.menu(ctx, query, language, chat_id, page)
+            }
+            Action::Broadcast { broadcast_id, send } => {
+                self.broadcast(ctx, query, language, broadcast_id, send)
+            }
+        }
+    }
+
+    fn expired(
+        &self,
+        ctx: &Context,
+        query: CallbackQuery,
+        language: &str,
+    ) -> Dispatched {
+        answer(
+            &ctx.outbox,
+            &ctx.templates,
+            query.id,
+            templates::ANSWER_EXPIRED,
+            language,
+            &json!({}),
+            true,
+        )
+    }
+
+    fn paused(
+        &self,
+        ctx: &Context,
+        query: CallbackQuery,
+        language: &str,
+    ) -> Dispatched {
+        answer(
+            &ctx.outbox,
+            &ctx.templates,
+            query.id,
+            templates::ANSWER_PAUSED,
+            language,
+            &json!({}),
+            true,
+        )
+    }
+
+    /// Record the nominee's acceptance, ask the group to approve it, and open
+    /// a private chat with the bot so the invite can be delivered later.
+    ///
+    /// The nominator is looked up before anything is recorded, and the
+    /// acceptance is undone if the group can't be asked, so a failure leaves
+    /// the nomination open to be accepted again. The button press is answered
+    /// either way.
+    fn accept(
+        &self,
+        ctx: &Context,
+        query: CallbackQuery,
+        language: String,
+        chat_id: i64,
+        nominator_id: i64,
+    ) -> Dispatched {
+        if query.from.id == nominator_id {
+            return answer(
+                &ctx.outbox,
+                &ctx.templates,
+                query.id,
+                templates::ANSWER_OWN_NOMINATION,
+                &language,
+                &json!({}),
+                true,
+            );
+        }
+
+        let db = match ctx.db.get() {
+            Ok(x) => x,
+            Err(e) => return Box::from(future::err(e)),
+        };
+
+        let chat = {
+            use schema::chats::dsl::*;
+            chats.find(chat_id).first::<Chat>(&*db).optional()
+        };
+
+        let chat = match chat {
+            Ok(Some(ref x)) if !x.active || x.disabled => {
+                return self.paused(ctx, query, &language)
+            }
+            Ok(Some(x)) => x,
+            Ok(None) => return self.expired(ctx, query, &language),
+            Err(e) => return Box::from(future::err(e.into())),
+        };
+
+        let url = format!(
+            "t.me/{}?start=nomination",
+            ctx.user.username.as_ref().map(String::as_str).unwrap_or("")
+        );
+
+        if chat.approval_mode == approval::AUTO {
+            let accepted = nominations::accept(
+                &db,
+                chat_id,
+                nominator_id,
+                query.from.id,
+                query.inline_message_id.as_ref().map(String::as_str),
+            );
+
+            let nomination = match accepted {
+                Ok(Some(x)) => x,
+                Ok(None) => return self.expired(ctx, query, &language),
+                Err(e) => return Box::from(future::err(e)),
+            };
+
+            info!(self.logger, "Nomination {} accepted", nomination.id;
+                  "chat_id" => chat_id, "nominee_id" => query.from.id);
+
+            return self.approve_now(ctx, query.id, url, nomination);
+        }
+
+        drop(db);
+
+        let group = chat.title;
+        let target = chat.notify_chat_id.unwrap_or(chat_id);
+        let pool = ctx.db.clone();
+        let tpl = ctx.templates.clone();
+        let outbox = ctx.outbox.clone();
+        let logger = self.logger.clone();
+        let nominee = query.from;
+        let inline_message_id = query.inline_message_id;
+
+        let member = ctx.outbox.call(None, Priority::Interactive, move |tg| {
+            Box::from(
+                tg.get_chat_member(chat_id, nominator_id)
+                    .send()
+                    .map(|x| x.1),
+            )
+        });
+
+        let requested = member.and_then(move |member| {
+            let db = match pool.get() {
+                Ok(x) => x,
+                Err(e) => return Box::from(future::err(e)) as Requested,
+            };
+
+            let accepted = nominations::accept(
+                &db,
+                chat_id,
+                nominator_id,
+                nominee.id,
+                inline_message_id.as_ref().map(String::as_str),
+            );
+
+            let nomination = match accepted {
+                Ok(Some(x)) => x,
+                Ok(None) => return Box::from(future::ok(None)),
+                Err(e) => return Box::from(future::err(e)),
+            };
+
+            info!(logger, "Nomination {} accepted", nomination.id;
+                  "chat_id" => chat_id, "nominee_id" => nominee.id);
+
+            let request = Self::approval_request(
+                &tpl,
+                &db,
+                chat_id,
+                nomination.id,
+                &group,
+                &nomination.reason,
+                &member.user,
+                &nominee,
+            );
+
+            let sent: Dispatched = match request {
+                Ok((text, keyboard)) => {
+                    text.send_with_keyboard(&outbox, target, keyboard)
+                }
+                Err(e) => Box::from(future::err(e)),
+            };
+
+            Box::from(sent.then(move |result| match result {
+                Ok(()) => Ok(Some(nomination)),
+                Err(e) => {
+                    let id = nomination.id;
+                    let reopened = pool.get()
+                        .and_then(|db| nominations::reopen(&db, id));
+
+                    if let Err(x) = reopened {
+                        warn!(logger, "unable to reopen nomination {}: {}",
+                              id, x);
+                    }
+
+                    Err(e)
+                }
+            }))
+        });
+
+        let logger = self.logger.clone();
+        let pool = ctx.db.clone();
+        let tpl = ctx.templates.clone();
+        let outbox = ctx.outbox.clone();
+        let query_id = query.id;
+        Box::from(requested.then(move |result| -> Dispatched {
+            let nomination = match result {
+                Ok(Some(x)) => x,
+                Ok(None) => {
+                    return answer(
+                        &outbox,
+                        &tpl,
+                        query_id,
+                        templates::ANSWER_EXPIRED,
+                        &language,
+                        &json!({}),
+                        true,
+                    )
+                }
+                Err(e) => {
+                    let answered = answer(
+                        &outbox,
+                        &tpl,
+                        query_id,
+                        templates::ANSWER_FAILED,
+                        &language,
+                        &json!({}),
+                        true,
+                    );
+
+                    return Box::from(answered.then(move |_| Err(e)));
+                }
+            };
+
+            let answered = open(&outbox, query_id, url);
+
+            // The inline message only shows the status, so it's updated
+            // after answering, and can't fail the update.
+            Box::from(answered.and_then(move |_| match pool.get() {
+                Ok(db) => refresh(&logger, &outbox, &tpl, &db, &nomination),
+                Err(e) => {
+                    warn!(logger, "unable to edit nomination {}: {}",
+                          nomination.id, e);
+                    Box::from(future::ok(()))
+                }
+            }))
+        }))
+    }
+
+    /// Approve a nomination as soon as it's accepted, for groups that don't
+    /// review them.
+    fn approve_now(
+        &self,
+        ctx: &Context,
+        query_id: String,
+        url: String,
+        nomination: Nomination,
+    ) -> Dispatched {
+        let db = match ctx.db.get() {
+            Ok(x) => x,
+            Err(e) => return Box::from(future::err(e)),
+        };
+
+        let approved = match conclude(&db, nomination.id, status::APPROVED) {
+            Ok(Some(x)) => x,
+            Ok(None) => return Box::from(future::ok(())),
+            Err(e) => return Box::from(future::err(e)),
+        };
+
+        info!(self.logger, "Nomination {} approved automatically",
+              approved.id);
+
+        let answered = open(&ctx.outbox, query_id, url);
+
+        let announced = announce(
+            &self.logger,
+            &ctx.outbox,
+            &ctx.templates,
+            &db,
+            &approved,
+            true,
+        );
+
+        Box::from(answered.join(announced).map(|_| ()))
+    }
+
+    /// The message asking a group to vote on and approve a nomination.
+    fn approval_request(
+        tpl: &Templates,
+        db: &PgConnection,
+        chat_id: i64,
+        nomination_id: i32,
+        group: &str,
+        reason: &str,
+        nominator: &User,
+        nominee: &User,
+    ) -> Result<(Rendered, InlineKeyboardMarkup)> {
+        let language = locale::resolve(db, None, Some(chat_id))?;
+
+        let text = tpl.render_for_chat(
+            db,
+            chat_id,
+            templates::APPROVAL_REQUEST,
+            &language,
+            &json!({
+                "group": group,
+                "reason": reason,
+                "nominator": format::user(nominator),
+                "nominee": format::user(nominee),
+            }),
+        )?;
+
+        let label = |name: &str| -> Result<String> {
+            Ok(tpl.render_in(name, &language, &json!({}))?.text)
+        };
+
+        let keyboard = InlineKeyboardMarkup::new(vec![
+            vec![
+                Action::Vote {
+                    nomination_id: nomination_id,
+                    approve: true,
+                }.button(label(templates::BUTTON_VOTE_YES)?),
+                Action::Vote {
+                    nomination_id: nomination_id,
+                    approve: false,
+                }.button(label(templates::BUTTON_VOTE_NO)?),
+            ],
+            vec![
+                Action::Approve {
+                    nomination_id: nomination_id,
+                }.button(label(templates::BUTTON_APPROVE)?),
+                Action::Reject {
+                    nomination_id: nomination_id,
+                }.button(label(templates::BUTTON_REJECT)?),
+            ],
+        ]);
+
+        Ok((text, keyboard))
+    }
+
+    fn vote(
+        &self,
+        ctx: &Context,
+        query: CallbackQuery,
+        language: String,
+        id: i32,
+        approve: bool,
+    ) -> Dispatched {
+        let db = match ctx.db.get() {
+            Ok(x) => x,
+            Err(e) => return Box::from(future::err(e)),
+        };
+
+        let chat_id = match find_nomination(&db, id) {
+            Ok(Some(ref x)) if x.status == status::ACCEPTED => x.chat_id,
+            Ok(_) => return self.expired(ctx, query, &language),
+            Err(e) => return Box::from(future::err(e)),
+        };
+
+        match membership::is_active(&db, chat_id) {
+            Ok(true) => (),
+            Ok(false) => return self.paused(ctx, query, &language),
+            Err(e) => return Box::from(future::err(e)),
+        }
+
+        drop(db);
+
+        let check = self.perms.check(
+            &ctx.outbox,
+            chat_id,
+            false,
+            Some(query.from.id),
+            Level::ChatMember,
+        );
+
+        let pool = ctx.db.clone();
+        let tpl = ctx.templates.clone();
+        let outbox = ctx.outbox.clone();
+        Box::from(check.and_then(move |allowed| {
+            if !allowed {
+                return forbidden(
+                    &outbox,
+                    &tpl,
+                    query.id,
+                    &language,
+                    Level::ChatMember,
+                );
+            }
+
+            let db = match pool.get() {
+                Ok(x) => x,
+                Err(e) => return Box::from(future::err(e)),
+            };
+
+            let vote = NewVote {
+                nomination_id: id,
+                user_id: query.from.id,
+                approve: approve,
+            };
+
+            let counted = {
+                use schema::votes::dsl;
+
+                diesel::insert_into(dsl::votes)
+                    .values(&vote)
+                    .on_conflict((dsl::nomination_id, dsl::user_id))
+                    .do_update()
+                    .set(dsl::approve.eq(approve))
+                    .execute(&*db)
+                    .map_err(Error::from)
+                    .and_then(|_| count_votes(&db, id))
+            };
+
+            match counted {
+                Ok((yes, no)) => answer(
+                    &outbox,
+                    &tpl,
+                    query.id,
+                    templates::ANSWER_VOTED,
+                    &language,
+                    &json!({ "yes": yes, "no": no }),
+                    false,
+                ),
+                Err(e) => Box::from(future::err(e)),
+            }
+        }))
+    }
+
+    /// Approve or reject a nomination, then let the nominee know.
+    fn decide(
+        &self,
+        ctx: &Context,
+        query: CallbackQuery,
+        language: String,
+        id: i32,
+        approve: bool,
+    ) -> Dispatched {
+        let db = match ctx.db.get() {
+            Ok(x) => x,
+            Err(e) => return Box::from(future::err(e)),
+        };
+
+        let chat_id = match find_nomination(&db, id) {
+            Ok(Some(ref x)) if x.status == status::ACCEPTED => x.chat_id,
+            Ok(_) => return self.expired(ctx, query, &language),
+            Err(e) => return Box::from(future::err(e)),
+        };
+
+        match membership::is_active(&db, chat_id) {
+            Ok(true) => (),
+            Ok(false) => return self.paused(ctx, query, &language),
+            Err(e) => return Box::from(future::err(e)),
+        }
+
+        drop(db);
+
+        let check = self.perms.check(
+            &ctx.outbox,
+            chat_id,
+            false,
+            Some(query.from.id),
+            Level::ChatAdmin,
+        );
+
+        let pool = ctx.db.clone();
+        let tpl = ctx.templates.clone();
+        let outbox = ctx.outbox.clone();
+        let logger = self.logger.clone();
+        Box::from(check.and_then(move |allowed| {
+            if !allowed {
+                return forbidden(
+                    &outbox,
+                    &tpl,
+                    query.id,
+                    &language,
+                    Level::ChatAdmin,
+                );
+            }
+
+            let new_status = if approve {
+                status::APPROVED
+            } else {
+                status::REJECTED
+            };
+
+            let db = match pool.get() {
+                Ok(x) => x,
+                Err(e) => return Box::from(future::err(e)),
+            };
+
+            let updated = conclude(&db, id, new_status);
+
+            let nomination = match updated {
+                Ok(Some(x)) => x,
+                Ok(None) => {
+                    return answer(
+                        &outbox,
+                        &tpl,
+                        query.id,
+                        templates::ANSWER_EXPIRED,
+                        &language,
+                        &json!({}),
+                        true,
+                    )
+                }
+                Err(e) => return Box::from(future::err(e)),
+            };
+
+            info!(logger, "Nomination {} {}", id, new_status;
+                  "chat_id" => nomination.chat_id, "by" => query.from.id);
+
+            let reply = if approve {
+                templates::ANSWER_APPROVED
+            } else {
+                templates::ANSWER_REJECTED
+            };
+
+            let answered = answer(
+                &outbox,
+                &tpl,
+                query.id,
+                reply,
+                &language,
+                &json!({}),
+                false,
+            );
+
+            let announced = announce(
+                &logger,
+                &outbox,
+                &tpl,
+                &db,
+                &nomination,
+                approve,
+            );
+
+            Box::from(answered.join(announced).map(|_| ()))
+        }))
+    }
+
+    /// Move to another page of the settings menu, editing the menu message
+    /// in place.
+    fn menu(
+        &self,
+        ctx: &Context,
+        query: CallbackQuery,
+        language: String,
+        chat_id: i64,
+        page: Page,
+    ) -> Dispatched {
+        let message_id = match query.message {
+            Some(ref x) if x.chat.id == chat_id => x.message_id,
+            _ => return self.expired(ctx, query, &language),
+        };
+
+        let check = self.perms.check(
+            &ctx.outbox,
+            chat_id,
+            false,
+            Some(query.from.id),
+            Level::ChatAdmin,
+        );
+
+        let pool = ctx.db.clone();
+        let tpl = ctx.templates.clone();
+        let outbox = ctx.outbox.clone();
+        Box::from(check.and_then(move |allowed| {
+            if !allowed {
+                return forbidden(
+                    &outbox,
+                    &tpl,
+                    query.id,
+                    &language,
+                    Level::ChatAdmin,
+                );
+            }
+
+            let shown = pool.get()
+                .and_then(|db| menu(&tpl, &db, chat_id, &language, page));
+            let (text, keyboard) = match shown {
+                Ok(x) => x,
+                Err(e) => (tpl.fallback(e), None),
+            };
+
+            let edited = text.edit(&outbox, chat_id, message_id, keyboard);
+            let query_id = query.id;
+            Box::from(edited.and_then(move |_| acknowledge(&outbox, query_id)))
+        }))
+    }
+
+    /// Start or cancel a broadcast drafted by `/broadcast`, removing the
+    /// buttons from its preview either way.
+    fn broadcast(
+        &self,
+        ctx: &Context,
+        query: CallbackQuery,
+        language: String,
+        broadcast_id: i32,
+        send: bool,
+    ) -> Dispatched {
+        if !self.perms.is_owner(query.from.id) {
+            return forbidden(
+                &ctx.outbox,
+                &ctx.templates,
+                query.id,
+                &language,
+                Level::BotOwner,
+            );
+        }
+
+        let db = match ctx.db.get() {
+            Ok(x) => x,
+            Err(e) => return Box::from(future::err(e)),
+        };
+
+        let (name, data) = if send {
+            match broadcast::start(&db, broadcast_id) {
+                Ok(Some(count)) => {
+                    info!(self.logger, "Started broadcast {}", broadcast_id;
+                          "chats" => count);
+                    (
+                        templates::ANSWER_BROADCAST_SENDING,
+                        json!({ "count": count }),
+                    )
+                }
+                Ok(None) => (templates::ANSWER_BROADCAST_CLOSED, json!({})),
+                Err(e) => return Box::from(future::err(e)),
+            }
+        } else {
+            match broadcast::cancel(&db, broadcast_id) {
+                Ok(true) => (templates::ANSWER_BROADCAST_CANCELLED, json!({})),
+                Ok(false) => (templates::ANSWER_BROADCAST_CLOSED, json!({})),
+                Err(e) => return Box::from(future::err(e)),
+            }
+        };
+
+        let answered = answer(
+            &ctx.outbox,
+            &ctx.templates,
+            query.id,
+            name,
+            &language,
+            &data,
+            false,
+        );
+
+        match query.message {
+            Some(ref x) => Box::from(
+                remove_keyboard(&ctx.outbox, x.chat.id, x.message_id)
+                    .join(answered)
+                    .map(|_| ()),
+            ),
+            None => answered,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actions_round_trip() {
+        let actions = [
+            Action::Accept {
+                chat_id: -1001234567890,
+                nominator_id: 42,
+            },
+            Action::Vote {
+                nomination_id: 7,
+                approve: true,
+            },
+            Action::Vote {
+                nomination_id: 7,
+                approve: false,
+            },
+            Action::Approve { nomination_id: 7 },
+            Action::Reject { nomination_id: 7 },
+            Action::Menu {
+                chat_id: -1001234567890,
+                page: Page::Main,
+            },
+            Action::Menu {
+                chat_id: -1001234567890,
+                page: Page::Language,
+            },
+            Action::Menu {
+                chat_id: -1001234567890,
+                page: Page::Templates,
+            },
+            Action::Menu {
+                chat_id: -1001234567890,
+                page: Page::Close,
+            },
+            Action::Broadcast {
+                broadcast_id: 3,
+                send: true,
+            },
+            Action::Broadcast {
+                broadcast_id: 3,
+                send: false,
+            },
+        ];
+
+        for action in actions.iter() {
+            let data = action.encode();
+
+            assert!(data.len() <= 64, "{} is too long", data);
+            assert_eq!(Action::decode(&data), Some(*action));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_data() {
+        for x in &["", "acc", "acc:1", "acc:1:2:3", "vote:1:maybe", "x:1"] {
+            assert_eq!(Action::decode(x), None, "{}", x);
+        }
+
+        assert_eq!(Action::decode("menu:1:nope"), None);
+    }
+}
@@ -1,79 +1,1621 @@
 use entice::Context;
 use templates;
-use telebot::{self, bot};
+use locale;
+use errors::*;
+use telebot::bot;
 use telebot::objects::Message;
-use telebot::functions::FunctionMessage;
+use telebot::functions::FunctionGetChatMember;
 use slog;
 
-use futures::{future, Future, Stream};
+use diesel;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+
+use models::{status, NewUser};
+use broadcast;
+use dead_letters;
+use membership;
+use setup;
+use conversation::Step;
+
+use futures::{future, Future};
+
+use templates::Templates;
+use format::Rendered;
+use outbox::Priority;
+use args::{self, Args, Kind, Param, ParseError};
+use permissions::{Level, Permissions};
+use callbacks::{self, Action, Page};
+
+use serde_json::Value;
+
+use telebot::objects::InlineKeyboardMarkup;
 
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Where a command can be used, and by whom.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Any,
+    Private,
+    Group,
 
-pub fn register_all(logger: slog::Logger,
-                    tg: &bot::RcBot,
-                    ctx: Rc<RefCell<Option<Context>>>) {
-    register(Start::new(tg.clone(), logger), tg, ctx.clone());
+    /// Groups, by administrators of the group.
+    Admin,
 }
 
-fn register<T: Command>(
-    mut cmd: T,
-    tg: &bot::RcBot,
-    ctx: Rc<RefCell<Option<Context>>>,
-) {
-    let hndl = tg.new_cmd(T::NAME).and_then(
-        move |(tg, msg)| match *ctx.borrow() {
-            None => Box::from(tg.message(
+impl Scope {
+    fn applies(&self, private: bool, admin: bool) -> bool {
+        match *self {
+            Scope::Any => true,
+            Scope::Private => private,
+            Scope::Group => !private,
+            Scope::Admin => !private && admin,
+        }
+    }
+}
+
+/// Whether a command is listed in `/help` and Telegram's command menu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    Listed,
+    Hidden,
+}
+
+/// Metadata describing a registered command.
+#[derive(Debug, Clone)]
+pub struct CommandInfo {
+    pub name: &'static str,
+
+    /// Name of the template describing the command.
+    pub description: &'static str,
+
+    pub usage: String,
+    pub scope: Scope,
+    pub visibility: Visibility,
+}
+
+impl CommandInfo {
+    fn of<T: Command>() -> CommandInfo {
+        CommandInfo {
+            name: T::NAME,
+            description: T::DESCRIPTION,
+            usage: T::usage(),
+            scope: T::SCOPE,
+            visibility: T::VISIBILITY,
+        }
+    }
+
+    fn listed(&self, private: bool, admin: bool) -> bool {
+        self.visibility == Visibility::Listed
+            && self.scope.applies(private, admin)
+    }
+}
+
+type Reply = Box<Future<Item = (), Error = Error>>;
+type Dispatched = Box<Future<Item = (), Error = Error>>;
+type Route = Box<Fn(&Context, Message) -> Reply>;
+
+/// Split a command message like `/start@EnticeBot hello` into the command
+/// (`/start`), the bot it's addressed to (`EnticeBot`), and the arguments.
+fn split_command(text: &str) -> Option<(&str, Option<&str>, &str)> {
+    if !text.starts_with('/') {
+        return None;
+    }
+
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    let (token, rest) = text.split_at(end);
+
+    Some(match token.find('@') {
+        Some(idx) => (&token[..idx], Some(&token[idx + 1..]), rest.trim()),
+        None => (token, None, rest.trim()),
+    })
+}
+
+/// Whether a command addressed to `bot`, as in `/start@EnticeBot`, is meant
+/// for the bot named `me`. Commands that don't name a bot are for every bot.
+fn addressed_to(bot: Option<&str>, me: Option<&str>) -> bool {
+    match (bot, me) {
+        (None, _) => true,
+        (Some(bot), Some(me)) => bot.eq_ignore_ascii_case(me),
+        (Some(_), None) => false,
+    }
+}
+
+/// Every registered command, routed by name from `stream::Handler`.
+pub struct Commands {
+    logger: slog::Logger,
+    perms: Rc<Permissions>,
+    routes: HashMap<&'static str, Route>,
+    infos: Vec<CommandInfo>,
+}
+
+impl Commands {
+    pub fn new(
+        logger: slog::Logger,
+        tg: bot::RcBot,
+        perms: Rc<Permissions>,
+    ) -> Commands {
+        let mut commands = Commands {
+            logger: logger.clone(),
+            perms: perms,
+            routes: HashMap::new(),
+            infos: Vec::new(),
+        };
+
+        commands.register(Start::new(tg.clone(), logger.clone()));
+        commands.register(Language::new(tg.clone(), logger.clone()));
+        commands.register(SetTemplate::new(tg.clone(), logger.clone()));
+        commands.register(ResetTemplate::new(tg.clone(), logger.clone()));
+        commands.register(Settings::new(tg.clone(), logger.clone()));
+        commands.register(Chats::new(tg.clone(), logger.clone()));
+        commands.register(Leave::new(tg.clone(), logger.clone()));
+        commands.register(Disable::new(tg.clone(), logger.clone()));
+        commands.register(Enable::new(tg.clone(), logger.clone()));
+        commands.register(Broadcast::new(tg.clone(), logger.clone()));
+        commands.register(Status::new(tg.clone(), logger.clone()));
+        commands.register(Failed::new(tg.clone(), logger.clone()));
+        commands.register(Replay::new(tg.clone(), logger.clone()));
+
+        commands.infos.push(CommandInfo::of::<Help>());
+
+        let mut help = Help::new(tg, logger);
+        help.commands = Rc::from(commands.infos.clone());
+        commands.register(help);
+
+        commands
+    }
+
+    pub fn infos(&self) -> &[CommandInfo] {
+        &self.infos
+    }
+
+    fn register<T: Command>(&mut self, cmd: T) {
+        let cmd = Rc::from(RefCell::from(cmd));
+        let perms = self.perms.clone();
+
+        let route = move |ctx: &Context, msg: Message| {
+            let check = perms.check(
+                &ctx.outbox,
                 msg.chat.id,
-                "Not ready yet :(".to_owned(),
-            ).send().map(|_| ())),
-            Some(ref x) => cmd.handle(x, msg),
-        },
-    );
+                msg.chat.kind == "private",
+                msg.from.as_ref().map(|x| x.id),
+                T::PERMISSION,
+            );
+
+            // The permission check may have to wait on Telegram, so keep the
+            // context for once it completes. Arguments are only parsed after
+            // it, so usage isn't explained to who can't run it.
+            let ctx = ctx.clone();
+            let cmd = cmd.clone();
+            Box::from(check.and_then(move |allowed| {
+                if !allowed {
+                    return forbidden(&ctx, &msg, T::PERMISSION);
+                }
+
+                match args::parse(T::PARAMS, &msg) {
+                    Ok(args) => cmd.borrow_mut().handle(&ctx, msg, args),
+                    Err(e) => usage::<T>(&ctx, &msg, e),
+                }
+            })) as Reply
+        };
+
+        self.routes.insert(T::NAME, Box::new(route));
+
+        if !self.infos.iter().any(|x| x.name == T::NAME) {
+            self.infos.push(CommandInfo::of::<T>());
+        }
+    }
 
-    tg.register(hndl);
+    /// Run the command in `msg`, if it is one, handing the message back
+    /// otherwise.
+    ///
+    /// Commands addressed to other bots (`/start@OtherBot`) and unknown
+    /// commands are ignored.
+    pub fn dispatch(
+        &self,
+        ctx: &Context,
+        mut msg: Message,
+    ) -> ::std::result::Result<Dispatched, Message> {
+        let (name, rest) = {
+            let text = msg.text.as_ref().map(String::as_str).unwrap_or("");
+            let (name, bot, rest) = match split_command(text) {
+                Some(x) => x,
+                None => return Err(msg),
+            };
+
+            let me = ctx.user.username.as_ref().map(String::as_str);
+            if !addressed_to(bot, me) {
+                debug!(self.logger, "Ignoring command for {:?}", bot);
+                return Ok(Box::from(future::ok(())));
+            }
+
+            (name.to_owned(), rest.to_owned())
+        };
+
+        let route = match self.routes.get(name.as_str()) {
+            Some(x) => x,
+            None => {
+                debug!(self.logger, "Unknown command: {}", name);
+                return Ok(Box::from(future::ok(())));
+            }
+        };
+
+        debug!(self.logger, "Command {}: {:?}", name, msg);
+
+        // Handlers only see the arguments, not the command itself.
+        msg.text = Some(rest);
+
+        Ok(route(ctx, msg))
+    }
+}
+
+/// Publish the listed commands to Telegram's command menu, with separate
+/// menus for private chats, groups, and group administrators.
+pub fn publish(
+    tg: &bot::RcBot,
+    tpl: &Templates,
+    commands: &[CommandInfo],
+) -> Box<Future<Item = (), Error = Error>> {
+    let scopes = [
+        ("all_private_chats", true, false),
+        ("all_group_chats", false, false),
+        ("all_chat_administrators", false, true),
+    ];
+
+    let mut requests = Vec::new();
+
+    for &(scope, private, admin) in scopes.iter() {
+        let mut menu = Vec::new();
+
+        for info in commands.iter().filter(|x| x.listed(private, admin)) {
+            let description = match tpl.render_in(
+                info.description,
+                locale::DEFAULT,
+                &json!({}),
+            ) {
+                Ok(x) => x.text,
+                Err(e) => return Box::from(future::err(e)),
+            };
+
+            menu.push(json!({
+                "command": info.name.trim_left_matches('/'),
+                "description": description,
+            }));
+        }
+
+        let body = json!({
+            "commands": menu,
+            "scope": { "type": scope },
+        }).to_string();
+
+        requests.push(tg.inner.fetch_json("setMyCommands", &body));
+    }
+
+    Box::from(future::join_all(requests).map(|_| ()).from_err())
+}
+
+/// Validate the template name given to `/settemplate` or `/resettemplate`,
+/// returning the reply to send instead if it's missing or unknown.
+fn template_name(
+    ctx: &Context,
+    args: &Args,
+    language: &str,
+) -> ::std::result::Result<String, Rendered> {
+    let name = args.text("name").unwrap_or("");
+
+    if templates::OVERRIDABLE.contains(&name) {
+        return Ok(name.to_owned());
+    }
+
+    Err(ctx.templates.render_in(
+        templates::REPLY_TEMPLATE_UNKNOWN,
+        language,
+        &json!({ "name": name, "names": templates::OVERRIDABLE }),
+    ).unwrap_or_else(|e| ctx.templates.fallback(e)))
+}
+
+/// Tell the sender they aren't allowed to use a command.
+fn forbidden(
+    ctx: &Context,
+    msg: &Message,
+    level: Level,
+) -> Box<Future<Item = (), Error = Error>> {
+    let language = ctx.db
+        .get()
+        .and_then(|db| {
+            locale::resolve(&db, msg.from.as_ref(), Some(msg.chat.id))
+        })
+        .unwrap_or_else(|_| locale::DEFAULT.to_owned());
+
+    let mut data = json!({});
+    data[level.name()] = json!(true);
+
+    ctx.templates
+        .render_in(templates::REPLY_FORBIDDEN, &language, &data)
+        .unwrap_or_else(|e| ctx.templates.fallback(e))
+        .send(&ctx.outbox, msg.chat.id)
+}
+
+/// Explain how to use a command after its arguments failed to parse.
+fn usage<T: Command>(
+    ctx: &Context,
+    msg: &Message,
+    err: ParseError,
+) -> Box<Future<Item = (), Error = Error>> {
+    let language = ctx.db
+        .get()
+        .and_then(|db| {
+            locale::resolve(&db, msg.from.as_ref(), Some(msg.chat.id))
+        })
+        .unwrap_or_else(|_| locale::DEFAULT.to_owned());
+
+    let missing = match err {
+        ParseError::Missing(_) => true,
+        ParseError::Invalid(_) => false,
+    };
+
+    ctx.templates
+        .render_in(templates::REPLY_USAGE, &language, &json!({
+            "usage": T::usage(),
+            "param": err.param(),
+            "missing": missing,
+        }))
+        .unwrap_or_else(|e| ctx.templates.fallback(e))
+        .send(&ctx.outbox, msg.chat.id)
 }
 
 trait Command: 'static {
     const NAME: &'static str;
 
+    /// Name of the template describing this command in `/help` and the
+    /// command menu.
+    const DESCRIPTION: &'static str;
+
+    const SCOPE: Scope = Scope::Any;
+
+    const VISIBILITY: Visibility = Visibility::Listed;
+
+    /// Who may use this command, checked before `handle` is called.
+    const PERMISSION: Level = Level::Anyone;
+
+    /// The arguments this command takes, parsed before `handle` is called.
+    const PARAMS: &'static [Param] = &[];
+
     fn new(tg: bot::RcBot, logger: slog::Logger) -> Self;
 
+    fn usage() -> String {
+        args::usage(Self::NAME, Self::PARAMS)
+    }
+
     fn handle(
         &mut self,
         &Context,
         Message,
-    ) -> Box<Future<Item = (), Error = telebot::Error>>;
+        Args,
+    ) -> Box<Future<Item = (), Error = Error>>;
 }
 
 struct Start {
-    tg: bot::RcBot,
     logger: slog::Logger,
 }
 
 impl Command for Start {
     const NAME: &'static str = "/start";
+    const DESCRIPTION: &'static str = templates::HELP_START;
+    const SCOPE: Scope = Scope::Private;
 
-    fn new(tg: bot::RcBot, logger: slog::Logger) -> Self {
-        Start {
-            tg: tg,
-            logger: logger,
-        }
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        Start { logger: logger }
     }
 
     fn handle(
         &mut self,
         ctx: &Context,
         msg: Message,
-    ) -> Box<Future<Item = (), Error = telebot::Error>> {
+        _: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
         if msg.chat.kind != "private" {
             return Box::from(future::ok(()));
         }
 
-        let text = ctx.templates.render(templates::REPLY_START, &json!({
+        let resolved = ctx.db
+            .get()
+            .and_then(|db| locale::resolve(&db, msg.from.as_ref(), None));
+        let language = match resolved {
+            Ok(x) => x,
+            Err(e) => {
+                error!(self.logger, "unable to resolve language: {}", e);
+                locale::DEFAULT.to_owned()
+            }
+        };
+
+        // Deep links from the "configure me" button carry the group to set
+        // up, as in `/start setup_-1001234`.
+        let payload = msg.text.as_ref().map(|x| x.trim()).unwrap_or("");
+        if payload.starts_with(setup::START_PREFIX) {
+            if let Ok(chat_id) = payload[setup::START_PREFIX.len()..].parse() {
+                return self.setup(ctx, &msg, chat_id, language);
+            }
+        }
+
+        let text = ctx.templates.render_in(templates::REPLY_START,
+                                           &language,
+                                           &json!({
             "username": ctx.user.username,
-        })).unwrap();
+        })).unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        text.send(&ctx.outbox, msg.chat.id)
+    }
+}
+
+impl Start {
+    /// Begin setting up a group, if the sender administers it.
+    fn setup(
+        &self,
+        ctx: &Context,
+        msg: &Message,
+        chat_id: i64,
+        language: String,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let user_id = match msg.from {
+            Some(ref x) => x.id,
+            None => return Box::from(future::ok(())),
+        };
+
+        let tpl = ctx.templates.clone();
+        let pool = ctx.db.clone();
+        let conversations = ctx.conversations.clone();
+        let outbox = ctx.outbox.clone();
+
+        let member = ctx.outbox.call(None, Priority::Interactive, move |tg| {
+            Box::from(tg.get_chat_member(chat_id, user_id).send().map(|x| x.1))
+        });
+
+        Box::from(member.and_then(move |member| {
+            if !membership::is_admin(&member.status) {
+                let mut data = json!({});
+                data[Level::ChatAdmin.name()] = json!(true);
+
+                return tpl.render_in(
+                    templates::REPLY_FORBIDDEN,
+                    &language,
+                    &data,
+                ).unwrap_or_else(|e| tpl.fallback(e))
+                    .send(&outbox, user_id);
+            }
+
+            let db = match pool.get() {
+                Ok(x) => x,
+                Err(e) => return Box::from(future::err(e)),
+            };
+
+            setup::begin(
+                &outbox,
+                &tpl,
+                &db,
+                &conversations,
+                user_id,
+                chat_id,
+                &language,
+            )
+        }))
+    }
+}
+
+/// Set the language used when replying to the sender (in private chats) or the
+/// default language of a group (administrators only). Without an argument, the
+/// preference is cleared.
+struct Language {
+    logger: slog::Logger,
+}
+
+impl Language {
+    fn store_user(
+        db: &PgConnection,
+        user_id: i64,
+        lang: Option<&str>,
+    ) -> Result<()> {
+        use schema::users::dsl::*;
+
+        let new_user = NewUser {
+            id: user_id,
+            language: lang,
+        };
+
+        diesel::insert_into(users)
+            .values(&new_user)
+            .on_conflict(id)
+            .do_update()
+            .set(language.eq(lang))
+            .execute(db)?;
+
+        Ok(())
+    }
+
+    fn store_chat(
+        db: &PgConnection,
+        chat_id: i64,
+        user_id: i64,
+        lang: Option<&str>,
+    ) -> Result<()> {
+        use schema::chats::dsl::*;
+
+        diesel::update(chats.find(chat_id))
+            .set(language.eq(lang))
+            .execute(db)?;
+
+        membership::configured(db, chat_id, user_id)
+    }
+
+    fn reply_name(lang: &Option<String>) -> &'static str {
+        match *lang {
+            Some(_) => templates::REPLY_LANGUAGE_SET,
+            None => templates::REPLY_LANGUAGE_CLEARED,
+        }
+    }
+}
+
+impl Command for Language {
+    const NAME: &'static str = "/language";
+    const DESCRIPTION: &'static str = templates::HELP_LANGUAGE;
+    const PERMISSION: Level = Level::ChatAdmin;
+
+    const PARAMS: &'static [Param] = &[
+        Param {
+            name: "language",
+            kind: Kind::Text,
+            required: false,
+        },
+    ];
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        Language { logger: logger }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        args: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let from = match msg.from {
+            Some(ref x) => x,
+            None => return Box::from(future::ok(())),
+        };
+
+        let chat_id = msg.chat.id;
+
+        let lang = match args.text("language") {
+            None => None,
+            Some(arg) => match locale::normalize(arg) {
+                Some(x) => Some(x),
+                None => {
+                    let current = ctx.db
+                        .get()
+                        .and_then(|db| locale::resolve(&db, Some(from), None))
+                        .unwrap_or_else(|_| locale::DEFAULT.to_owned());
+                    let text = ctx.templates.render_in(
+                        templates::REPLY_LANGUAGE_INVALID,
+                        &current,
+                        &json!({ "language": arg }),
+                    ).unwrap_or_else(|e| ctx.templates.fallback(e));
+
+                    return text.send(&ctx.outbox, chat_id);
+                }
+            }
+        };
+
+        let stored = ctx.db.get().and_then(|db| {
+            if msg.chat.kind == "private" {
+                let lang = lang.as_ref().map(String::as_str);
+                Self::store_user(&db, from.id, lang)
+                    .and_then(|_| locale::resolve(&db, Some(from), None))
+            } else {
+                Self::store_chat(
+                    &db,
+                    chat_id,
+                    from.id,
+                    lang.as_ref().map(String::as_str),
+                ).map(|_| {
+                    lang.clone().unwrap_or_else(|| locale::DEFAULT.to_owned())
+                })
+            }
+        });
+
+        let text = match stored {
+            Ok(current) => ctx.templates.render_in(
+                Self::reply_name(&lang),
+                &current,
+                &json!({ "language": current }),
+            ),
+            Err(e) => {
+                error!(self.logger, "unable to store language: {}", e);
+                ctx.templates.render(templates::REPLY_ERROR, &json!({}))
+            }
+        }.unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        text.send(&ctx.outbox, chat_id)
+    }
+}
+
+/// Start replacing one of this group's messages. The sender's next message in
+/// the group becomes the new template (see `stream::Handler`).
+struct SetTemplate {
+    logger: slog::Logger,
+}
+
+impl Command for SetTemplate {
+    const NAME: &'static str = "/settemplate";
+    const DESCRIPTION: &'static str = templates::HELP_SETTEMPLATE;
+    const SCOPE: Scope = Scope::Admin;
+    const PERMISSION: Level = Level::ChatAdmin;
+
+    const PARAMS: &'static [Param] = &[
+        Param {
+            name: "name",
+            kind: Kind::Text,
+            required: false,
+        },
+    ];
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        SetTemplate { logger: logger }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        args: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let user_id = match msg.from {
+            Some(ref x) => x.id,
+            None => return Box::from(future::ok(())),
+        };
+
+        let chat_id = msg.chat.id;
+        let resolved = ctx.db
+            .get()
+            .and_then(|db| {
+                locale::resolve(&db, msg.from.as_ref(), Some(chat_id))
+            });
+        let language = match resolved {
+            Ok(x) => x,
+            Err(e) => {
+                error!(self.logger, "unable to resolve language: {}", e);
+                locale::DEFAULT.to_owned()
+            }
+        };
+
+        if msg.chat.kind == "private" {
+            let text = ctx.templates
+                .render_in(templates::REPLY_GROUP_ONLY, &language, &json!({}))
+                .unwrap_or_else(|e| ctx.templates.fallback(e));
+            return text.send(&ctx.outbox, chat_id);
+        }
+
+        let name = match template_name(ctx, &args, &language) {
+            Ok(x) => x,
+            Err(text) => return text.send(&ctx.outbox, chat_id),
+        };
+
+        let data = json!({ "name": name });
+        ctx.conversations.start(
+            chat_id,
+            user_id,
+            Step::SetTemplate { name: name },
+        );
+
+        ctx.templates
+            .render_in(templates::REPLY_TEMPLATE_PROMPT, &language, &data)
+            .unwrap_or_else(|e| ctx.templates.fallback(e))
+            .send(&ctx.outbox, chat_id)
+    }
+}
+
+/// Go back to the default version of one of this group's messages.
+struct ResetTemplate {
+    logger: slog::Logger,
+}
+
+impl ResetTemplate {
+    fn delete(
+        db: &PgConnection,
+        chat: i64,
+        user_id: i64,
+        template: &str,
+    ) -> Result<()> {
+        use schema::chat_templates::dsl::*;
+
+        diesel::delete(chat_templates.find((chat, template))).execute(db)?;
+
+        membership::configured(db, chat, user_id)
+    }
+}
+
+impl Command for ResetTemplate {
+    const NAME: &'static str = "/resettemplate";
+    const DESCRIPTION: &'static str = templates::HELP_RESETTEMPLATE;
+    const SCOPE: Scope = Scope::Admin;
+    const PERMISSION: Level = Level::ChatAdmin;
+
+    const PARAMS: &'static [Param] = &[
+        Param {
+            name: "name",
+            kind: Kind::Text,
+            required: false,
+        },
+    ];
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        ResetTemplate { logger: logger }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        args: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let user_id = match msg.from {
+            Some(ref x) => x.id,
+            None => return Box::from(future::ok(())),
+        };
+
+        let chat_id = msg.chat.id;
+        let resolved = ctx.db
+            .get()
+            .and_then(|db| {
+                locale::resolve(&db, msg.from.as_ref(), Some(chat_id))
+            });
+        let language = match resolved {
+            Ok(x) => x,
+            Err(e) => {
+                error!(self.logger, "unable to resolve language: {}", e);
+                locale::DEFAULT.to_owned()
+            }
+        };
+
+        if msg.chat.kind == "private" {
+            let text = ctx.templates
+                .render_in(templates::REPLY_GROUP_ONLY, &language, &json!({}))
+                .unwrap_or_else(|e| ctx.templates.fallback(e));
+            return text.send(&ctx.outbox, chat_id);
+        }
+
+        let name = match template_name(ctx, &args, &language) {
+            Ok(x) => x,
+            Err(text) => return text.send(&ctx.outbox, chat_id),
+        };
+
+        let deleted = ctx.db
+            .get()
+            .and_then(|db| Self::delete(&db, chat_id, user_id, &name));
+        let text = match deleted {
+            Ok(()) => ctx.templates.render_in(
+                templates::REPLY_TEMPLATE_RESET,
+                &language,
+                &json!({ "name": name }),
+            ),
+            Err(e) => {
+                error!(self.logger, "unable to reset template: {}", e);
+                ctx.templates.render_in(
+                    templates::REPLY_ERROR,
+                    &language,
+                    &json!({}),
+                )
+            }
+        }.unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        text.send(&ctx.outbox, chat_id)
+    }
+}
+
+/// Open a menu of this group's settings, navigated with buttons (see
+/// `callbacks::Callbacks`).
+struct Settings {
+    logger: slog::Logger,
+}
+
+impl Command for Settings {
+    const NAME: &'static str = "/settings";
+    const DESCRIPTION: &'static str = templates::HELP_SETTINGS;
+    const SCOPE: Scope = Scope::Admin;
+    const PERMISSION: Level = Level::ChatAdmin;
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        Settings { logger: logger }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        _: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let chat_id = msg.chat.id;
+        let resolved = ctx.db
+            .get()
+            .and_then(|db| {
+                locale::resolve(&db, msg.from.as_ref(), Some(chat_id))
+            });
+        let language = match resolved {
+            Ok(x) => x,
+            Err(e) => {
+                error!(self.logger, "unable to resolve language: {}", e);
+                locale::DEFAULT.to_owned()
+            }
+        };
+
+        if msg.chat.kind == "private" {
+            let text = ctx.templates
+                .render_in(templates::REPLY_GROUP_ONLY, &language, &json!({}))
+                .unwrap_or_else(|e| ctx.templates.fallback(e));
+            return text.send(&ctx.outbox, chat_id);
+        }
+
+        let shown = ctx.db.get().and_then(|db| {
+            callbacks::menu(&ctx.templates, &db, chat_id, &language, Page::Main)
+        });
+
+        match shown {
+            Ok((text, Some(keyboard))) => {
+                text.send_with_keyboard(&ctx.outbox, chat_id, keyboard)
+            }
+            Ok((text, None)) => text.send(&ctx.outbox, chat_id),
+            Err(e) => ctx.templates.fallback(e).send(&ctx.outbox, chat_id),
+        }
+    }
+}
+
+/// How many chats, and how many of those are active.
+fn chat_counts(db: &PgConnection) -> Result<(i64, i64)> {
+    use schema::chats::dsl;
+
+    let total = dsl::chats.count().get_result(db)?;
+    let active = dsl::chats
+        .filter(dsl::active.eq(true))
+        .filter(dsl::disabled.eq(false))
+        .count()
+        .get_result(db)?;
+
+    Ok((total, active))
+}
+
+/// The language to reply to the bot's owner in, in a private chat.
+fn owner_language(
+    logger: &slog::Logger,
+    ctx: &Context,
+    msg: &Message,
+) -> String {
+    match ctx.db
+        .get()
+        .and_then(|db| locale::resolve(&db, msg.from.as_ref(), None))
+    {
+        Ok(x) => x,
+        Err(e) => {
+            error!(logger, "unable to resolve language: {}", e);
+            locale::DEFAULT.to_owned()
+        }
+    }
+}
+
+/// How many chats `/chats` lists before summarizing the rest.
+const CHATS_LISTED: i64 = 50;
+
+/// List the chats the bot has joined, with how many nominations each has
+/// seen. Bot owners only.
+struct Chats {
+    logger: slog::Logger,
+}
+
+impl Chats {
+    fn registry(db: &PgConnection) -> Result<Value> {
+        use schema::chats::dsl;
+        use schema::nominations;
+
+        let (total, active) = chat_counts(db)?;
+
+        let listed: Vec<(i64, String, bool, bool)> = dsl::chats
+            .select((dsl::id, dsl::title, dsl::active, dsl::disabled))
+            .order(dsl::title.asc())
+            .limit(CHATS_LISTED)
+            .load(db)?;
+
+        let ids: Vec<i64> = listed.iter().map(|&(id, _, _, _)| id).collect();
+        let states: Vec<(i64, String)> = nominations::table
+            .filter(nominations::chat_id.eq_any(ids))
+            .select((nominations::chat_id, nominations::status))
+            .load(db)?;
+
+        let mut counts: HashMap<i64, (u64, u64)> = HashMap::new();
+        for (chat_id, state) in states {
+            let entry = counts.entry(chat_id).or_insert((0, 0));
+            entry.0 += 1;
+            if state == status::APPROVED {
+                entry.1 += 1;
+            }
+        }
+
+        let more = total - listed.len() as i64;
+        let chats: Vec<Value> = listed
+            .into_iter()
+            .map(|(id, title, active, disabled)| {
+                let (nominated, approved) =
+                    counts.get(&id).cloned().unwrap_or((0, 0));
+
+                json!({
+                    "id": id,
+                    "title": title,
+                    "active": active,
+                    "disabled": disabled,
+                    "nominations": nominated,
+                    "approved": approved,
+                })
+            })
+            .collect();
+
+        Ok(json!({
+            "chats": chats,
+            "total": total,
+            "active": active,
+            "more": more,
+        }))
+    }
+}
+
+impl Command for Chats {
+    const NAME: &'static str = "/chats";
+    const DESCRIPTION: &'static str = templates::HELP_CHATS;
+    const SCOPE: Scope = Scope::Private;
+    const VISIBILITY: Visibility = Visibility::Hidden;
+    const PERMISSION: Level = Level::BotOwner;
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        Chats { logger: logger }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        _: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let language = owner_language(&self.logger, ctx, &msg);
+
+        let text = ctx.db
+            .get()
+            .and_then(|db| Self::registry(&db))
+            .and_then(|x| {
+                ctx.templates.render_in(templates::REPLY_CHATS, &language, &x)
+            })
+            .unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        text.send(&ctx.outbox, msg.chat.id)
+    }
+}
+
+/// Make the bot leave a chat. Bot owners only.
+struct Leave {
+    logger: slog::Logger,
+}
+
+impl Command for Leave {
+    const NAME: &'static str = "/leave";
+    const DESCRIPTION: &'static str = templates::HELP_LEAVE;
+    const SCOPE: Scope = Scope::Private;
+    const VISIBILITY: Visibility = Visibility::Hidden;
+    const PERMISSION: Level = Level::BotOwner;
+
+    const PARAMS: &'static [Param] = &[
+        Param {
+            name: "chat",
+            kind: Kind::Integer,
+            required: true,
+        },
+    ];
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        Leave { logger: logger }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        args: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let language = owner_language(&self.logger, ctx, &msg);
+        let chat_id = args.integer("chat").unwrap_or(0);
+        let reply_to = msg.chat.id;
+
+        info!(self.logger, "Leaving chat {}", chat_id);
+
+        // Telegram also reports the bot's removal, which deactivates the chat
+        // and tells whoever configured it.
+        let logger = self.logger.clone();
+        let outbox = ctx.outbox.clone();
+        let tpl = ctx.templates.clone();
+        Box::from(membership::leave(&ctx.outbox, chat_id).then(move |result| {
+            let text = match result {
+                Ok(()) => tpl.render_in(
+                    templates::REPLY_CHAT_LEFT,
+                    &language,
+                    &json!({ "chat_id": chat_id }),
+                ),
+                Err(e) => {
+                    warn!(logger, "unable to leave {}: {}", chat_id, e);
+                    tpl.render_in(
+                        templates::REPLY_CHAT_UNKNOWN,
+                        &language,
+                        &json!({ "chat_id": chat_id, "error": e.to_string() }),
+                    )
+                }
+            }.unwrap_or_else(|e| tpl.fallback(e));
+
+            text.send(&outbox, reply_to)
+        }))
+    }
+}
+
+/// Pause nominations to a chat without leaving it. Bot owners only.
+struct Disable {
+    logger: slog::Logger,
+}
+
+impl Command for Disable {
+    const NAME: &'static str = "/disable";
+    const DESCRIPTION: &'static str = templates::HELP_DISABLE;
+    const SCOPE: Scope = Scope::Private;
+    const VISIBILITY: Visibility = Visibility::Hidden;
+    const PERMISSION: Level = Level::BotOwner;
+
+    const PARAMS: &'static [Param] = &[
+        Param {
+            name: "chat",
+            kind: Kind::Integer,
+            required: true,
+        },
+    ];
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        Disable { logger: logger }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        args: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let language = owner_language(&self.logger, ctx, &msg);
+        let chat_id = args.integer("chat").unwrap_or(0);
+
+        let disabled = ctx.db
+            .get()
+            .and_then(|db| membership::set_disabled(&db, chat_id, true));
+        let text = match disabled {
+            Ok(Some(chat)) => {
+                info!(self.logger, "Disabled chat {}", chat_id);
+                ctx.templates.render_in(
+                    templates::REPLY_CHAT_DISABLED,
+                    &language,
+                    &json!({ "group": chat.title, "chat_id": chat_id }),
+                )
+            }
+            Ok(None) => ctx.templates.render_in(
+                templates::REPLY_CHAT_UNKNOWN,
+                &language,
+                &json!({ "chat_id": chat_id }),
+            ),
+            Err(e) => {
+                error!(self.logger, "unable to disable chat: {}", e);
+                ctx.templates.render_in(
+                    templates::REPLY_ERROR,
+                    &language,
+                    &json!({}),
+                )
+            }
+        }.unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        text.send(&ctx.outbox, msg.chat.id)
+    }
+}
+
+/// Resume nominations to a chat paused with `/disable`. Bot owners only.
+struct Enable {
+    logger: slog::Logger,
+}
+
+impl Command for Enable {
+    const NAME: &'static str = "/enable";
+    const DESCRIPTION: &'static str = templates::HELP_ENABLE;
+    const SCOPE: Scope = Scope::Private;
+    const VISIBILITY: Visibility = Visibility::Hidden;
+    const PERMISSION: Level = Level::BotOwner;
+
+    const PARAMS: &'static [Param] = &[
+        Param {
+            name: "chat",
+            kind: Kind::Integer,
+            required: true,
+        },
+    ];
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        Enable { logger: logger }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        args: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let language = owner_language(&self.logger, ctx, &msg);
+        let chat_id = args.integer("chat").unwrap_or(0);
+
+        let enabled = ctx.db
+            .get()
+            .and_then(|db| membership::set_disabled(&db, chat_id, false));
+        let text = match enabled {
+            Ok(Some(chat)) => {
+                info!(self.logger, "Enabled chat {}", chat_id);
+                ctx.templates.render_in(
+                    templates::REPLY_CHAT_ENABLED,
+                    &language,
+                    &json!({ "group": chat.title, "chat_id": chat_id }),
+                )
+            }
+            Ok(None) => ctx.templates.render_in(
+                templates::REPLY_CHAT_UNKNOWN,
+                &language,
+                &json!({ "chat_id": chat_id }),
+            ),
+            Err(e) => {
+                error!(self.logger, "unable to enable chat: {}", e);
+                ctx.templates.render_in(
+                    templates::REPLY_ERROR,
+                    &language,
+                    &json!({}),
+                )
+            }
+        }.unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        text.send(&ctx.outbox, msg.chat.id)
+    }
+}
+
+/// Draft an announcement to every active chat, or those matching a
+/// `broadcast::Filter`, and preview it with buttons to send or cancel it (see
+/// `callbacks::Callbacks`). Bot owners only.
+struct Broadcast {
+    logger: slog::Logger,
+}
+
+impl Command for Broadcast {
+    const NAME: &'static str = "/broadcast";
+    const DESCRIPTION: &'static str = templates::HELP_BROADCAST;
+    const SCOPE: Scope = Scope::Private;
+    const VISIBILITY: Visibility = Visibility::Hidden;
+    const PERMISSION: Level = Level::BotOwner;
+
+    const PARAMS: &'static [Param] = &[
+        Param {
+            name: "message",
+            kind: Kind::Text,
+            required: true,
+        },
+    ];
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        Broadcast { logger: logger }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        args: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let owner_id = match msg.from {
+            Some(ref x) => x.id,
+            None => return Box::from(future::ok(())),
+        };
+
+        let language = owner_language(&self.logger, ctx, &msg);
+        let chat_id = msg.chat.id;
+
+        let (filter, body) =
+            broadcast::Filter::parse(args.text("message").unwrap_or(""));
+
+        if body.is_empty() {
+            return usage::<Self>(ctx, &msg, ParseError::Missing("message"));
+        }
+
+        if let Err(e) = ctx.templates.validate(body) {
+            return ctx.templates
+                .render_in(
+                    templates::REPLY_BROADCAST_INVALID,
+                    &language,
+                    &json!({ "error": e.to_string() }),
+                )
+                .unwrap_or_else(|e| ctx.templates.fallback(e))
+                .send(&ctx.outbox, chat_id);
+        }
+
+        let drafted = ctx.db
+            .get()
+            .and_then(|db| broadcast::draft(&db, owner_id, body, &filter));
+        let (draft, chats) = match drafted {
+            Ok(x) => x,
+            Err(e) => {
+                error!(self.logger, "unable to draft broadcast: {}", e);
+                return ctx.templates.fallback(e).send(&ctx.outbox, chat_id);
+            }
+        };
+
+        let count = chats.len();
+        let label = |name: &str| {
+            ctx.templates
+                .render_in(name, &language, &json!({ "count": count }))
+                .map(|x| x.text)
+        };
+        let button = |send: bool, text: String| {
+            Action::Broadcast {
+                broadcast_id: draft.id,
+                send: send,
+            }.button(text)
+        };
+
+        let keyboard = label(templates::BUTTON_BROADCAST_SEND).and_then(|x| {
+            let cancel = label(templates::BUTTON_BROADCAST_CANCEL)?;
+
+            Ok(InlineKeyboardMarkup::new(vec![
+                vec![button(true, x), button(false, cancel)],
+            ]))
+        });
+
+        let keyboard = match keyboard {
+            Ok(x) => x,
+            Err(e) => {
+                return ctx.templates.fallback(e).send(&ctx.outbox, chat_id)
+            }
+        };
+
+        // Preview the announcement as the first chat it goes to will see it.
+        let group = chats.first().map(|&(_, ref x)| x.as_str()).unwrap_or("");
+        let username = ctx.user.username.as_ref().map(String::as_str);
+
+        broadcast::render(&ctx.templates, body, group, username)
+            .unwrap_or_else(|e| ctx.templates.fallback(e))
+            .send_with_keyboard(&ctx.outbox, chat_id, keyboard)
+    }
+}
+
+/// Report how long the bot has been running, whether the database is
+/// reachable, and how many messages are waiting to be sent. Bot owners only.
+struct Status {
+    logger: slog::Logger,
+}
+
+impl Command for Status {
+    const NAME: &'static str = "/status";
+    const DESCRIPTION: &'static str = templates::HELP_STATUS;
+    const SCOPE: Scope = Scope::Private;
+    const VISIBILITY: Visibility = Visibility::Hidden;
+    const PERMISSION: Level = Level::BotOwner;
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        Status { logger: logger }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        _: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let language = owner_language(&self.logger, ctx, &msg);
+
+        // Counting chats doubles as the database health check.
+        let counts = ctx.db.get().and_then(|db| {
+            let (total, active) = chat_counts(&db)?;
+            Ok((total, active, broadcast::pending(&db)?))
+        });
+
+        let (database, error, total, active, pending) = match counts {
+            Ok((total, active, x)) => (true, None, total, active, x),
+            Err(e) => {
+                warn!(self.logger, "database health check failed: {}", e);
+                (false, Some(e.to_string()), 0, 0, 0)
+            }
+        };
+
+        let (connections, idle) = ctx.db.size();
+
+        let text = ctx.templates.render_in(
+            templates::REPLY_STATUS,
+            &language,
+            &json!({
+                "uptime": args::format_duration(ctx.started.elapsed()),
+                "restarts": ctx.restarts,
+                "database": database,
+                "error": error,
+                "connections": connections,
+                "idle": idle,
+                "total": total,
+                "active": active,
+                "pending": pending,
+                "queue": ctx.outbox.len(),
+            }),
+        ).unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        text.send(&ctx.outbox, msg.chat.id)
+    }
+}
+
+/// How many dead letters `/failed` lists.
+const DEAD_LETTERS_LISTED: i64 = 10;
+
+/// List the most recent updates that kept failing (see
+/// `dead_letters::Dispatcher`). Bot owners only.
+struct Failed {
+    logger: slog::Logger,
+}
+
+impl Command for Failed {
+    const NAME: &'static str = "/failed";
+    const DESCRIPTION: &'static str = templates::HELP_FAILED;
+    const SCOPE: Scope = Scope::Private;
+    const VISIBILITY: Visibility = Visibility::Hidden;
+    const PERMISSION: Level = Level::BotOwner;
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        Failed { logger: logger }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        _: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let language = owner_language(&self.logger, ctx, &msg);
+
+        let text = ctx.db
+            .get()
+            .and_then(|db| dead_letters::recent(&db, DEAD_LETTERS_LISTED))
+            .and_then(|letters| {
+                let letters: Vec<Value> = letters
+                    .into_iter()
+                    .map(|x| {
+                        json!({
+                            "id": x.id,
+                            "update_id": x.update_id,
+                            "status": x.status,
+                            "attempts": x.attempts,
+                            "created_at": x.created_at.to_rfc3339(),
+                            "error": x.error,
+                        })
+                    })
+                    .collect();
+
+                ctx.templates.render_in(
+                    templates::REPLY_DEAD_LETTERS,
+                    &language,
+                    &json!({ "letters": letters }),
+                )
+            })
+            .unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        text.send(&ctx.outbox, msg.chat.id)
+    }
+}
+
+/// Handle a dead letter again. Bot owners only.
+struct Replay {
+    logger: slog::Logger,
+}
+
+impl Command for Replay {
+    const NAME: &'static str = "/replay";
+    const DESCRIPTION: &'static str = templates::HELP_REPLAY;
+    const SCOPE: Scope = Scope::Private;
+    const VISIBILITY: Visibility = Visibility::Hidden;
+    const PERMISSION: Level = Level::BotOwner;
+
+    const PARAMS: &'static [Param] = &[
+        Param {
+            name: "id",
+            kind: Kind::Integer,
+            required: true,
+        },
+    ];
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        Replay { logger: logger }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        args: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let language = owner_language(&self.logger, ctx, &msg);
+        let id = args.integer("id").unwrap_or(0) as i32;
+
+        let queued = ctx.db.get().and_then(|db| dead_letters::queue(&db, id));
+        let text = match queued {
+            Ok(true) => {
+                info!(self.logger, "Queued dead letter {} for replay", id);
+                ctx.templates.render_in(
+                    templates::REPLY_REPLAY_QUEUED,
+                    &language,
+                    &json!({ "id": id }),
+                )
+            }
+            Ok(false) => ctx.templates.render_in(
+                templates::REPLY_REPLAY_UNKNOWN,
+                &language,
+                &json!({ "id": id }),
+            ),
+            Err(e) => {
+                error!(self.logger, "unable to queue replay: {}", e);
+                ctx.templates.render_in(
+                    templates::REPLY_ERROR,
+                    &language,
+                    &json!({}),
+                )
+            }
+        }.unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        text.send(&ctx.outbox, msg.chat.id)
+    }
+}
+
+/// List the commands available to the sender in the current chat.
+struct Help {
+    logger: slog::Logger,
+    commands: Rc<Vec<CommandInfo>>,
+}
+
+impl Help {
+    fn render(
+        tpl: &Templates,
+        commands: &[CommandInfo],
+        language: &str,
+        private: bool,
+        admin: bool,
+    ) -> Rendered {
+        let listed: ::std::result::Result<Vec<_>, Error> = commands
+            .iter()
+            .filter(|x| x.listed(private, admin))
+            .map(|x| {
+                let description =
+                    tpl.render_in(x.description, language, &json!({}))?;
+
+                Ok(json!({
+                    "usage": x.usage,
+                    "description": description.text,
+                }))
+            })
+            .collect();
+
+        listed
+            .and_then(|x| {
+                tpl.render_in(
+                    templates::REPLY_HELP,
+                    language,
+                    &json!({ "commands": x }),
+                )
+            })
+            .unwrap_or_else(|e| tpl.fallback(e))
+    }
+}
+
+impl Command for Help {
+    const NAME: &'static str = "/help";
+    const DESCRIPTION: &'static str = templates::HELP_HELP;
+
+    fn new(_: bot::RcBot, logger: slog::Logger) -> Self {
+        Help {
+            logger: logger,
+            commands: Rc::from(Vec::new()),
+        }
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &Context,
+        msg: Message,
+        _: Args,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let chat_id = msg.chat.id;
+        let resolved = ctx.db
+            .get()
+            .and_then(|db| {
+                locale::resolve(&db, msg.from.as_ref(), Some(chat_id))
+            });
+        let language = match resolved {
+            Ok(x) => x,
+            Err(e) => {
+                error!(self.logger, "unable to resolve language: {}", e);
+                locale::DEFAULT.to_owned()
+            }
+        };
+
+        if msg.chat.kind == "private" {
+            let text = Self::render(
+                &ctx.templates,
+                &self.commands,
+                &language,
+                true,
+                false,
+            );
+            return text.send(&ctx.outbox, chat_id);
+        }
+
+        let user_id = match msg.from {
+            Some(ref x) => x.id,
+            None => return Box::from(future::ok(())),
+        };
+
+        let tpl = ctx.templates.clone();
+        let commands = self.commands.clone();
+        let outbox = ctx.outbox.clone();
+
+        let member = ctx.outbox.call(None, Priority::Interactive, move |tg| {
+            Box::from(tg.get_chat_member(chat_id, user_id).send().map(|x| x.1))
+        });
+
+        Box::from(member.and_then(move |member| {
+            let admin = membership::is_admin(&member.status);
+            let text =
+                Self::render(&tpl, &commands, &language, false, admin);
+            text.send(&outbox, chat_id)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_commands() {
+        assert_eq!(split_command("/help"), Some(("/help", None, "")));
+        assert_eq!(
+            split_command("/leave  -100 "),
+            Some(("/leave", None, "-100"))
+        );
+    }
+
+    #[test]
+    fn splits_the_addressed_bot() {
+        assert_eq!(
+            split_command("/start@EnticeBot setup_-1"),
+            Some(("/start", Some("EnticeBot"), "setup_-1"))
+        );
+        assert_eq!(split_command("/start@"), Some(("/start", Some(""), "")));
+    }
+
+    #[test]
+    fn matches_the_bot_name() {
+        assert!(addressed_to(None, Some("EnticeBot")));
+        assert!(addressed_to(Some("EnticeBot"), Some("EnticeBot")));
+        assert!(addressed_to(Some("enticebot"), Some("EnticeBot")));
+        assert!(!addressed_to(Some("OtherBot"), Some("EnticeBot")));
+        assert!(!addressed_to(Some("EnticeBot"), None));
+    }
 
-        Box::from(self.tg.message(msg.chat.id, text).send().map(|_| ()))
+    #[test]
+    fn ignores_other_messages() {
+        assert_eq!(split_command("hello /help"), None);
+        assert_eq!(split_command(""), None);
     }
 }
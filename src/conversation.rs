@@ -0,0 +1,52 @@
+use setup::Setup;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long the bot waits for the reply to a prompt before forgetting it.
+const TIMEOUT_SECS: u64 = 300;
+
+/// Something the bot asked a user for, and is waiting on their next message
+/// to complete.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    SetTemplate { name: String },
+
+    /// Answering one of the questions in a group's initial setup.
+    Setup(Setup),
+}
+
+/// Pending prompts, keyed by chat and user, so the same person can be in the
+/// middle of different conversations in different chats.
+pub struct Conversations {
+    pending: RefCell<HashMap<(i64, i64), (Instant, Step)>>,
+}
+
+impl Conversations {
+    pub fn new() -> Conversations {
+        Conversations {
+            pending: RefCell::from(HashMap::new()),
+        }
+    }
+
+    /// Wait for the next message from `user_id` in `chat_id`, replacing any
+    /// earlier prompt.
+    pub fn start(&self, chat_id: i64, user_id: i64, step: Step) {
+        self.pending
+            .borrow_mut()
+            .insert((chat_id, user_id), (Instant::now(), step));
+    }
+
+    /// Remove and return the prompt waiting on `user_id` in `chat_id`, if it
+    /// hasn't expired.
+    pub fn take(&self, chat_id: i64, user_id: i64) -> Option<Step> {
+        let timeout = Duration::from_secs(TIMEOUT_SECS);
+        let mut pending = self.pending.borrow_mut();
+
+        pending.retain(|_, &mut (started, _)| started.elapsed() < timeout);
+
+        pending.remove(&(chat_id, user_id)).map(|(_, step)| step)
+    }
+
+}
@@ -0,0 +1,416 @@
+use entice::{Context, State};
+use errors::*;
+use locale;
+use middleware::{self, Pipeline};
+use models::{dead_letter, DeadLetter, NewDeadLetter};
+use permissions::Permissions;
+use pool;
+use settings;
+use templates;
+use unwind;
+
+use diesel;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+
+use serde_json::{self, Value};
+
+use slog;
+
+use telebot::objects::Update;
+
+use tokio_core::reactor::Handle;
+
+use futures::{future, Future};
+
+use std::rc::Rc;
+
+type Dispatched = Box<Future<Item = (), Error = Error>>;
+
+/// Seconds between checks for dead letters to replay.
+pub const REPLAY_SECS: u64 = 5;
+
+fn store(
+    db: &PgConnection,
+    update_id: i64,
+    payload: &Value,
+    error: &str,
+    attempts: u32,
+) -> Result<DeadLetter> {
+    use schema::dead_letters::dsl;
+
+    let payload = payload.to_string();
+    let letter = NewDeadLetter {
+        update_id: update_id,
+        payload: &payload,
+        error: error,
+        attempts: attempts as i32,
+    };
+
+    Ok(diesel::insert_into(dsl::dead_letters)
+        .values(&letter)
+        .get_result(db)?)
+}
+
+/// The most recent dead letters that haven't been replayed, newest first.
+pub fn recent(db: &PgConnection, limit: i64) -> Result<Vec<DeadLetter>> {
+    use schema::dead_letters::dsl;
+
+    Ok(dsl::dead_letters
+        .filter(dsl::status.ne(dead_letter::REPLAYED))
+        .order(dsl::id.desc())
+        .limit(limit)
+        .load(db)?)
+}
+
+/// Ask for a dead letter to be replayed, returning whether there was a
+/// failed one with that id.
+pub fn queue(db: &PgConnection, id: i32) -> Result<bool> {
+    use schema::dead_letters::dsl;
+
+    let queued = diesel::update(
+        dsl::dead_letters
+            .filter(dsl::id.eq(id))
+            .filter(dsl::status.eq(dead_letter::FAILED)),
+    ).set(dsl::status.eq(dead_letter::QUEUED))
+        .execute(db)?;
+
+    Ok(queued > 0)
+}
+
+/// Give up on replays that were running when the bot last stopped. They're
+/// left for an owner to replay again, since handling an update twice may
+/// repeat whatever it did.
+pub fn recover(db: &PgConnection) -> Result<usize> {
+    use schema::dead_letters::dsl;
+
+    let interrupted =
+        dsl::dead_letters.filter(dsl::status.eq(dead_letter::REPLAYING));
+
+    Ok(diesel::update(interrupted)
+        .set((
+            dsl::status.eq(dead_letter::FAILED),
+            dsl::error.eq("interrupted by a restart"),
+        ))
+        .execute(db)?)
+}
+
+/// Claim every dead letter waiting to be replayed.
+fn claim(db: &PgConnection) -> Result<Vec<DeadLetter>> {
+    use schema::dead_letters::dsl;
+
+    Ok(diesel::update(
+        dsl::dead_letters.filter(dsl::status.eq(dead_letter::QUEUED)),
+    ).set(dsl::status.eq(dead_letter::REPLAYING))
+        .get_results(db)?)
+}
+
+fn record(db: &PgConnection, id: i32, error: Option<String>) -> Result<()> {
+    use schema::dead_letters::dsl;
+
+    let letter = dsl::dead_letters.find(id);
+
+    match error {
+        None => diesel::update(letter)
+            .set(dsl::status.eq(dead_letter::REPLAYED))
+            .execute(db)?,
+        Some(e) => diesel::update(letter)
+            .set((
+                dsl::status.eq(dead_letter::FAILED),
+                dsl::error.eq(e),
+                dsl::attempts.eq(dsl::attempts + 1),
+            ))
+            .execute(db)?,
+    };
+
+    Ok(())
+}
+
+fn panicked(err: &Error) -> bool {
+    match *err.kind() {
+        ErrorKind::Panicked(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether `err` might not happen again if the update is handled once more,
+/// like when the database went away or every connection was in use.
+fn transient(err: &Error) -> bool {
+    match *err.kind() {
+        ErrorKind::DatabaseError(ref e) => pool::disconnected(e),
+        ErrorKind::DatabaseUnavailable | ErrorKind::PoolExhausted(_) => true,
+        _ => false,
+    }
+}
+
+/// Hands updates to the pipeline, so one that fails can't stop the bot.
+///
+/// An update that fails for a reason that may pass, like losing the database
+/// connection, is handled again up to the configured number of attempts.
+/// Any other failure would only happen again after repeating whatever the
+/// handler did first, like sending messages, so the update is stored as a
+/// dead letter straight away. Owners can list dead letters with `/failed`
+/// and replay them with `/replay`. Telegram asking the bot to slow down is
+/// dealt with by the outbox, and never reaches here.
+///
+/// A handler panicking is treated as a bug: the update is stored straight
+/// away, and the owners are told about it.
+#[derive(Clone)]
+pub struct Dispatcher {
+    logger: slog::Logger,
+    pipeline: Rc<Pipeline>,
+    state: State,
+    perms: Rc<Permissions>,
+    attempts: u32,
+}
+
+impl Dispatcher {
+    pub fn new(
+        logger: slog::Logger,
+        pipeline: Rc<Pipeline>,
+        state: State,
+        perms: Rc<Permissions>,
+        settings: settings::DeadLetters,
+    ) -> Dispatcher {
+        Dispatcher {
+            logger: logger,
+            pipeline: pipeline,
+            state: state,
+            perms: perms,
+            attempts: settings.attempts,
+        }
+    }
+
+    /// Run `upd` through the pipeline, catching any panic along the way.
+    ///
+    /// A database connection is reserved for the update first, waiting for
+    /// one if all of them are in use, so the handler can't run out partway.
+    fn handle(&self, upd: Update) -> Dispatched {
+        let pool = match self.state.borrow().context() {
+            Some(x) => x.db.clone(),
+            None => return Box::from(future::err(Error::from("not ready yet"))),
+        };
+
+        let pipeline = self.pipeline.clone();
+        let state = self.state.clone();
+        Box::from(pool.reserve().and_then(move |db| {
+            let ctx = match state.borrow().context() {
+                Some(x) => Context { db: db, ..x.clone() },
+                None => {
+                    return Box::from(future::err(Error::from("not ready yet")))
+                        as Dispatched
+                }
+            };
+
+            match unwind::call(move || pipeline.dispatch(&ctx, upd)) {
+                Ok(x) => Box::from(unwind::guard(x)),
+                Err(e) => Box::from(future::err(e)),
+            }
+        }))
+    }
+
+    /// Handle `upd`. The returned future never fails; errors are logged
+    /// instead.
+    pub fn dispatch(&self, upd: Update) -> Dispatched {
+        let logger = self.logger.new(o!(
+            "update_id" => upd.update_id,
+            "user_id" => format!("{:?}", middleware::sender(&upd)),
+        ));
+
+        // Handling takes the update by value, so keep a copy to try again.
+        let payload = match serde_json::to_value(&upd) {
+            Ok(x) => Rc::from(x),
+            Err(e) => {
+                warn!(logger, "unable to copy update: {}", e);
+
+                return Box::from(self.handle(upd).or_else(move |e| {
+                    error!(logger, "Update failed: {}", e);
+                    Ok(())
+                }));
+            }
+        };
+
+        self.attempt(logger, payload, upd, 1)
+    }
+
+    fn attempt(
+        &self,
+        logger: slog::Logger,
+        payload: Rc<Value>,
+        upd: Update,
+        attempt: u32,
+    ) -> Dispatched {
+        let update_id = upd.update_id;
+
+        let this = self.clone();
+        Box::from(self.handle(upd).or_else(move |e| {
+            if panicked(&e) {
+                error!(logger, "Update handler {}", e;
+                       "update" => payload.to_string());
+                this.bury(&logger, update_id, &payload, attempt, &e);
+                return this.report(&logger, update_id, &e);
+            }
+
+            this.reconnect(&e);
+
+            if !transient(&e) || attempt >= this.attempts {
+                this.bury(&logger, update_id, &payload, attempt, &e);
+                return Box::from(future::ok(())) as Dispatched;
+            }
+
+            warn!(logger, "Update failed (attempt {} of {}): {}",
+                  attempt, this.attempts, e);
+
+            match serde_json::from_value((*payload).clone()) {
+                Ok(x) => this.attempt(logger, payload, x, attempt + 1),
+                Err(e) => {
+                    let e = Error::from(e);
+                    this.bury(&logger, update_id, &payload, attempt, &e);
+                    Box::from(future::ok(()))
+                }
+            }
+        }))
+    }
+
+    /// Drop every database connection if `err` says they may be broken, so
+    /// the next attempt, and the next update, get a new one.
+    fn reconnect(&self, err: &Error) {
+        match *err.kind() {
+            ErrorKind::DatabaseError(ref e) if pool::disconnected(e) => (),
+            _ => return,
+        }
+
+        if let Some(x) = self.state.borrow().context() {
+            x.db.reset();
+        }
+    }
+
+    /// Store an update that kept failing, or failed in a way that handling
+    /// it again wouldn't fix, so it can be looked at and replayed later.
+    fn bury(
+        &self,
+        logger: &slog::Logger,
+        update_id: i64,
+        payload: &Value,
+        attempts: u32,
+        err: &Error,
+    ) {
+        let stored = match self.state.borrow().context() {
+            Some(x) => x.db.get().and_then(|db| {
+                store(&db, update_id, payload, &err.to_string(), attempts)
+            }),
+            None => Err(Error::from("not ready yet")),
+        };
+
+        match stored {
+            Ok(x) => error!(logger, "Update stored as dead letter {}: {}",
+                            x.id, err),
+            Err(e) => error!(logger, "Update dropped: {} ({})", err, e),
+        }
+    }
+
+    /// Tell the bot's owners that handling an update panicked. Never fails;
+    /// owners who can't be reached are only logged.
+    fn report(
+        &self,
+        logger: &slog::Logger,
+        update_id: i64,
+        err: &Error,
+    ) -> Dispatched {
+        let state = self.state.borrow();
+        let ctx = match state.context() {
+            Some(x) => x,
+            None => return Box::from(future::ok(())),
+        };
+
+        let text = ctx.templates
+            .render_in(templates::REPLY_PANIC, locale::DEFAULT, &json!({
+                "update_id": update_id,
+                "error": err.to_string(),
+            }))
+            .unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        let sent: Vec<_> = self.perms
+            .owner_ids()
+            .iter()
+            .map(|&owner| {
+                let logger = logger.clone();
+                text.clone().send(&ctx.outbox, owner).or_else(move |e| {
+                    warn!(logger, "unable to report panic to {}: {}",
+                          owner, e);
+                    Ok(())
+                })
+            })
+            .collect();
+
+        Box::from(future::join_all(sent).map(|_| ()))
+    }
+
+    /// Start replaying the dead letters owners have asked for. Each is
+    /// handled once more, and goes back to being a dead letter if it fails
+    /// again.
+    pub fn replay(&self, handle: &Handle) -> Result<()> {
+        let pool = match self.state.borrow().context() {
+            Some(x) => x.db.clone(),
+            None => return Ok(()),
+        };
+
+        let letters = claim(&*pool.get()?)?;
+
+        for letter in letters {
+            let id = letter.id;
+            let update_id = letter.update_id;
+            let logger = self.logger.new(o!(
+                "update_id" => letter.update_id,
+                "dead_letter" => id,
+            ));
+
+            info!(logger, "Replaying update");
+
+            let upd: Update = match serde_json::from_str(&letter.payload) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!(logger, "unable to restore update: {}", e);
+                    record(&*pool.get()?, id, Some(e.to_string()))?;
+                    continue;
+                }
+            };
+
+            let pool = pool.clone();
+            let this = self.clone();
+            let payload = letter.payload;
+            let replayed = self.handle(upd).then(move |result| {
+                let (error, report) = match result {
+                    Ok(()) => (None, None),
+                    Err(e) => {
+                        let error = Some(e.to_string());
+                        this.reconnect(&e);
+
+                        if panicked(&e) {
+                            error!(logger, "Update handler {}", e;
+                                   "update" => payload);
+                            (error, Some(e))
+                        } else {
+                            warn!(logger, "Replay failed: {}", e);
+                            (error, None)
+                        }
+                    }
+                };
+
+                let recorded = pool.get().and_then(|db| record(&db, id, error));
+                if let Err(e) = recorded {
+                    error!(logger, "unable to record replay: {}", e);
+                }
+
+                match report {
+                    Some(e) => this.report(&logger, update_id, &e),
+                    None => Box::from(future::ok(())),
+                }
+            });
+
+            handle.spawn(replayed.map_err(|_| ()));
+        }
+
+        Ok(())
+    }
+}
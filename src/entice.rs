@@ -1,125 +1,427 @@
 use stream::Handler;
-use commands;
-use templates;
+use middleware::Pipeline;
+use commands::{self, Commands};
+use callbacks::Callbacks;
+use templates::Templates;
+use conversation::Conversations;
+use permissions::Permissions;
+use nominations;
+use broadcast::{self, Broadcaster};
+use outbox::Outbox;
+use pool::Pool;
+use dead_letters::{self, Dispatcher};
+use unwind;
 
 use slog;
 
 use errors::*;
 
 use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem;
 use std::rc::Rc;
 use std::thread;
 
-use settings::Settings;
+use settings::{self, Settings};
 
-use diesel::prelude::*;
-use diesel::pg::PgConnection;
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
 
-use tokio_core::reactor::Core;
+use std::time::{Duration, Instant};
 
 use telebot::bot::RcBot;
 use telebot::objects::{Update, User};
 use telebot::functions::FunctionGetMe;
 
-use futures::{future, Future, IntoFuture, Stream};
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures::future::Loop;
 use futures::sync::mpsc::{channel, Receiver, Sender};
 
-use handlebars::Handlebars;
+use std::sync::{Arc, Mutex};
 
 pub type JoinHandle = ::std::thread::JoinHandle<Result<()>>;
 
 enum StreamItem {
     Command(Command),
     Telegram(RcBot, Update),
+
+    /// The bot knows who it is, and can start handling updates.
+    Ready(Context),
 }
 
+#[derive(Clone)]
 pub struct Context {
     pub user: User,
-    pub db: PgConnection,
-    pub templates: Handlebars,
+    pub db: Pool,
+    pub templates: Rc<Templates>,
+    pub conversations: Rc<Conversations>,
+    pub outbox: Outbox,
+
+    /// When the event loop started running.
+    pub started: Instant,
+
+    /// How many times the event loop has been restarted after failing.
+    pub restarts: u32,
+}
+
+/// Whether the bot has finished starting up.
+pub enum Readiness {
+    /// Still finding out who the bot is. Updates that arrive in the meantime
+    /// wait here, oldest first.
+    Starting(VecDeque<Update>),
+
+    Ready(Context),
+}
+
+impl Readiness {
+    /// The context to handle updates with, once the bot is ready.
+    pub fn context(&self) -> Option<&Context> {
+        match *self {
+            Readiness::Ready(ref x) => Some(x),
+            Readiness::Starting(_) => None,
+        }
+    }
+}
+
+/// The readiness of the bot, shared by everything that handles updates.
+pub type State = Rc<RefCell<Readiness>>;
+
+/// Ask Telegram who the bot is, trying again with backoff until it answers.
+fn identify(
+    logger: slog::Logger,
+    tg: RcBot,
+    handle: Handle,
+    settings: settings::Startup,
+) -> Box<Future<Item = User, Error = Error>> {
+    let max = Duration::from_secs(settings.max_backoff);
+    let initial = Duration::from_secs(settings.initial_backoff);
+
+    Box::from(future::loop_fn(initial, move |delay| {
+        let logger = logger.clone();
+        let handle = handle.clone();
+
+        tg.get_me().send().then(move |result| {
+            let e = match result {
+                Ok((_, user)) => {
+                    return Box::from(future::ok(Loop::Break(user)))
+                        as Box<Future<Item = _, Error = Error>>;
+                }
+                Err(e) => e,
+            };
+
+            error!(logger, "unable to get_me(), retrying in {}s: {}",
+                   delay.as_secs(), e);
+
+            let next = ::std::cmp::min(delay * 2, max);
+            match Timeout::new(delay, &handle) {
+                Ok(x) => Box::from(x.map(move |_| Loop::Continue(next))
+                    .map_err(|e| Error::with_chain(e, "startup timer failed"))),
+                Err(e) => Box::from(future::err(
+                    Error::with_chain(e, "unable to create startup timer"),
+                )),
+            }
+        })
+    }))
 }
 
 struct EventLoop {
     tg: RcBot,
+    outbox: Outbox,
     event_loop: Core,
-    receiver: Receiver<Command>,
-    context: Rc<RefCell<Option<Context>>>,
-    update_handler: Rc<Handler>,
+    state: State,
+    templates: Rc<Templates>,
+    dispatcher: Dispatcher,
+    commands: Rc<Commands>,
     logger: slog::Logger,
     settings: Settings,
+    restarts: u32,
 }
 
 impl EventLoop {
     pub fn new(
         logger: slog::Logger,
         settings: Settings,
-        receiver: Receiver<Command>,
+        restarts: u32,
     ) -> Result<EventLoop> {
         let ev = Core::new().chain_err(|| "unable to create event loop")?;
         let tg = RcBot::new(ev.handle(), &settings.telegram_bot.auth_token)
             .update_interval(settings.telegram_bot.update_interval);
+        let outbox = Outbox::new(
+            logger.new(o!("module" => "outbox")),
+            tg.clone(),
+            ev.handle(),
+            settings.outbox.clone(),
+        );
+
+        let templates = Templates::new(
+            logger.new(o!("module" => "templates")),
+            settings.templates.path.as_ref().map(String::as_str),
+        )?;
+
+        templates.self_test().chain_err(|| "template self-test failed")?;
+
+        let state = Rc::from(RefCell::from(Readiness::Starting(
+            VecDeque::new(),
+        )));
+        let templates = Rc::from(templates);
+        let permissions =
+            Rc::from(Permissions::new(settings.permissions.clone()));
+
+        let commands = Rc::from(Commands::new(
+            logger.new(o!("module" => "commands")),
+            tg.clone(),
+            permissions.clone(),
+        ));
+
+        let callbacks = Rc::from(Callbacks::new(
+            logger.new(o!("module" => "callbacks")),
+            permissions.clone(),
+        ));
+
+        let handler = Handler::new(
+            logger.clone(),
+            commands.clone(),
+            callbacks,
+            permissions.clone(),
+            settings.join.clone(),
+        );
+        let pipeline = Pipeline::new(
+            logger.clone(),
+            &settings.middleware,
+            Rc::from(handler),
+        )?;
+        let dispatcher = Dispatcher::new(
+            logger.new(o!("module" => "dead_letters")),
+            Rc::from(pipeline),
+            state.clone(),
+            permissions,
+            settings.dead_letters.clone(),
+        );
 
         Ok(EventLoop {
             event_loop: ev,
-            tg: tg.clone(),
-            receiver: receiver,
-            context: Rc::from(RefCell::from(None)),
-            update_handler: Rc::from(Handler::new(logger.clone(), tg.clone())),
+            tg: tg,
+            outbox: outbox,
+            restarts: restarts,
+            state: state,
+            templates: templates,
+            dispatcher: dispatcher,
+            commands: commands,
             logger: logger,
             settings: settings,
         })
     }
 
-    pub fn setup(&mut self) -> Result<()> {
-        commands::register_all(self.logger.clone(),
-                               &self.tg,
-                               self.context.clone());
-        Ok(())
-    }
-
-    pub fn run(mut self) -> Result<()> {
+    /// Handle updates until `Command::Stop` arrives on `receiver`.
+    pub fn run(mut self, receiver: &mut Receiver<Command>) -> Result<()> {
+        let started = Instant::now();
         let tg = &self.tg;
 
-        let db = PgConnection::establish(&self.settings.database.url)
-            .chain_err(|| "unable to connect to database")?;
+        let pool = Pool::new(
+            self.logger.new(o!("module" => "pool")),
+            self.event_loop.handle(),
+            &self.settings.database,
+        );
+        let db = pool.get()?;
+
+        let unfinished = broadcast::recover(&db)
+            .chain_err(|| "unable to recover broadcasts")?;
+        if !unfinished.is_empty() {
+            warn!(self.logger, "{} broadcasts were interrupted",
+                  unfinished.len());
+        }
 
-        let mut handlebars = Handlebars::new();
-        templates::register_all(&mut handlebars)?;
+        let interrupted = dead_letters::recover(&db)
+            .chain_err(|| "unable to recover dead letters")?;
+        if interrupted > 0 {
+            warn!(self.logger, "{} update replays were interrupted",
+                  interrupted);
+        }
 
-        let log1 = self.logger.clone();
-        let log2 = self.logger.clone();
-        let ctx = self.context.clone();
-        self.event_loop.handle().spawn(
-            tg.get_me()
-                .send()
-                .and_then(move |(_, user)| {
-                    info!(log1, "My username: {:?}", &user.username);
-
-                    *ctx.borrow_mut() = Some(Context {
-                        user: user,
-                        db: db,
-                        templates: handlebars,
-                    });
+        drop(db);
+
+        let templates = self.templates.clone();
+
+        if self.settings.templates.path.is_some() {
+            let reloader = self.templates.clone();
+            let interval = Duration::from_secs(
+                self.settings.templates.reload_interval,
+            );
+            let reload = Interval::new(interval, &self.event_loop.handle())
+                .chain_err(|| "unable to create template reload timer")?
+                .for_each(move |_| {
+                    reloader.reload();
                     Ok(())
-                })
-                .or_else(move |x| {
-                    error!(log2, "unable to get_me(): {}", x);
+                });
+
+            let log = self.logger.clone();
+            self.event_loop.handle().spawn(reload.map_err(move |e| {
+                error!(log, "template reload timer failed: {}", e);
+            }));
+        }
+
+        let expirer = self.state.clone();
+        let handle = self.event_loop.handle();
+        let ttl = Duration::from_secs(self.settings.nominations.ttl);
+        let interval =
+            Duration::from_secs(self.settings.nominations.expire_interval);
+        let log = self.logger.clone();
+        let expire = Interval::new(interval, &self.event_loop.handle())
+            .chain_err(|| "unable to create nomination expiry timer")?
+            .for_each(move |_| {
+                let (pool, tpl, outbox) = match expirer.borrow().context() {
+                    Some(x) => {
+                        (x.db.clone(), x.templates.clone(), x.outbox.clone())
+                    }
+                    None => return Ok(()),
+                };
+
+                let db = match pool.get() {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(log, "unable to expire nominations: {}", e);
+                        return Ok(());
+                    }
+                };
+
+                let expired = match nominations::expire(&db, ttl) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(log, "unable to expire nominations: {}", e);
+                        return Ok(());
+                    }
+                };
+
+                for nomination in expired {
+                    info!(log, "Nomination {} expired", nomination.id);
+
+                    let log = log.clone();
+                    let id = nomination.id;
+                    handle.spawn(
+                        nominations::refresh(&outbox, &tpl, &db, &nomination)
+                            .map_err(move |e| {
+                                warn!(log, "unable to edit nomination {}: {}",
+                                      id, e);
+                            }),
+                    );
+                }
+
+                Ok(())
+            });
+
+        let log = self.logger.clone();
+        self.event_loop.handle().spawn(expire.map_err(move |e| {
+            error!(log, "nomination expiry timer failed: {}", e);
+        }));
+
+        let broadcaster = Broadcaster::new(
+            self.logger.new(o!("module" => "broadcast")),
+            self.outbox.clone(),
+            self.settings.broadcasts.clone(),
+        );
+        broadcaster.settle(
+            &self.event_loop.handle(),
+            &*pool.get()?,
+            &self.templates,
+            &unfinished,
+        );
+
+        let sender = self.state.clone();
+        let handle = self.event_loop.handle();
+        let log = self.logger.clone();
+        let send = Interval::new(
+            Duration::from_secs(broadcast::TICK_SECS),
+            &self.event_loop.handle(),
+        ).chain_err(|| "unable to create broadcast timer")?
+            .for_each(move |_| {
+                if let Some(ctx) = sender.borrow().context() {
+                    let username = ctx.user.username.as_ref();
+                    if let Err(e) = broadcaster.tick(
+                        &handle,
+                        &ctx.db,
+                        &ctx.templates,
+                        username.map(String::as_str),
+                    ) {
+                        error!(log, "unable to send broadcasts: {}", e);
+                    }
+                }
+
+                Ok(())
+            });
+
+        let log = self.logger.clone();
+        self.event_loop.handle().spawn(send.map_err(move |e| {
+            error!(log, "broadcast timer failed: {}", e);
+        }));
+
+        let replayer = self.dispatcher.clone();
+        let handle = self.event_loop.handle();
+        let log = self.logger.clone();
+        let replay = Interval::new(
+            Duration::from_secs(dead_letters::REPLAY_SECS),
+            &self.event_loop.handle(),
+        ).chain_err(|| "unable to create replay timer")?
+            .for_each(move |_| {
+                if let Err(e) = replayer.replay(&handle) {
+                    error!(log, "unable to replay dead letters: {}", e);
+                }
+
+                Ok(())
+            });
+
+        let log = self.logger.clone();
+        self.event_loop.handle().spawn(replay.map_err(move |e| {
+            error!(log, "replay timer failed: {}", e);
+        }));
+
+        let log = self.logger.clone();
+        self.event_loop.handle().spawn(
+            commands::publish(tg, &self.templates, self.commands.infos())
+                .or_else(move |e| {
+                    error!(log, "unable to publish commands: {}", e);
                     Ok(())
                 }),
         );
 
+        let log = self.logger.clone();
+        let outbox = self.outbox.clone();
+        let restarts = self.restarts;
+        let startup = identify(
+            self.logger.clone(),
+            tg.clone(),
+            self.event_loop.handle(),
+            self.settings.startup.clone(),
+        ).map(move |user| {
+            info!(log, "My username: {:?}", &user.username);
+
+            StreamItem::Ready(Context {
+                user: user,
+                db: pool,
+                templates: templates,
+                conversations: Rc::from(Conversations::new()),
+                outbox: outbox,
+                started: started,
+                restarts: restarts,
+            })
+        })
+            .map_err(|e| Error::with_chain(e, "unable to start"))
+            .into_stream();
+
         let updates = tg.get_stream()
             .map(|(tg, u)| StreamItem::Telegram(tg, u))
             .from_err();
 
-        let commands = self.receiver
+        let commands = receiver
+            .by_ref()
             .map(|x| StreamItem::Command(x))
             .map_err(|_| Error::from("command error"));
 
-        let ctx = self.context.clone();
-        let handler = self.update_handler.clone();
+        let dispatcher = self.dispatcher.clone();
+        let state = self.state.clone();
+        let limit = self.settings.startup.pending;
+        let log = self.logger.clone();
         let stream = commands
             .select(updates)
+            .select(startup)
             .take_while(|x| {
                 Ok(match x {
                     &StreamItem::Command(Command::Stop) => false,
@@ -132,8 +434,48 @@ impl EventLoop {
                         as Box<Future<Item = (), Error = Error>>
                 }
 
+                // Updates held while starting are handled one at a time,
+                // and before any that arrive later, since the stream waits
+                // for them to finish.
+                StreamItem::Ready(ctx) => {
+                    let ready = Readiness::Ready(ctx);
+                    let held = mem::replace(&mut *state.borrow_mut(), ready);
+                    let pending = match held {
+                        Readiness::Starting(x) => x,
+                        Readiness::Ready(_) => VecDeque::new(),
+                    };
+
+                    if !pending.is_empty() {
+                        info!(log, "Handling {} updates from startup",
+                              pending.len());
+                    }
+
+                    let dispatcher = dispatcher.clone();
+                    Box::from(
+                        stream::iter_ok(pending)
+                            .for_each(move |x| dispatcher.dispatch(x)),
+                    )
+                }
+
+                // Until the bot knows who it is, updates wait to be handled.
                 StreamItem::Telegram(_, u) => {
-                    handler.dispatch(&*ctx.borrow(), u)
+                    if let Readiness::Starting(ref mut pending) =
+                        *state.borrow_mut()
+                    {
+                        if pending.len() >= limit {
+                            if let Some(x) = pending.pop_front() {
+                                warn!(log, "Dropped update {} while starting",
+                                      x.update_id);
+                            }
+                        }
+
+                        pending.push_back(u);
+                        return Box::from(future::ok(()));
+                    }
+
+                    // Failures are handled per update, so they never end
+                    // the stream.
+                    dispatcher.dispatch(u)
                 }
             });
 
@@ -144,13 +486,54 @@ impl EventLoop {
 }
 
 #[derive(Debug)]
-enum Command {
+pub enum Command {
     Stop,
 }
 
+/// What the bot is up to, as seen from outside the event loop thread.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// Not started, or stopped with `EnticeBot::stop`.
+    Stopped,
+
+    Running { restarts: u32 },
+
+    /// The event loop failed, and will be started again after a delay.
+    Restarting {
+        restarts: u32,
+        error: String,
+        backoff: Duration,
+    },
+
+    /// The event loop failed too often, and the bot gave up on it.
+    Failed { restarts: u32, error: String },
+}
+
+/// Wait for `delay`, or until the bot is stopped. Returns whether it was
+/// stopped.
+fn backoff(receiver: &mut Receiver<Command>, delay: Duration) -> Result<bool> {
+    let mut core = Core::new().chain_err(|| "unable to create event loop")?;
+
+    let timeout = Timeout::new(delay, &core.handle())
+        .chain_err(|| "unable to create backoff timer")?
+        .map(|_| false)
+        .map_err(|e| Error::with_chain(e, "backoff timer failed"));
+
+    // Either `stop()` was called, or the `EnticeBot` is gone; both mean
+    // there's nothing left to restart for.
+    let stopped = receiver
+        .by_ref()
+        .into_future()
+        .map(|_| true)
+        .map_err(|_| Error::from("command error"));
+
+    core.run(stopped.select(timeout).map(|(x, _)| x).map_err(|(e, _)| e))
+}
+
 pub struct EnticeBot {
     sender: Sender<Command>,
     receiver: Option<Receiver<Command>>,
+    status: Arc<Mutex<Status>>,
 }
 
 impl EnticeBot {
@@ -160,6 +543,7 @@ impl EnticeBot {
         EnticeBot {
             sender: sender,
             receiver: Some(receiver),
+            status: Arc::from(Mutex::new(Status::Stopped)),
         }
     }
 
@@ -173,7 +557,8 @@ impl EnticeBot {
             None => bail!(ErrorKind::AlreadyStarted),
         };
 
-        Ok(thread::spawn(|| Self::run(logger, settings, receiver)))
+        let status = self.status.clone();
+        Ok(thread::spawn(|| Self::run(logger, settings, receiver, status)))
     }
 
     pub fn stop(&mut self) -> Result<()> {
@@ -186,14 +571,89 @@ impl EnticeBot {
         }
     }
 
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Run the event loop, starting it again whenever it fails.
+    ///
+    /// Restarts back off exponentially, starting over once the event loop
+    /// has stayed up for `max_backoff`. If it fails more than
+    /// `max_restarts` times within `restart_window`, the error is returned
+    /// instead.
     fn run(
         logger: slog::Logger,
         settings: Settings,
-        receiver: Receiver<Command>,
+        mut receiver: Receiver<Command>,
+        status: Arc<Mutex<Status>>,
     ) -> Result<()> {
-        let mut lp = EventLoop::new(logger, settings, receiver)?;
+        let limits = settings.supervisor.clone();
+        let initial = Duration::from_secs(limits.initial_backoff);
+        let max = Duration::from_secs(limits.max_backoff);
+        let window = Duration::from_secs(limits.restart_window);
+
+        let set = |x| *status.lock().unwrap() = x;
+
+        let mut delay = initial;
+        let mut recent: Vec<Instant> = Vec::new();
+        let mut restarts = 0;
+
+        loop {
+            set(Status::Running { restarts: restarts });
+
+            let started = Instant::now();
+            let result = unwind::call(|| {
+                let lp = EventLoop::new(
+                    logger.new(o!("restarts" => restarts)),
+                    settings.clone(),
+                    restarts,
+                )?;
+                lp.run(&mut receiver)
+            }).and_then(|x| x);
+
+            let err = match result {
+                Ok(()) => {
+                    set(Status::Stopped);
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
 
-        lp.setup()?;
-        lp.run()
+            if started.elapsed() >= max {
+                delay = initial;
+            }
+
+            let now = Instant::now();
+            recent.retain(|x| now.duration_since(*x) < window);
+
+            if recent.len() >= limits.max_restarts as usize {
+                error!(logger, "Event loop failed too often, giving up: {}",
+                       err; "restarts" => restarts);
+                set(Status::Failed {
+                    restarts: restarts,
+                    error: err.to_string(),
+                });
+                return Err(err);
+            }
+
+            recent.push(now);
+            restarts += 1;
+
+            error!(logger, "Event loop failed, restarting in {}s: {}",
+                   delay.as_secs(), err; "restarts" => restarts);
+            set(Status::Restarting {
+                restarts: restarts,
+                error: err.to_string(),
+                backoff: delay,
+            });
+
+            if backoff(&mut receiver, delay)? {
+                info!(logger, "Stopped while waiting to restart");
+                set(Status::Stopped);
+                return Ok(());
+            }
+
+            delay = ::std::cmp::min(delay * 2, max);
+        }
     }
 }
@@ -2,12 +2,39 @@ error_chain! {
     errors {
         AlreadyStarted
         AlreadyStopped
+
+        RenderTemplate(name: String) {
+            description("unable to render template")
+            display("unable to render template `{}`", name)
+        }
+
+        TemplateSelfTest(names: Vec<String>) {
+            description("templates failed to render")
+            display("templates failed to render: {}", names.join(", "))
+        }
+
+        Panicked(message: String) {
+            description("panicked")
+            display("panicked: {}", message)
+        }
+
+        DatabaseUnavailable {
+            description("unable to connect to database")
+        }
+
+        PoolExhausted(max: usize) {
+            description("all database connections are in use")
+            display("all {} database connections are in use", max)
+        }
     }
 
     foreign_links {
         ConfigError(::config::ConfigError);
         TelebotError(::telebot::Error);
         DatabaseError(::diesel::result::Error);
+        JsonError(::serde_json::Error);
         TemplateError(::handlebars::TemplateError);
+        RenderError(::handlebars::RenderError);
+        TemplateRenderError(::handlebars::TemplateRenderError);
     }
 }
@@ -0,0 +1,422 @@
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+
+use handlebars::{self, Handlebars, Helper, HelperDef, RenderContext,
+                 RenderError};
+
+use serde_json::Value;
+
+use outbox::{Outbox, Priority, Sent};
+
+use telebot::functions::{FunctionEditMessageText, FunctionMessage};
+use telebot::objects::{InlineKeyboardMarkup, User};
+
+use futures::Future;
+
+use std::io::Write;
+
+/// Directive a template uses to choose how Telegram should format it, for
+/// example `{{!-- parse_mode: html --}}`. It must be the first thing in the
+/// template.
+const DIRECTIVE: &'static str = "parse_mode:";
+
+/// How Telegram should interpret the text of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    Plain,
+    Html,
+    Markdown,
+}
+
+impl ParseMode {
+    /// Read the parse mode directive at the start of a template, defaulting to
+    /// plain text.
+    pub fn from_source(source: &str) -> ParseMode {
+        let source = source.trim_left();
+
+        let comment = if source.starts_with("{{!--") {
+            source[5..].split("--}}").next()
+        } else if source.starts_with("{{!") {
+            source[3..].split("}}").next()
+        } else {
+            None
+        };
+
+        let mode = comment.map(str::trim).and_then(|x| {
+            if x.starts_with(DIRECTIVE) {
+                Some(x[DIRECTIVE.len()..].trim().to_lowercase())
+            } else {
+                None
+            }
+        });
+
+        match mode.as_ref().map(String::as_str) {
+            Some("html") => ParseMode::Html,
+            Some("markdown") => ParseMode::Markdown,
+            _ => ParseMode::Plain,
+        }
+    }
+
+    /// The value of Telegram's `parse_mode` parameter, if any.
+    pub fn telegram_name(&self) -> Option<&'static str> {
+        match *self {
+            ParseMode::Plain => None,
+            ParseMode::Html => Some("HTML"),
+            ParseMode::Markdown => Some("Markdown"),
+        }
+    }
+
+    pub fn escape(&self, text: &str) -> String {
+        match *self {
+            ParseMode::Plain => text.to_owned(),
+            ParseMode::Html => escape_html(text),
+            ParseMode::Markdown => escape_md(text),
+        }
+    }
+
+    /// Set up `registry` to render templates in this mode: interpolated
+    /// values are escaped to match, and `mention` produces the right markup.
+    pub fn prepare(&self, registry: &mut Handlebars) {
+        match *self {
+            ParseMode::Plain => {
+                registry.register_escape_fn(handlebars::no_escape)
+            }
+            ParseMode::Html => registry.register_escape_fn(escape_html),
+            ParseMode::Markdown => registry.register_escape_fn(escape_md),
+        }
+
+        registry.register_helper("mention", Box::new(Mention(*self)));
+    }
+}
+
+/// The output of a template, along with how Telegram should format it.
+#[derive(Debug, Clone)]
+pub struct Rendered {
+    pub text: String,
+    pub parse_mode: ParseMode,
+}
+
+impl Rendered {
+    pub fn plain(text: String) -> Rendered {
+        Rendered {
+            text: text,
+            parse_mode: ParseMode::Plain,
+        }
+    }
+
+    /// Send as an interactive reply.
+    pub fn send(self, outbox: &Outbox, chat_id: i64) -> Sent {
+        self.queue(outbox, chat_id, Priority::Interactive)
+    }
+
+    pub fn queue(
+        self,
+        outbox: &Outbox,
+        chat_id: i64,
+        priority: Priority,
+    ) -> Sent {
+        outbox.push(Some(chat_id), priority, move |tg| {
+            let msg = tg.message(chat_id, self.text.clone());
+
+            Box::from(match self.parse_mode.telegram_name() {
+                Some(x) => msg.parse_mode(x).send().map(|_| ()),
+                None => msg.send().map(|_| ()),
+            })
+        })
+    }
+
+    /// Send with buttons attached below the message.
+    pub fn send_with_keyboard(
+        self,
+        outbox: &Outbox,
+        chat_id: i64,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Sent {
+        outbox.push(Some(chat_id), Priority::Interactive, move |tg| {
+            let msg = tg.message(chat_id, self.text.clone())
+                .reply_markup(keyboard.clone());
+
+            Box::from(match self.parse_mode.telegram_name() {
+                Some(x) => msg.parse_mode(x).send().map(|_| ()),
+                None => msg.send().map(|_| ()),
+            })
+        })
+    }
+
+    /// Replace the text of a message the bot sent earlier, along with its
+    /// buttons. Without a keyboard, any existing buttons are removed.
+    pub fn edit(
+        self,
+        outbox: &Outbox,
+        chat_id: i64,
+        message_id: i64,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> Sent {
+        outbox.push(Some(chat_id), Priority::Interactive, move |tg| {
+            let mut msg = tg.edit_message_text(self.text.clone())
+                .chat_id(chat_id)
+                .message_id(message_id);
+
+            if let Some(ref x) = keyboard {
+                msg = msg.reply_markup(x.clone());
+            }
+
+            if let Some(x) = self.parse_mode.telegram_name() {
+                msg = msg.parse_mode(x);
+            }
+
+            Box::from(msg.send().map(|_| ()))
+        })
+    }
+
+    /// Replace the text of a message sent through inline mode.
+    pub fn edit_inline(
+        self,
+        outbox: &Outbox,
+        inline_message_id: String,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> Sent {
+        outbox.push(None, Priority::Interactive, move |tg| {
+            let mut msg = tg.edit_message_text(self.text.clone())
+                .inline_message_id(inline_message_id.clone());
+
+            if let Some(ref x) = keyboard {
+                msg = msg.reply_markup(x.clone());
+            }
+
+            if let Some(x) = self.parse_mode.telegram_name() {
+                msg = msg.parse_mode(x);
+            }
+
+            Box::from(msg.send().map(|_| ()))
+        })
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Escape the characters that start an entity in Telegram's Markdown.
+pub fn escape_md(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '_' | '*' | '`' | '[' => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// The JSON representation of a Telegram user passed to templates, suitable
+/// for `{{mention user}}`.
+pub fn user(user: &User) -> Value {
+    json!({
+        "id": user.id,
+        "first_name": user.first_name,
+        "last_name": user.last_name,
+        "username": user.username,
+    })
+}
+
+/// Format a value as text for a helper's output, treating `null` and missing
+/// values as empty.
+fn text_of(value: Option<&Value>) -> String {
+    match value {
+        None | Some(&Value::Null) => String::new(),
+        Some(&Value::String(ref x)) => x.clone(),
+        Some(x) => x.to_string(),
+    }
+}
+
+fn write(rc: &mut RenderContext, text: &str) -> Result<(), RenderError> {
+    rc.writer.write_all(text.as_bytes())?;
+    Ok(())
+}
+
+fn escape_html_helper(
+    h: &Helper,
+    _: &Handlebars,
+    rc: &mut RenderContext,
+) -> Result<(), RenderError> {
+    let text = text_of(h.param(0).map(|x| x.value()));
+    write(rc, &escape_html(&text))
+}
+
+fn escape_md_helper(
+    h: &Helper,
+    _: &Handlebars,
+    rc: &mut RenderContext,
+) -> Result<(), RenderError> {
+    let text = text_of(h.param(0).map(|x| x.value()));
+    write(rc, &escape_md(&text))
+}
+
+/// Parse a UTC offset such as `+02:00`, `-0530` or `UTC`.
+fn parse_offset(text: &str) -> Option<FixedOffset> {
+    let text = text.trim();
+
+    if text.is_empty() || text.eq_ignore_ascii_case("utc") || text == "Z" {
+        return Some(FixedOffset::east(0));
+    }
+
+    let (sign, rest) = match text.chars().next() {
+        Some('+') => (1, &text[1..]),
+        Some('-') => (-1, &text[1..]),
+        _ => return None,
+    };
+
+    let digits: String = rest.chars().filter(|&c| c != ':').collect();
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_digit(10)) {
+        return None;
+    }
+
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+/// `{{datetime ts tz}}`: format a Unix timestamp or RFC 3339 string in the
+/// given UTC offset (UTC if omitted).
+fn datetime_helper(
+    h: &Helper,
+    _: &Handlebars,
+    rc: &mut RenderContext,
+) -> Result<(), RenderError> {
+    let utc: DateTime<Utc> = match h.param(0).map(|x| x.value()) {
+        None | Some(&Value::Null) => return Ok(()),
+        Some(&Value::Number(ref x)) => {
+            let secs = x.as_i64().ok_or_else(|| {
+                RenderError::new("datetime: timestamp must be an integer")
+            })?;
+            let naive = NaiveDateTime::from_timestamp_opt(secs, 0)
+                .ok_or_else(|| {
+                    RenderError::new("datetime: timestamp out of range")
+                })?;
+            Utc.from_utc_datetime(&naive)
+        }
+        Some(&Value::String(ref x)) => DateTime::parse_from_rfc3339(x)
+            .map_err(|_| RenderError::new("datetime: invalid timestamp"))?
+            .with_timezone(&Utc),
+        Some(_) => return Err(RenderError::new("datetime: invalid timestamp")),
+    };
+
+    let tz = text_of(h.param(1).map(|x| x.value()));
+    let offset = parse_offset(&tz)
+        .ok_or_else(|| RenderError::new("datetime: invalid time zone"))?;
+
+    let text = utc.with_timezone(&offset)
+        .format("%Y-%m-%d %H:%M (UTC%:z)")
+        .to_string();
+
+    write(rc, &text)
+}
+
+/// `{{mention user}}`: link to a user by id, so they can be mentioned even
+/// without a username.
+struct Mention(ParseMode);
+
+impl HelperDef for Mention {
+    fn call(
+        &self,
+        h: &Helper,
+        _: &Handlebars,
+        rc: &mut RenderContext,
+    ) -> Result<(), RenderError> {
+        let user = match h.param(0).map(|x| x.value()) {
+            None | Some(&Value::Null) => return Ok(()),
+            Some(x) => x,
+        };
+
+        let mut name = text_of(user.get("first_name"));
+        let last_name = text_of(user.get("last_name"));
+        if !last_name.is_empty() {
+            name.push(' ');
+            name.push_str(&last_name);
+        }
+
+        let id = user.get("id").and_then(Value::as_i64);
+
+        let text = match (self.0, id) {
+            (ParseMode::Html, Some(id)) => format!(
+                "<a href=\"tg://user?id={}\">{}</a>",
+                id,
+                escape_html(&name)
+            ),
+            (ParseMode::Markdown, Some(id)) => {
+                // Square brackets can't be escaped inside link text.
+                let name = name.replace('[', "(").replace(']', ")");
+                format!("[{}](tg://user?id={})", escape_md(&name), id)
+            }
+            (mode, _) => mode.escape(&name),
+        };
+
+        write(rc, &text)
+    }
+}
+
+/// Register the formatting helpers that don't depend on the parse mode.
+pub fn register_helpers(registry: &mut Handlebars) {
+    registry.register_helper("escape_html", Box::new(escape_html_helper));
+    registry.register_helper("escape_md", Box::new(escape_md_helper));
+    registry.register_helper("datetime", Box::new(datetime_helper));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape_html(r#"<b>"Tom" & Jerry</b>"#),
+            "&lt;b&gt;&quot;Tom&quot; &amp; Jerry&lt;/b&gt;"
+        );
+        assert_eq!(escape_html("plain *text*"), "plain *text*");
+    }
+
+    #[test]
+    fn escapes_markdown() {
+        assert_eq!(
+            escape_md("snake_case *bold* `code` [link]"),
+            r"snake\_case \*bold\* \`code\` \[link]"
+        );
+        assert_eq!(escape_md("<b>&</b>"), "<b>&</b>");
+    }
+
+    #[test]
+    fn parses_offsets() {
+        let secs = |x| parse_offset(x).map(|x| x.local_minus_utc());
+
+        assert_eq!(secs(""), Some(0));
+        assert_eq!(secs("UTC"), Some(0));
+        assert_eq!(secs("Z"), Some(0));
+        assert_eq!(secs("+02:00"), Some(2 * 3600));
+        assert_eq!(secs("-0530"), Some(-(5 * 3600 + 30 * 60)));
+    }
+
+    #[test]
+    fn rejects_invalid_offsets() {
+        for x in &["02:00", "+2", "+02:0", "+ab:cd", "+99:00", "GMT"] {
+            assert_eq!(parse_offset(x), None, "{}", x);
+        }
+    }
+}
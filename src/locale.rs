@@ -0,0 +1,135 @@
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+
+use errors::*;
+
+use telebot::objects::User;
+
+/// Language used when nothing more specific is known. The built-in templates
+/// are written in this language.
+pub const DEFAULT: &'static str = "en";
+
+/// Normalize an IETF language tag (`pt_BR`, `pt-br`, ...) to the lowercase,
+/// hyphenated form used in template file names, returning `None` if it does
+/// not look like a language tag at all.
+pub fn normalize(tag: &str) -> Option<String> {
+    let tag = tag.trim().replace('_', "-").to_lowercase();
+
+    let valid = {
+        let mut parts = tag.split('-');
+
+        let primary = parts.next().unwrap_or("");
+        let primary_ok = primary.len() >= 2 && primary.len() <= 3
+            && primary.chars().all(|c| c.is_ascii_alphabetic());
+
+        primary_ok && parts.all(|x| {
+            !x.is_empty() && x.len() <= 8
+                && x.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+    };
+
+    if valid {
+        Some(tag)
+    } else {
+        None
+    }
+}
+
+/// Choose the language for a message, preferring in order: the user's
+/// `/language` preference, the chat's default, the language reported by the
+/// user's Telegram client, and finally `DEFAULT`.
+pub fn resolve(
+    db: &PgConnection,
+    user: Option<&User>,
+    chat_id: Option<i64>,
+) -> Result<String> {
+    let user_pref = match user {
+        Some(x) => user_language(db, x.id)?,
+        None => None,
+    };
+
+    // The chat's default only matters if the user hasn't picked a language.
+    let chat_pref = match chat_id {
+        Some(x) if user_pref.is_none() => chat_language(db, x)?,
+        _ => None,
+    };
+
+    let client = user.and_then(|x| x.language_code.as_ref())
+        .map(String::as_str);
+
+    Ok(choose(user_pref, chat_pref, client))
+}
+
+fn user_language(db: &PgConnection, user_id: i64) -> Result<Option<String>> {
+    use schema::users::dsl::*;
+
+    let pref: Option<Option<String>> = users
+        .find(user_id)
+        .select(language)
+        .first(db)
+        .optional()?;
+
+    Ok(pref.and_then(|x| x))
+}
+
+fn chat_language(db: &PgConnection, chat_id: i64) -> Result<Option<String>> {
+    use schema::chats::dsl::*;
+
+    let pref: Option<Option<String>> = chats
+        .find(chat_id)
+        .select(language)
+        .first(db)
+        .optional()?;
+
+    Ok(pref.and_then(|x| x))
+}
+
+/// The first language that's known, in the order `resolve` prefers them.
+fn choose(
+    user: Option<String>,
+    chat: Option<String>,
+    client: Option<&str>,
+) -> String {
+    user.or(chat)
+        .or_else(|| client.and_then(normalize))
+        .unwrap_or_else(|| DEFAULT.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_tags() {
+        assert_eq!(normalize("en"), Some("en".to_owned()));
+        assert_eq!(normalize(" pt_BR "), Some("pt-br".to_owned()));
+        assert_eq!(normalize("zh-Hant-TW"), Some("zh-hant-tw".to_owned()));
+    }
+
+    #[test]
+    fn prefers_the_users_choice() {
+        let user = Some("de".to_owned());
+        let chat = Some("fr".to_owned());
+
+        assert_eq!(choose(user, chat, Some("es")), "de");
+    }
+
+    #[test]
+    fn falls_back_to_the_chat_then_the_client() {
+        assert_eq!(choose(None, Some("fr".to_owned()), Some("es")), "fr");
+        assert_eq!(choose(None, None, Some("pt_BR")), "pt-br");
+    }
+
+    #[test]
+    fn falls_back_to_the_default() {
+        assert_eq!(choose(None, None, Some("klingon")), DEFAULT);
+        assert_eq!(choose(None, None, None), DEFAULT);
+    }
+
+    #[test]
+    fn rejects_invalid_tags() {
+        for x in &["", "e", "english", "e1", "en-", "en--us", "en-toolongtag"] {
+            assert_eq!(normalize(x), None, "{}", x);
+        }
+    }
+}
@@ -8,6 +8,7 @@ extern crate handlebars;
 #[macro_use]
 extern crate diesel;
 extern crate erased_serde;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
@@ -42,6 +43,21 @@ mod entice;
 mod schema;
 mod models;
 mod templates;
+mod locale;
+mod conversation;
+mod format;
+mod args;
+mod permissions;
+mod middleware;
+mod callbacks;
+mod nominations;
+mod membership;
+mod setup;
+mod broadcast;
+mod outbox;
+mod dead_letters;
+mod unwind;
+mod pool;
 
 use errors::*;
 use settings::Settings;
@@ -107,7 +123,10 @@ fn run() -> Result<()> {
         { ENTICE.lock().unwrap().start(root_log.new(o!()), settings)? };
     info!(root_log, "Started.");
 
-    join_handle.join().unwrap()?;
+    let result = join_handle.join().unwrap();
+    info!(root_log, "Bot status: {:?}", ENTICE.lock().unwrap().status());
+    result?;
+
     info!(root_log, "Shut down complete!");
     Ok(())
 }
@@ -0,0 +1,166 @@
+use errors::*;
+use models::Chat;
+use outbox::{Outbox, Priority, Sent};
+use permissions::Permissions;
+use settings::{Join, JoinPolicy};
+
+use diesel;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+
+use futures::Future;
+
+/// Whether a member with `status` administers the chat. The bot has to, to
+/// do everything it needs in a group, such as creating invite links.
+pub fn is_admin(status: &str) -> bool {
+    match status {
+        "creator" | "administrator" => true,
+        _ => false,
+    }
+}
+
+/// Whether a member with `status` is still in the chat.
+pub fn is_member(status: &str) -> bool {
+    match status {
+        "left" | "kicked" => false,
+        _ => true,
+    }
+}
+
+/// Mark a chat as active or inactive, returning it if it is known.
+///
+/// Inactive chats are hidden from nominators, and their open nominations are
+/// paused until the chat becomes active again.
+pub fn set_active(
+    db: &PgConnection,
+    chat_id: i64,
+    active: bool,
+) -> Result<Option<Chat>> {
+    use schema::chats::dsl;
+
+    Ok(diesel::update(dsl::chats.find(chat_id))
+        .set(dsl::active.eq(active))
+        .get_result(db)
+        .optional()?)
+}
+
+/// Pause or resume a chat on behalf of a bot owner, returning it if it is
+/// known.
+///
+/// This is kept apart from `active`, which follows the bot's own membership,
+/// so a chat an owner disabled stays disabled when the bot is promoted again.
+pub fn set_disabled(
+    db: &PgConnection,
+    chat_id: i64,
+    disabled: bool,
+) -> Result<Option<Chat>> {
+    use schema::chats::dsl;
+
+    Ok(diesel::update(dsl::chats.find(chat_id))
+        .set(dsl::disabled.eq(disabled))
+        .get_result(db)
+        .optional()?)
+}
+
+/// Whether nominations to a chat can go ahead.
+pub fn is_active(db: &PgConnection, chat_id: i64) -> Result<bool> {
+    use schema::chats::dsl;
+
+    let state: Option<(bool, bool)> = dsl::chats
+        .find(chat_id)
+        .select((dsl::active, dsl::disabled))
+        .first(db)
+        .optional()?;
+
+    Ok(match state {
+        Some((active, disabled)) => active && !disabled,
+        None => false,
+    })
+}
+
+/// Remember who most recently changed a chat's settings, so they can be told
+/// if the bot loses access to it.
+pub fn configured(db: &PgConnection, chat_id: i64, user_id: i64) -> Result<()> {
+    use schema::chats::dsl;
+
+    diesel::update(dsl::chats.find(chat_id))
+        .set(dsl::configured_by.eq(user_id))
+        .execute(db)?;
+
+    Ok(())
+}
+
+/// Whether the join policy lets `user_id` add the bot to `chat_id`. This
+/// doesn't check that the user administers the chat.
+pub fn permitted(
+    join: &Join,
+    perms: &Permissions,
+    user_id: i64,
+    chat_id: i64,
+) -> bool {
+    match join.policy {
+        JoinPolicy::Open => true,
+        JoinPolicy::OwnerApproved => perms.is_moderator(user_id),
+        JoinPolicy::Allowlist => {
+            join.allowlist.contains(&user_id)
+                || join.allowlist.contains(&chat_id)
+        }
+    }
+}
+
+/// Make the bot leave a chat.
+pub fn leave(outbox: &Outbox, chat_id: i64) -> Sent {
+    let body = json!({ "chat_id": chat_id }).to_string();
+
+    outbox.push(Some(chat_id), Priority::Interactive, move |tg| {
+        Box::from(tg.inner.fetch_json("leaveChat", &body).map(|_| ()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use settings;
+
+    fn join(policy: JoinPolicy, allowlist: Vec<i64>) -> Join {
+        Join {
+            policy: policy,
+            allowlist: allowlist,
+        }
+    }
+
+    fn perms() -> Permissions {
+        Permissions::new(settings::Permissions {
+            owner_ids: vec![1],
+            moderator_ids: vec![2],
+            ..settings::Permissions::default()
+        })
+    }
+
+    #[test]
+    fn open_lets_anyone_in() {
+        let join = join(JoinPolicy::Open, Vec::new());
+
+        assert!(permitted(&join, &perms(), 3, -1));
+    }
+
+    #[test]
+    fn owner_approved_lets_owners_and_moderators_in() {
+        let join = join(JoinPolicy::OwnerApproved, vec![3, -1]);
+
+        assert!(permitted(&join, &perms(), 1, -1));
+        assert!(permitted(&join, &perms(), 2, -1));
+        assert!(!permitted(&join, &perms(), 3, -1));
+    }
+
+    #[test]
+    fn allowlist_lets_listed_users_and_chats_in() {
+        let join = join(JoinPolicy::Allowlist, vec![3, -1]);
+
+        assert!(permitted(&join, &perms(), 3, -2));
+        assert!(permitted(&join, &perms(), 4, -1));
+        assert!(!permitted(&join, &perms(), 4, -2));
+        assert!(!permitted(&join, &perms(), 1, -2));
+    }
+}
@@ -0,0 +1,359 @@
+use entice::Context;
+use errors::*;
+use settings;
+use stream::Handler;
+
+use slog;
+
+use telebot::objects::Update;
+
+use futures::{future, Future};
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+pub type Dispatched = Box<Future<Item = (), Error = Error>>;
+
+/// A layer wrapped around update handling. Each layer decides whether to pass
+/// the update on to the rest of the pipeline with `next.run`, and may wrap the
+/// resulting future.
+pub trait Middleware {
+    fn call(
+        &self,
+        ctx: &Context,
+        upd: Update,
+        next: Next,
+    ) -> Dispatched;
+}
+
+/// The remainder of the pipeline after the current layer.
+pub struct Next<'a> {
+    layers: &'a [Box<Middleware>],
+    handler: &'a Handler,
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, ctx: &Context, upd: Update) -> Dispatched {
+        match self.layers.split_first() {
+            Some((layer, rest)) => layer.call(
+                ctx,
+                upd,
+                Next {
+                    layers: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.dispatch(ctx, upd),
+        }
+    }
+}
+
+/// `Handler` wrapped in the configured middleware layers, outermost first.
+pub struct Pipeline {
+    layers: Vec<Box<Middleware>>,
+    handler: Rc<Handler>,
+}
+
+impl Pipeline {
+    pub fn new(
+        logger: slog::Logger,
+        settings: &settings::Middleware,
+        handler: Rc<Handler>,
+    ) -> Result<Pipeline> {
+        let mut layers: Vec<Box<Middleware>> = Vec::new();
+
+        for name in settings.layers.iter() {
+            let log = logger.new(o!("middleware" => name.clone()));
+
+            layers.push(match name.as_str() {
+                "logging" => Box::new(Logging::new(log)),
+                "metrics" => Box::new(Metrics::new(log)),
+                "throttle" => Box::new(Throttle::new(
+                    log,
+                    settings.throttle_limit,
+                    Duration::from_secs(settings.throttle_window),
+                )),
+                _ => bail!("unknown middleware: {}", name),
+            });
+        }
+
+        Ok(Pipeline {
+            layers: layers,
+            handler: handler,
+        })
+    }
+
+    pub fn dispatch(&self, ctx: &Context, upd: Update) -> Dispatched {
+        Next {
+            layers: &self.layers,
+            handler: &self.handler,
+        }.run(ctx, upd)
+    }
+}
+
+/// The user who caused an update, if there is one.
+pub fn sender(upd: &Update) -> Option<i64> {
+    if let Some(ref x) = upd.message {
+        return x.from.as_ref().map(|x| x.id);
+    }
+
+    if let Some(ref x) = upd.callback_query {
+        return Some(x.from.id);
+    }
+
+    if let Some(ref x) = upd.inline_query {
+        return Some(x.from.id);
+    }
+
+    if let Some(ref x) = upd.chosen_inline_result {
+        return Some(x.from.id);
+    }
+
+    if let Some(ref x) = upd.my_chat_member {
+        return Some(x.from.id);
+    }
+
+    None
+}
+
+/// Logs every update on the way in, and any error it produces on the way out.
+pub struct Logging {
+    logger: slog::Logger,
+}
+
+impl Logging {
+    pub fn new(logger: slog::Logger) -> Logging {
+        Logging { logger: logger }
+    }
+}
+
+impl Middleware for Logging {
+    fn call(
+        &self,
+        ctx: &Context,
+        upd: Update,
+        next: Next,
+    ) -> Dispatched {
+        let logger = self.logger.new(o!(
+            "update_id" => upd.update_id,
+            "user_id" => format!("{:?}", sender(&upd)),
+        ));
+
+        debug!(logger, "Update received");
+
+        Box::from(next.run(ctx, upd).then(move |result| {
+            match result {
+                Ok(()) => debug!(logger, "Update handled"),
+                Err(ref e) => error!(logger, "Update failed: {}", e),
+            }
+            result
+        }))
+    }
+}
+
+/// The user to count an update against for throttling, if it's a command or
+/// a button press.
+///
+/// The bot sees every message in the groups it administers, so plain
+/// messages aren't counted, or anyone chatting away would have their
+/// commands dropped. Neither are service messages or membership changes,
+/// which must never be dropped.
+fn throttled(upd: &Update) -> Option<i64> {
+    if let Some(ref x) = upd.callback_query {
+        return Some(x.from.id);
+    }
+
+    let msg = upd.message.as_ref()?;
+    let text = msg.text.as_ref()?;
+
+    if text.starts_with('/') {
+        msg.from.as_ref().map(|x| x.id)
+    } else {
+        None
+    }
+}
+
+/// Drops commands and button presses from users who send more than `limit`
+/// of them within `window`.
+pub struct Throttle {
+    logger: slog::Logger,
+    limit: u32,
+    window: Duration,
+    seen: RefCell<HashMap<i64, (Instant, u32)>>,
+}
+
+impl Throttle {
+    pub fn new(logger: slog::Logger, limit: u32, window: Duration) -> Throttle {
+        Throttle {
+            logger: logger,
+            limit: limit,
+            window: window,
+            seen: RefCell::from(HashMap::new()),
+        }
+    }
+
+    fn allow(&self, user_id: i64) -> bool {
+        let window = self.window;
+        let mut seen = self.seen.borrow_mut();
+
+        seen.retain(|_, &mut (started, _)| started.elapsed() < window);
+
+        let entry = seen.entry(user_id).or_insert((Instant::now(), 0));
+        entry.1 += 1;
+        entry.1 <= self.limit
+    }
+}
+
+impl Middleware for Throttle {
+    fn call(
+        &self,
+        ctx: &Context,
+        upd: Update,
+        next: Next,
+    ) -> Dispatched {
+        if let Some(user_id) = throttled(&upd) {
+            if !self.allow(user_id) {
+                debug!(self.logger, "Throttled update {} from {}",
+                       upd.update_id, user_id);
+                return Box::from(future::ok(()));
+            }
+        }
+
+        next.run(ctx, upd)
+    }
+}
+
+/// Counts updates, failures and handling time, logging a summary every
+/// `REPORT_EVERY` updates.
+pub struct Metrics {
+    logger: slog::Logger,
+    stats: Rc<Stats>,
+}
+
+const REPORT_EVERY: u64 = 100;
+
+#[derive(Default)]
+struct Stats {
+    updates: Cell<u64>,
+    errors: Cell<u64>,
+    total: Cell<Duration>,
+    slowest: Cell<Duration>,
+}
+
+impl Metrics {
+    pub fn new(logger: slog::Logger) -> Metrics {
+        Metrics {
+            logger: logger,
+            stats: Rc::from(Stats::default()),
+        }
+    }
+}
+
+fn millis(x: Duration) -> u64 {
+    x.as_secs() * 1000 + u64::from(x.subsec_nanos()) / 1_000_000
+}
+
+impl Middleware for Metrics {
+    fn call(
+        &self,
+        ctx: &Context,
+        upd: Update,
+        next: Next,
+    ) -> Dispatched {
+        let started = Instant::now();
+        let stats = self.stats.clone();
+        let logger = self.logger.clone();
+
+        Box::from(next.run(ctx, upd).then(move |result| {
+            let elapsed = started.elapsed();
+
+            stats.updates.set(stats.updates.get() + 1);
+            stats.total.set(stats.total.get() + elapsed);
+            if elapsed > stats.slowest.get() {
+                stats.slowest.set(elapsed);
+            }
+            if result.is_err() {
+                stats.errors.set(stats.errors.get() + 1);
+            }
+
+            let updates = stats.updates.get();
+            if updates % REPORT_EVERY == 0 {
+                info!(logger, "Handled {} updates", updates;
+                      "errors" => stats.errors.get(),
+                      "mean_ms" => millis(stats.total.get()) / updates,
+                      "slowest_ms" => millis(stats.slowest.get()));
+            }
+
+            result
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{self, Value};
+
+    fn update(message: Value) -> Update {
+        serde_json::from_value(json!({
+            "update_id": 1,
+            "message": message,
+        })).unwrap()
+    }
+
+    fn message(text: Option<&str>) -> Value {
+        let mut msg = json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": -1, "type": "group"},
+            "from": {"id": 7, "is_bot": false, "first_name": "Ann"},
+        });
+
+        if let Some(x) = text {
+            msg["text"] = json!(x);
+        }
+
+        msg
+    }
+
+    fn throttle(limit: u32, window: Duration) -> Throttle {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        Throttle::new(logger, limit, window)
+    }
+
+    #[test]
+    fn counts_only_commands() {
+        assert_eq!(throttled(&update(message(Some("/help")))), Some(7));
+        assert_eq!(throttled(&update(message(Some("hello")))), None);
+        assert_eq!(throttled(&update(message(None))), None);
+    }
+
+    #[test]
+    fn allows_up_to_the_limit() {
+        let throttle = throttle(2, Duration::from_secs(60));
+
+        assert!(throttle.allow(1));
+        assert!(throttle.allow(1));
+        assert!(!throttle.allow(1));
+    }
+
+    #[test]
+    fn counts_each_user_separately() {
+        let throttle = throttle(1, Duration::from_secs(60));
+
+        assert!(throttle.allow(1));
+        assert!(!throttle.allow(1));
+        assert!(throttle.allow(2));
+    }
+
+    #[test]
+    fn forgets_users_after_the_window() {
+        let throttle = throttle(1, Duration::from_secs(0));
+
+        assert!(throttle.allow(1));
+        assert!(throttle.allow(1));
+    }
+}
@@ -1,4 +1,5 @@
-use schema::chats;
+use schema::{broadcasts, chat_templates, chats, dead_letters, deliveries,
+             nominations, users, votes};
 use chrono::{DateTime, Utc};
 
 #[derive(Queryable)]
@@ -7,6 +8,37 @@ pub struct Chat {
     pub title: String,
     pub description: String,
     pub last_updated: DateTime<Utc>,
+    pub language: Option<String>,
+
+    /// Whether the bot is still in the chat with the rights it needs.
+    pub active: bool,
+
+    /// The user who most recently changed the chat's settings.
+    pub configured_by: Option<i64>,
+
+    /// One of the values in `approval`.
+    pub approval_mode: String,
+
+    /// Seconds a nomination stays open, overriding the global setting.
+    pub nomination_ttl: Option<i64>,
+
+    /// Sent to approved nominees along with their invite.
+    pub welcome: Option<String>,
+
+    /// Where approval requests are posted, instead of the chat itself.
+    pub notify_chat_id: Option<i64>,
+
+    /// Whether a bot owner has paused the chat with `/disable`.
+    pub disabled: bool,
+}
+
+/// Values of `Chat::approval_mode`.
+pub mod approval {
+    /// An administrator approves or rejects each accepted nomination.
+    pub const ADMIN: &'static str = "admin";
+
+    /// Accepted nominations are approved straight away.
+    pub const AUTO: &'static str = "auto";
 }
 
 #[derive(Insertable)]
@@ -15,4 +47,184 @@ pub struct NewChat<'a> {
     pub id: i64,
     pub title: &'a str,
     pub description: &'a str,
+    pub active: bool,
+}
+
+#[derive(Queryable)]
+pub struct User {
+    pub id: i64,
+    pub language: Option<String>,
+    pub last_updated: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "users"]
+pub struct NewUser<'a> {
+    pub id: i64,
+    pub language: Option<&'a str>,
+}
+
+#[derive(Queryable)]
+pub struct ChatTemplate {
+    pub chat_id: i64,
+    pub name: String,
+    pub body: String,
+    pub last_updated: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "chat_templates"]
+pub struct NewChatTemplate<'a> {
+    pub chat_id: i64,
+    pub name: &'a str,
+    pub body: &'a str,
+}
+
+/// Values of `Nomination::status`.
+pub mod status {
+    /// Sent to the nominee, who hasn't accepted yet.
+    pub const PENDING: &'static str = "pending";
+
+    /// The nominee has accepted, and the group hasn't decided yet.
+    pub const ACCEPTED: &'static str = "accepted";
+
+    pub const APPROVED: &'static str = "approved";
+    pub const REJECTED: &'static str = "rejected";
+
+    /// Nobody acted on it in time.
+    pub const EXPIRED: &'static str = "expired";
+}
+
+#[derive(Queryable)]
+pub struct Nomination {
+    pub id: i32,
+    pub chat_id: i64,
+    pub nominator_id: i64,
+    pub nominee_id: Option<i64>,
+    pub reason: String,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub last_updated: DateTime<Utc>,
+
+    /// The message the nomination was sent in through inline mode.
+    pub inline_message_id: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "nominations"]
+pub struct NewNomination<'a> {
+    pub chat_id: i64,
+    pub nominator_id: i64,
+    pub nominee_id: Option<i64>,
+    pub reason: &'a str,
+    pub status: &'a str,
+    pub inline_message_id: Option<&'a str>,
+}
+
+#[derive(Insertable)]
+#[table_name = "votes"]
+pub struct NewVote {
+    pub nomination_id: i32,
+    pub user_id: i64,
+    pub approve: bool,
+}
+
+#[derive(Queryable)]
+pub struct Broadcast {
+    pub id: i32,
+
+    /// Template source, rendered separately for each chat.
+    pub body: String,
+
+    /// Only send to chats using this language.
+    pub language: Option<String>,
+
+    /// Only send to chats with this `Chat::approval_mode`.
+    pub approval_mode: Option<String>,
+
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+
+    /// When the owner confirmed it. Unconfirmed broadcasts are drafts.
+    pub started_at: Option<DateTime<Utc>>,
+
+    /// When the last delivery was attempted.
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "broadcasts"]
+pub struct NewBroadcast<'a> {
+    pub body: &'a str,
+    pub language: Option<&'a str>,
+    pub approval_mode: Option<&'a str>,
+    pub created_by: i64,
+}
+
+/// Values of `Delivery::status`.
+pub mod delivery {
+    /// Waiting for its turn to be sent.
+    pub const PENDING: &'static str = "pending";
+
+    /// Handed to Telegram, with no result recorded yet.
+    pub const SENDING: &'static str = "sending";
+
+    pub const SENT: &'static str = "sent";
+    pub const FAILED: &'static str = "failed";
+}
+
+#[derive(Queryable)]
+pub struct Delivery {
+    pub broadcast_id: i32,
+    pub chat_id: i64,
+    pub status: String,
+    pub error: Option<String>,
+    pub last_updated: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "deliveries"]
+pub struct NewDelivery {
+    pub broadcast_id: i32,
+    pub chat_id: i64,
+}
+
+/// Values of `DeadLetter::status`.
+pub mod dead_letter {
+    /// Gave up on, until an owner asks for it to be replayed.
+    pub const FAILED: &'static str = "failed";
+
+    /// Waiting to be replayed.
+    pub const QUEUED: &'static str = "queued";
+
+    /// Handed to the handlers again, with no result recorded yet.
+    pub const REPLAYING: &'static str = "replaying";
+
+    pub const REPLAYED: &'static str = "replayed";
+}
+
+/// An update that failed every time it was handled.
+#[derive(Queryable)]
+pub struct DeadLetter {
+    pub id: i32,
+    pub update_id: i64,
+
+    /// The update as JSON, so it can be handled again.
+    pub payload: String,
+
+    /// The error from the most recent attempt.
+    pub error: String,
+    pub attempts: i32,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub last_updated: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "dead_letters"]
+pub struct NewDeadLetter<'a> {
+    pub update_id: i64,
+    pub payload: &'a str,
+    pub error: &'a str,
+    pub attempts: i32,
 }
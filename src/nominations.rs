@@ -0,0 +1,226 @@
+use errors::*;
+use locale;
+use models::{status, NewNomination, Nomination};
+use templates::{self, Templates};
+
+use chrono::{self, DateTime, Utc};
+
+use diesel;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+
+use outbox::Outbox;
+
+use futures::{future, Future};
+
+use std::time::Duration;
+
+/// The template shown in place of a nomination's inline message once it has
+/// reached `status`. Pending nominations keep the message they were sent
+/// with.
+fn template(state: &str) -> Option<&'static str> {
+    match state {
+        status::ACCEPTED => Some(templates::NOMINATION_ACCEPTED),
+        status::APPROVED => Some(templates::NOMINATION_APPROVED),
+        status::REJECTED => Some(templates::NOMINATION_REJECTED),
+        status::EXPIRED => Some(templates::NOMINATION_EXPIRED),
+        _ => None,
+    }
+}
+
+/// Edit the message a nomination was sent in to show its current status,
+/// removing the button to accept it.
+pub fn refresh(
+    outbox: &Outbox,
+    tpl: &Templates,
+    db: &PgConnection,
+    nomination: &Nomination,
+) -> Box<Future<Item = (), Error = Error>> {
+    let inline_message_id = match nomination.inline_message_id {
+        Some(ref x) => x.clone(),
+        None => return Box::from(future::ok(())),
+    };
+
+    let name = match template(&nomination.status) {
+        Some(x) => x,
+        None => return Box::from(future::ok(())),
+    };
+
+    let group = {
+        use schema::chats::dsl::*;
+
+        chats
+            .find(nomination.chat_id)
+            .select(title)
+            .first::<String>(db)
+            .optional()
+    };
+
+    let group = match group {
+        Ok(x) => x,
+        Err(e) => return Box::from(future::err(e.into())),
+    };
+
+    let language = locale::resolve(db, None, Some(nomination.chat_id))
+        .unwrap_or_else(|_| locale::DEFAULT.to_owned());
+
+    let text = tpl.render_for_chat(
+        db,
+        nomination.chat_id,
+        name,
+        &language,
+        &json!({
+            "group": group,
+            "reason": nomination.reason,
+        }),
+    ).unwrap_or_else(|e| tpl.fallback(e));
+
+    text.edit_inline(outbox, inline_message_id, None)
+}
+
+/// Mark every open nomination to an active chat as expired once it is older
+/// than the chat's own TTL, or `ttl` if the chat hasn't set one. Returns the
+/// nominations that expired.
+pub fn expire(db: &PgConnection, ttl: Duration) -> Result<Vec<Nomination>> {
+    use schema::chats;
+    use schema::nominations::dsl;
+
+    let now = Utc::now();
+    let default_ttl = ttl.as_secs() as i64;
+    let open = vec![status::PENDING, status::ACCEPTED];
+
+    // Nominations to inactive chats are paused, so they don't expire.
+    let candidates: Vec<(i32, DateTime<Utc>, Option<i64>)> = dsl::nominations
+        .inner_join(chats::table)
+        .filter(dsl::status.eq_any(open.clone()))
+        .filter(chats::active.eq(true))
+        .filter(chats::disabled.eq(false))
+        .select((dsl::id, dsl::created_at, chats::nomination_ttl))
+        .load(db)?;
+
+    let ids: Vec<i32> = candidates
+        .into_iter()
+        .filter(|&(_, created_at, chat_ttl)| {
+            let ttl = chat_ttl.unwrap_or(default_ttl);
+            created_at + chrono::Duration::seconds(ttl) < now
+        })
+        .map(|(id, _, _)| id)
+        .collect();
+
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let expired = diesel::update(
+        dsl::nominations
+            .filter(dsl::id.eq_any(ids))
+            .filter(dsl::status.eq_any(open)),
+    ).set(dsl::status.eq(status::EXPIRED))
+        .get_results(db)?;
+
+    Ok(expired)
+}
+
+/// Record a nomination the moment it is sent through inline mode, as reported
+/// by a `chosen_inline_result` update. Repeated reports of the same message
+/// are ignored.
+pub fn record_sent(
+    db: &PgConnection,
+    chat_id: i64,
+    nominator_id: i64,
+    reason: &str,
+    inline_message_id: &str,
+) -> Result<()> {
+    use schema::nominations::dsl;
+
+    let new_nomination = NewNomination {
+        chat_id: chat_id,
+        nominator_id: nominator_id,
+        nominee_id: None,
+        reason: reason,
+        status: status::PENDING,
+        inline_message_id: Some(inline_message_id),
+    };
+
+    diesel::insert_into(dsl::nominations)
+        .values(&new_nomination)
+        .on_conflict_do_nothing()
+        .execute(db)?;
+
+    Ok(())
+}
+
+/// Mark the nomination sent in `inline_message_id` as accepted by
+/// `nominee_id`, returning `None` if it isn't pending anymore.
+///
+/// If the message was never recorded (inline feedback is disabled, or the
+/// message predates it), the nomination is created here instead, unless the
+/// nominee already accepted an open nomination from the same nominator.
+pub fn accept(
+    db: &PgConnection,
+    chat_id: i64,
+    nominator_id: i64,
+    nominee_id: i64,
+    inline_message_id: Option<&str>,
+) -> Result<Option<Nomination>> {
+    use schema::nominations::dsl;
+
+    let existing: Option<Nomination> = match inline_message_id {
+        Some(x) => dsl::nominations
+            .filter(dsl::inline_message_id.eq(x))
+            .first(db)
+            .optional()?,
+        None => dsl::nominations
+            .filter(dsl::chat_id.eq(chat_id))
+            .filter(dsl::nominator_id.eq(nominator_id))
+            .filter(dsl::nominee_id.eq(nominee_id))
+            .filter(dsl::status.eq_any(vec![status::PENDING, status::ACCEPTED]))
+            .first(db)
+            .optional()?,
+    };
+
+    if let Some(existing) = existing {
+        let accepted = diesel::update(
+            dsl::nominations
+                .filter(dsl::id.eq(existing.id))
+                .filter(dsl::status.eq(status::PENDING)),
+        ).set((
+            dsl::status.eq(status::ACCEPTED),
+            dsl::nominee_id.eq(nominee_id),
+        ))
+            .get_result(db)
+            .optional()?;
+
+        return Ok(accepted);
+    }
+
+    let new_nomination = NewNomination {
+        chat_id: chat_id,
+        nominator_id: nominator_id,
+        nominee_id: Some(nominee_id),
+        reason: "",
+        status: status::ACCEPTED,
+        inline_message_id: inline_message_id,
+    };
+
+    let created = diesel::insert_into(dsl::nominations)
+        .values(&new_nomination)
+        .get_result(db)?;
+
+    Ok(Some(created))
+}
+
+/// Put an accepted nomination back to pending, for when the group couldn't
+/// be asked to approve it, so the nominee can accept it again.
+pub fn reopen(db: &PgConnection, id: i32) -> Result<()> {
+    use schema::nominations::dsl;
+
+    diesel::update(
+        dsl::nominations
+            .filter(dsl::id.eq(id))
+            .filter(dsl::status.eq(status::ACCEPTED)),
+    ).set(dsl::status.eq(status::PENDING))
+        .execute(db)?;
+
+    Ok(())
+}
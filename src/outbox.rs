@@ -0,0 +1,484 @@
+use errors::*;
+use settings;
+
+use slog;
+
+use telebot::{self, bot};
+
+use tokio_core::reactor::{Handle, Timeout};
+
+use futures::Future;
+use futures::unsync::oneshot;
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// A request to Telegram. It is built again for each attempt, since a failed
+/// request can't be sent twice.
+pub type Request = Box<Future<Item = (), Error = telebot::Error>>;
+
+/// Resolves once a queued request has been made.
+pub type Sent = Box<Future<Item = (), Error = Error>>;
+
+/// How many times a request is tried before giving up, as long as Telegram
+/// keeps asking the bot to slow down.
+const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Replies to something a user just did.
+    Interactive,
+
+    /// Messages nobody is waiting on, like broadcasts. They are only sent
+    /// when no interactive message is ready to go.
+    Bulk,
+}
+
+/// How long Telegram asked the bot to wait, if `err` is a flood error. The
+/// delay only survives in the error's description, as in
+/// `Too Many Requests: retry after 35`.
+fn retry_after(err: &telebot::Error) -> Option<u64> {
+    parse_retry_after(&err.to_string())
+}
+
+fn parse_retry_after(text: &str) -> Option<u64> {
+    const MARKER: &'static str = "retry after ";
+
+    let start = text.find(MARKER)? + MARKER.len();
+
+    let digits: String = text[start..]
+        .chars()
+        .take_while(|x| x.is_digit(10))
+        .collect();
+
+    digits.parse().ok()
+}
+
+fn secs(x: Duration) -> f64 {
+    x.as_secs() as f64 + f64::from(x.subsec_nanos()) / 1e9
+}
+
+/// A token bucket holding at most `burst` tokens, refilled at `rate` tokens
+/// per second. Sending a message spends one token.
+struct Bucket {
+    tokens: f64,
+    burst: f64,
+    rate: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, burst: f64) -> Bucket {
+        Bucket {
+            tokens: burst,
+            burst: burst,
+            rate: rate,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = secs(now.duration_since(self.updated));
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    /// How long until there's a token to spend.
+    fn wait(&self) -> Duration {
+        if self.tokens >= 1.0 {
+            return Duration::from_secs(0);
+        }
+
+        let wait = (1.0 - self.tokens) / self.rate;
+        Duration::new(wait as u64, (wait.fract() * 1e9) as u32)
+    }
+
+    /// Spend tokens so the next one is only available after `wait`.
+    fn hold(&mut self, wait: Duration) {
+        self.tokens = self.tokens.min(1.0 - secs(wait) * self.rate);
+    }
+
+    fn full(&self) -> bool {
+        self.tokens >= self.burst
+    }
+}
+
+struct Job {
+    chat_id: Option<i64>,
+    priority: Priority,
+    request: Box<Fn(&bot::RcBot) -> Request>,
+    attempts: u32,
+    done: oneshot::Sender<Result<()>>,
+}
+
+struct Inner {
+    logger: slog::Logger,
+    tg: bot::RcBot,
+    handle: Handle,
+    settings: settings::Outbox,
+
+    interactive: RefCell<VecDeque<Job>>,
+    bulk: RefCell<VecDeque<Job>>,
+
+    global: RefCell<Bucket>,
+    chats: RefCell<HashMap<i64, Bucket>>,
+
+    /// Chats with a request on its way, which have to wait for it to finish
+    /// before the next one goes, so their messages arrive in order.
+    in_flight: RefCell<HashSet<i64>>,
+
+    /// When the queue was last scheduled to be looked at again.
+    wakeup: Cell<Option<Instant>>,
+}
+
+/// Every message the bot sends goes through here, so bursts (like a
+/// broadcast) stay under Telegram's flood limits instead of failing.
+///
+/// Requests are limited by a global token bucket and one bucket per chat, and
+/// only one request per chat is made at a time. When Telegram asks the bot to
+/// slow down anyway, the request is retried once the requested time has
+/// passed.
+#[derive(Clone)]
+pub struct Outbox {
+    inner: Rc<Inner>,
+}
+
+impl Outbox {
+    pub fn new(
+        logger: slog::Logger,
+        tg: bot::RcBot,
+        handle: Handle,
+        settings: settings::Outbox,
+    ) -> Outbox {
+        let global = Bucket::new(
+            f64::from(settings.global_per_second),
+            f64::from(settings.global_per_second),
+        );
+
+        Outbox {
+            inner: Rc::from(Inner {
+                logger: logger,
+                tg: tg,
+                handle: handle,
+                settings: settings,
+                interactive: RefCell::from(VecDeque::new()),
+                bulk: RefCell::from(VecDeque::new()),
+                global: RefCell::from(global),
+                chats: RefCell::from(HashMap::new()),
+                in_flight: RefCell::from(HashSet::new()),
+                wakeup: Cell::new(None),
+            }),
+        }
+    }
+
+    /// How many requests are waiting to be made.
+    pub fn len(&self) -> usize {
+        self.inner.interactive.borrow().len() + self.inner.bulk.borrow().len()
+    }
+
+    /// Queue a request concerning `chat_id`, or no chat in particular (like
+    /// editing an inline message).
+    pub fn push<F>(
+        &self,
+        chat_id: Option<i64>,
+        priority: Priority,
+        request: F,
+    ) -> Sent
+    where
+        F: Fn(&bot::RcBot) -> Request + 'static,
+    {
+        let (done, sent) = oneshot::channel();
+
+        self.queue(priority).borrow_mut().push_back(Job {
+            chat_id: chat_id,
+            priority: priority,
+            request: Box::new(request),
+            attempts: 0,
+            done: done,
+        });
+
+        self.pump();
+
+        Box::from(sent.then(|x| match x {
+            Ok(result) => result,
+            Err(_) => Err(Error::from("message dropped from the outbox")),
+        }))
+    }
+
+    /// Queue a request whose answer is needed, like looking up a chat
+    /// member, and resolve to that answer once it's made.
+    pub fn call<F, T>(
+        &self,
+        chat_id: Option<i64>,
+        priority: Priority,
+        request: F,
+    ) -> Box<Future<Item = T, Error = Error>>
+    where
+        F: Fn(&bot::RcBot) -> Box<Future<Item = T, Error = telebot::Error>>
+            + 'static,
+        T: 'static,
+    {
+        let answer = Rc::new(RefCell::new(None));
+        let slot = answer.clone();
+
+        let sent = self.push(chat_id, priority, move |tg| {
+            let slot = slot.clone();
+            Box::from(request(tg).map(move |x| *slot.borrow_mut() = Some(x)))
+        });
+
+        Box::from(sent.and_then(move |()| {
+            answer
+                .borrow_mut()
+                .take()
+                .ok_or_else(|| Error::from("request made without an answer"))
+        }))
+    }
+
+    fn queue(&self, priority: Priority) -> &RefCell<VecDeque<Job>> {
+        match priority {
+            Priority::Interactive => &self.inner.interactive,
+            Priority::Bulk => &self.inner.bulk,
+        }
+    }
+
+    fn chat_bucket(&self) -> Bucket {
+        let settings = &self.inner.settings;
+
+        Bucket::new(
+            f64::from(settings.chat_per_minute) / 60.0,
+            f64::from(settings.chat_burst),
+        )
+    }
+
+    /// Make every request that can be made right now, then schedule another
+    /// look at the queue for when the next one can.
+    fn pump(&self) {
+        let now = Instant::now();
+
+        loop {
+            let wait = {
+                let mut global = self.inner.global.borrow_mut();
+                global.refill(now);
+                global.wait()
+            };
+
+            if wait > Duration::from_secs(0) {
+                return self.wake_at(now + wait);
+            }
+
+            let job = match self.next(now) {
+                Ok(x) => x,
+                Err(Some(wait)) => return self.wake_at(now + wait),
+                Err(None) => return,
+            };
+
+            self.inner.global.borrow_mut().tokens -= 1.0;
+            self.run(job);
+        }
+    }
+
+    /// Remove the first job whose chat has a token to spend and no request in
+    /// flight, spending the token. Interactive jobs are considered before
+    /// bulk ones. If no job is ready, returns how long until one will be, if
+    /// that depends on the rate limits.
+    fn next(
+        &self,
+        now: Instant,
+    ) -> ::std::result::Result<Job, Option<Duration>> {
+        let mut chats = self.inner.chats.borrow_mut();
+        let in_flight = self.inner.in_flight.borrow();
+        let mut soonest: Option<Duration> = None;
+
+        // Chats that have caught up don't need a bucket of their own anymore.
+        chats.retain(|_, x| {
+            x.refill(now);
+            !x.full()
+        });
+
+        for queue in [&self.inner.interactive, &self.inner.bulk].iter() {
+            let mut queue = queue.borrow_mut();
+            let mut ready = None;
+
+            for (idx, job) in queue.iter().enumerate() {
+                let chat_id = match job.chat_id {
+                    Some(x) => x,
+                    None => {
+                        ready = Some(idx);
+                        break;
+                    }
+                };
+
+                // The queue is looked at again once the request finishes.
+                if in_flight.contains(&chat_id) {
+                    continue;
+                }
+
+                let bucket = chats
+                    .entry(chat_id)
+                    .or_insert_with(|| self.chat_bucket());
+
+                let wait = bucket.wait();
+                if wait == Duration::from_secs(0) {
+                    bucket.tokens -= 1.0;
+                    ready = Some(idx);
+                    break;
+                }
+
+                soonest = Some(soonest.map_or(wait, |x| x.min(wait)));
+            }
+
+            if let Some(job) = ready.and_then(|x| queue.remove(x)) {
+                return Ok(job);
+            }
+        }
+
+        Err(soonest)
+    }
+
+    /// Look at the queue again at `at`, unless that's already planned for
+    /// sooner.
+    fn wake_at(&self, at: Instant) {
+        if let Some(x) = self.inner.wakeup.get() {
+            if x <= at {
+                return;
+            }
+        }
+
+        let now = Instant::now();
+        let wait = if at > now { at - now } else { Duration::from_secs(0) };
+
+        let timeout = match Timeout::new(wait, &self.inner.handle) {
+            Ok(x) => x,
+            Err(e) => {
+                error!(self.inner.logger, "unable to schedule outbox: {}", e);
+                return;
+            }
+        };
+
+        self.inner.wakeup.set(Some(at));
+
+        let outbox = self.clone();
+        self.inner.handle.spawn(timeout.then(move |_| {
+            if outbox.inner.wakeup.get() == Some(at) {
+                outbox.inner.wakeup.set(None);
+            }
+
+            outbox.pump();
+            Ok(())
+        }));
+    }
+
+    /// Keep anything else from being sent to `chat_id` (or anywhere, without
+    /// a chat) for `wait`.
+    fn hold(&self, chat_id: Option<i64>, wait: Duration) {
+        match chat_id {
+            Some(x) => self.inner
+                .chats
+                .borrow_mut()
+                .entry(x)
+                .or_insert_with(|| self.chat_bucket())
+                .hold(wait),
+            None => self.inner.global.borrow_mut().hold(wait),
+        }
+    }
+
+    fn run(&self, mut job: Job) {
+        job.attempts += 1;
+
+        if let Some(x) = job.chat_id {
+            self.inner.in_flight.borrow_mut().insert(x);
+        }
+
+        let outbox = self.clone();
+        let request = (job.request)(&self.inner.tg);
+
+        self.inner.handle.spawn(request.then(move |result| {
+            if let Some(x) = job.chat_id {
+                outbox.inner.in_flight.borrow_mut().remove(&x);
+            }
+
+            let err = match result {
+                Ok(()) => {
+                    let _ = job.done.send(Ok(()));
+                    outbox.pump();
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+
+            match retry_after(&err) {
+                Some(wait) if job.attempts < MAX_ATTEMPTS => {
+                    warn!(outbox.inner.logger, "Flood limit hit, waiting {}s",
+                          wait; "chat_id" => format!("{:?}", job.chat_id));
+
+                    // Retries go first, so messages to a chat stay in order.
+                    outbox.hold(job.chat_id, Duration::from_secs(wait));
+                    outbox.queue(job.priority).borrow_mut().push_front(job);
+                    outbox.pump();
+                }
+                _ => {
+                    let _ = job.done.send(Err(err.into()));
+                    outbox.pump();
+                }
+            }
+
+            Ok(())
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_retry_after() {
+        assert_eq!(
+            parse_retry_after("Too Many Requests: retry after 35"),
+            Some(35)
+        );
+        assert_eq!(parse_retry_after("Bad Request: chat not found"), None);
+        assert_eq!(parse_retry_after("retry after soon"), None);
+    }
+
+    #[test]
+    fn bucket_starts_full() {
+        let bucket = Bucket::new(1.0, 3.0);
+
+        assert!(bucket.full());
+        assert_eq!(bucket.wait(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn bucket_waits_for_a_token() {
+        let mut bucket = Bucket::new(2.0, 1.0);
+        bucket.tokens -= 1.0;
+
+        assert!(!bucket.full());
+        assert_eq!(bucket.wait(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_burst() {
+        let mut bucket = Bucket::new(1.0, 3.0);
+        bucket.tokens = 0.0;
+
+        let start = bucket.updated;
+        bucket.refill(start + Duration::from_secs(2));
+        assert!((bucket.tokens - 2.0).abs() < 1e-9);
+        assert!(!bucket.full());
+
+        bucket.refill(start + Duration::from_secs(10));
+        assert!(bucket.full());
+    }
+
+    #[test]
+    fn bucket_holds_for_retry_after() {
+        let mut bucket = Bucket::new(1.0, 3.0);
+        bucket.hold(Duration::from_secs(35));
+
+        assert_eq!(bucket.wait(), Duration::from_secs(35));
+    }
+}
@@ -0,0 +1,182 @@
+use errors::*;
+use outbox::{Outbox, Priority};
+use settings;
+
+use telebot::functions::FunctionGetChatMember;
+
+use futures::{future, Future};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Who may use a command, from least to most privileged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Anyone,
+
+    /// Members of the chat the command is used in.
+    ChatMember,
+
+    /// Administrators of the chat the command is used in. In a private chat,
+    /// the user is considered the administrator.
+    ChatAdmin,
+
+    /// Users listed in `moderator_ids` or `owner_ids`.
+    BotModerator,
+
+    /// Users listed in `owner_ids`.
+    BotOwner,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Level::Anyone => "anyone",
+            Level::ChatMember => "chat_member",
+            Level::ChatAdmin => "chat_admin",
+            Level::BotModerator => "bot_moderator",
+            Level::BotOwner => "bot_owner",
+        }
+    }
+}
+
+type Cache = HashMap<(i64, i64), (Instant, String)>;
+
+/// Decides whether a user holds a permission level in a chat, remembering
+/// chat member lookups for a short while to avoid hammering the API.
+pub struct Permissions {
+    settings: settings::Permissions,
+    cache: Rc<RefCell<Cache>>,
+}
+
+impl Permissions {
+    pub fn new(settings: settings::Permissions) -> Permissions {
+        Permissions {
+            settings: settings,
+            cache: Rc::from(RefCell::from(HashMap::new())),
+        }
+    }
+
+    pub fn owner_ids(&self) -> &[i64] {
+        &self.settings.owner_ids
+    }
+
+    pub fn is_owner(&self, user_id: i64) -> bool {
+        self.settings.owner_ids.contains(&user_id)
+    }
+
+    pub fn is_moderator(&self, user_id: i64) -> bool {
+        self.is_owner(user_id) || self.settings.moderator_ids.contains(&user_id)
+    }
+
+    pub fn check(
+        &self,
+        outbox: &Outbox,
+        chat_id: i64,
+        private: bool,
+        user_id: Option<i64>,
+        level: Level,
+    ) -> Box<Future<Item = bool, Error = Error>> {
+        let user_id = match (level, user_id) {
+            (Level::Anyone, _) => return Box::from(future::ok(true)),
+            (_, None) => return Box::from(future::ok(false)),
+            (_, Some(x)) => x,
+        };
+
+        let allowed = match level {
+            Level::BotOwner => self.is_owner(user_id),
+            Level::BotModerator => self.is_moderator(user_id),
+            _ if private => true,
+            _ => return self.check_member(outbox, chat_id, user_id, level),
+        };
+
+        Box::from(future::ok(allowed))
+    }
+
+    fn check_member(
+        &self,
+        outbox: &Outbox,
+        chat_id: i64,
+        user_id: i64,
+        level: Level,
+    ) -> Box<Future<Item = bool, Error = Error>> {
+        let ttl = Duration::from_secs(self.settings.cache_ttl);
+
+        let cached = {
+            let mut cache = self.cache.borrow_mut();
+            cache.retain(|_, &mut (fetched, _)| fetched.elapsed() < ttl);
+            cache.get(&(chat_id, user_id)).map(|&(_, ref x)| x.clone())
+        };
+
+        if let Some(status) = cached {
+            return Box::from(future::ok(Self::allows(&status, level)));
+        }
+
+        let cache = self.cache.clone();
+        let member = outbox.call(None, Priority::Interactive, move |tg| {
+            Box::from(tg.get_chat_member(chat_id, user_id).send().map(|x| x.1))
+        });
+
+        Box::from(member.map(move |member| {
+            let allowed = Self::allows(&member.status, level);
+            cache.borrow_mut().insert(
+                (chat_id, user_id),
+                (Instant::now(), member.status),
+            );
+            allowed
+        }))
+    }
+
+    fn allows(status: &str, level: Level) -> bool {
+        match (status, level) {
+            ("creator", _) | ("administrator", _) => true,
+            ("member", Level::ChatMember) => true,
+            ("restricted", Level::ChatMember) => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admins_hold_every_chat_level() {
+        for status in &["creator", "administrator"] {
+            assert!(Permissions::allows(status, Level::ChatMember));
+            assert!(Permissions::allows(status, Level::ChatAdmin));
+        }
+    }
+
+    #[test]
+    fn members_are_not_admins() {
+        for status in &["member", "restricted"] {
+            assert!(Permissions::allows(status, Level::ChatMember));
+            assert!(!Permissions::allows(status, Level::ChatAdmin));
+        }
+    }
+
+    #[test]
+    fn former_members_hold_nothing() {
+        for status in &["left", "kicked"] {
+            assert!(!Permissions::allows(status, Level::ChatMember));
+            assert!(!Permissions::allows(status, Level::ChatAdmin));
+        }
+    }
+
+    #[test]
+    fn owners_are_moderators() {
+        let perms = Permissions::new(settings::Permissions {
+            owner_ids: vec![1],
+            moderator_ids: vec![2],
+            ..settings::Permissions::default()
+        });
+
+        assert!(perms.is_owner(1) && perms.is_moderator(1));
+        assert!(!perms.is_owner(2) && perms.is_moderator(2));
+        assert!(!perms.is_moderator(3));
+    }
+}
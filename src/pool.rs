@@ -0,0 +1,387 @@
+use errors::*;
+use settings;
+
+use diesel::prelude::*;
+use diesel::connection::SimpleConnection;
+use diesel::pg::PgConnection;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+use slog;
+
+use tokio_core::reactor::{Handle, Timeout};
+
+use futures::{future, Future};
+use futures::unsync::oneshot;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Add libpq's `connect_timeout` to `url`, unless it already sets one. Both
+/// URIs and `key=value` connection strings are understood.
+fn with_timeout(url: &str, timeout: u64) -> String {
+    if url.contains("connect_timeout=") {
+        return url.to_owned();
+    }
+
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        let separator = if url.contains('?') { '&' } else { '?' };
+        format!("{}{}connect_timeout={}", url, separator, timeout)
+    } else {
+        format!("{} connect_timeout={}", url, timeout)
+    }
+}
+
+struct Idle {
+    conn: PgConnection,
+    since: Instant,
+}
+
+struct Inner {
+    logger: slog::Logger,
+    handle: Handle,
+    url: String,
+    max: usize,
+    idle_timeout: Duration,
+    checkout_timeout: Duration,
+    check_after: Duration,
+
+    /// Connections waiting to be used, most recently returned last.
+    idle: RefCell<Vec<Idle>>,
+
+    /// Connections open, whether idle or checked out.
+    open: Cell<usize>,
+
+    /// Bumped when the pool is reset. Connections opened before then are
+    /// closed instead of being returned.
+    generation: Cell<u64>,
+
+    /// Checkouts waiting for a connection to be returned, oldest first.
+    waiters: RefCell<VecDeque<oneshot::Sender<()>>>,
+}
+
+impl Inner {
+    fn close(&self, conn: PgConnection) {
+        drop(conn);
+        self.open.set(self.open.get() - 1);
+        self.wake();
+    }
+
+    /// Take back a connection that's no longer being used.
+    fn put(&self, conn: PgConnection, generation: u64) {
+        if generation != self.generation.get() {
+            debug!(self.logger, "Closing database connection after reset");
+            return self.close(conn);
+        }
+
+        self.idle.borrow_mut().push(Idle {
+            conn: conn,
+            since: Instant::now(),
+        });
+        self.wake();
+    }
+
+    /// Let the longest waiting checkout that's still waiting know there's a
+    /// connection free.
+    fn wake(&self) {
+        while let Some(x) = self.waiters.borrow_mut().pop_front() {
+            if x.send(()).is_ok() {
+                return;
+            }
+        }
+    }
+}
+
+/// A connection set aside for handling one update, so the handler doesn't
+/// find every connection in use halfway through.
+struct Reserved {
+    conn: RefCell<Option<PgConnection>>,
+    generation: u64,
+    pool: Rc<Inner>,
+}
+
+impl Drop for Reserved {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.borrow_mut().take() {
+            self.pool.put(conn, self.generation);
+        }
+    }
+}
+
+/// Whether `err` may have left the connection it happened on unusable, like
+/// when the database restarts. Constraint violations are the only errors
+/// the database reports that are known to leave the connection working.
+pub fn disconnected(err: &DieselError) -> bool {
+    match *err {
+        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)
+        | DieselError::DatabaseError(
+            DatabaseErrorKind::ForeignKeyViolation,
+            _,
+        ) => false,
+        DieselError::DatabaseError(_, _) => true,
+        _ => false,
+    }
+}
+
+/// Database connections shared by everything that handles updates.
+///
+/// Connections are opened as they're needed, up to `max_connections`. One
+/// that has been idle for a while is checked before it's handed out, and
+/// replaced if the database went away. When a query fails because the
+/// connection broke, the pool is `reset`, so a restarted database is
+/// reconnected to on the next checkout.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Rc<Inner>,
+
+    /// The connection reserved for the update this pool was handed to, if
+    /// any. Checkouts use it first.
+    reserved: Option<Rc<Reserved>>,
+}
+
+impl Pool {
+    pub fn new(
+        logger: slog::Logger,
+        handle: Handle,
+        settings: &settings::Database,
+    ) -> Pool {
+        Pool {
+            inner: Rc::from(Inner {
+                logger: logger,
+                handle: handle,
+                url: with_timeout(&settings.url, settings.connect_timeout),
+                max: settings.max_connections,
+                idle_timeout: Duration::from_secs(settings.idle_timeout),
+                checkout_timeout: Duration::from_secs(
+                    settings.checkout_timeout,
+                ),
+                check_after: Duration::from_secs(settings.check_after),
+                idle: RefCell::from(Vec::new()),
+                open: Cell::from(0),
+                generation: Cell::from(0),
+                waiters: RefCell::from(VecDeque::new()),
+            }),
+            reserved: None,
+        }
+    }
+
+    /// Check out a working connection, which goes back to the pool when
+    /// dropped. Fails straight away if all of them are in use.
+    pub fn get(&self) -> Result<Conn> {
+        let inner = &self.inner;
+
+        if let Some(ref reserved) = self.reserved {
+            if let Some(conn) = reserved.conn.borrow_mut().take() {
+                return Ok(Conn {
+                    conn: Some(conn),
+                    generation: reserved.generation,
+                    pool: inner.clone(),
+                    reserved: Some(reserved.clone()),
+                });
+            }
+        }
+
+        loop {
+            let idle = match inner.idle.borrow_mut().pop() {
+                Some(x) => x,
+                None => break,
+            };
+
+            let unused = idle.since.elapsed();
+
+            if unused >= inner.idle_timeout {
+                debug!(inner.logger, "Closing idle database connection");
+                inner.close(idle.conn);
+                continue;
+            }
+
+            if unused < inner.check_after {
+                return Ok(self.wrap(idle.conn));
+            }
+
+            match idle.conn.batch_execute("SELECT 1") {
+                Ok(()) => return Ok(self.wrap(idle.conn)),
+                Err(e) => {
+                    warn!(inner.logger, "Dropping database connection: {}", e);
+                    inner.close(idle.conn);
+                }
+            }
+        }
+
+        if inner.open.get() >= inner.max {
+            bail!(ErrorKind::PoolExhausted(inner.max));
+        }
+
+        let conn = PgConnection::establish(&inner.url)
+            .chain_err(|| ErrorKind::DatabaseUnavailable)?;
+
+        inner.open.set(inner.open.get() + 1);
+        debug!(inner.logger, "Opened database connection";
+               "open" => inner.open.get());
+
+        Ok(self.wrap(conn))
+    }
+
+    /// Set a connection aside for handling one update, waiting up to
+    /// `checkout_timeout` for one if all of them are in use. Checkouts from
+    /// the returned pool use that connection while it's free, and it goes
+    /// back once every clone of the returned pool is dropped.
+    pub fn reserve(&self) -> Box<Future<Item = Pool, Error = Error>> {
+        let deadline = Instant::now() + self.inner.checkout_timeout;
+        let inner = self.inner.clone();
+
+        Box::from(self.checkout(deadline).map(move |mut conn| {
+            let reserved = Reserved {
+                conn: RefCell::from(conn.conn.take()),
+                generation: conn.generation,
+                pool: inner.clone(),
+            };
+
+            Pool {
+                inner: inner,
+                reserved: Some(Rc::from(reserved)),
+            }
+        }))
+    }
+
+    /// Check out a connection like `get`, but if all of them are in use, wait
+    /// until `deadline` for one to be returned. Another checkout can take the
+    /// one a waiter was woken for, so it goes back to waiting if it missed.
+    fn checkout(
+        &self,
+        deadline: Instant,
+    ) -> Box<Future<Item = Conn, Error = Error>> {
+        let inner = &self.inner;
+
+        if !inner.idle.borrow().is_empty() || inner.open.get() < inner.max {
+            return Box::from(future::result(self.get()));
+        }
+
+        if Instant::now() >= deadline {
+            return Box::from(future::err(
+                ErrorKind::PoolExhausted(inner.max).into(),
+            ));
+        }
+
+        let timeout = match Timeout::new_at(deadline, &inner.handle) {
+            Ok(x) => x,
+            Err(e) => {
+                return Box::from(future::err(Error::with_chain(
+                    e,
+                    "unable to create checkout timer",
+                )))
+            }
+        };
+
+        let (waiter, returned) = oneshot::channel();
+        inner.waiters.borrow_mut().push_back(waiter);
+
+        let returned = returned.then(|_| -> Result<()> { Ok(()) });
+        let expired = timeout.then(|_| -> Result<()> { Ok(()) });
+
+        let pool = self.clone();
+        Box::from(
+            returned
+                .select(expired)
+                .map_err(|(e, _)| e)
+                .and_then(move |_| pool.checkout(deadline)),
+        )
+    }
+
+    /// Close every connection, because the database went away. Ones in use
+    /// are closed when they're returned, and new ones are opened as they're
+    /// needed.
+    pub fn reset(&self) {
+        let inner = &self.inner;
+        inner.generation.set(inner.generation.get() + 1);
+
+        let idle: Vec<_> = inner.idle.borrow_mut().drain(..).collect();
+        if !idle.is_empty() {
+            warn!(inner.logger, "Closing {} idle database connections",
+                  idle.len());
+        }
+
+        for x in idle {
+            inner.close(x.conn);
+        }
+    }
+
+    fn wrap(&self, conn: PgConnection) -> Conn {
+        Conn {
+            conn: Some(conn),
+            generation: self.inner.generation.get(),
+            pool: self.inner.clone(),
+            reserved: None,
+        }
+    }
+
+    /// How many connections are open, and how many of those are idle.
+    pub fn size(&self) -> (usize, usize) {
+        (self.inner.open.get(), self.inner.idle.borrow().len())
+    }
+}
+
+/// A connection checked out of a `Pool`.
+pub struct Conn {
+    conn: Option<PgConnection>,
+    generation: u64,
+    pool: Rc<Inner>,
+
+    /// Where the connection goes back to, if it was reserved for an update.
+    reserved: Option<Rc<Reserved>>,
+}
+
+impl Deref for Conn {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        self.conn.as_ref().expect("connection already returned")
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        let conn = match self.conn.take() {
+            Some(x) => x,
+            None => return,
+        };
+
+        match self.reserved {
+            Some(ref x) => *x.conn.borrow_mut() = Some(conn),
+            None => self.pool.put(conn, self.generation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_timeout_to_uris() {
+        assert_eq!(
+            with_timeout("postgres://localhost/entice", 5),
+            "postgres://localhost/entice?connect_timeout=5"
+        );
+        assert_eq!(
+            with_timeout("postgresql://localhost/entice?sslmode=require", 5),
+            "postgresql://localhost/entice?sslmode=require&connect_timeout=5"
+        );
+    }
+
+    #[test]
+    fn adds_timeout_to_connection_strings() {
+        assert_eq!(
+            with_timeout("host=localhost dbname=entice", 5),
+            "host=localhost dbname=entice connect_timeout=5"
+        );
+    }
+
+    #[test]
+    fn keeps_existing_timeout() {
+        let url = "postgres://localhost/entice?connect_timeout=1";
+        assert_eq!(with_timeout(url, 5), url);
+    }
+}
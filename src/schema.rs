@@ -4,5 +4,104 @@ table! {
         title -> Varchar,
         description -> Varchar,
         updated_at -> Timestamptz,
+        language -> Nullable<Varchar>,
+        active -> Bool,
+        configured_by -> Nullable<Int8>,
+        approval_mode -> Varchar,
+        nomination_ttl -> Nullable<Int8>,
+        welcome -> Nullable<Text>,
+        notify_chat_id -> Nullable<Int8>,
+        disabled -> Bool,
     }
 }
+
+table! {
+    users (id) {
+        id -> Int8,
+        language -> Nullable<Varchar>,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    chat_templates (chat_id, name) {
+        chat_id -> Int8,
+        name -> Varchar,
+        body -> Text,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    nominations (id) {
+        id -> Int4,
+        chat_id -> Int8,
+        nominator_id -> Int8,
+        nominee_id -> Nullable<Int8>,
+        reason -> Varchar,
+        status -> Varchar,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        inline_message_id -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    votes (nomination_id, user_id) {
+        nomination_id -> Int4,
+        user_id -> Int8,
+        approve -> Bool,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    broadcasts (id) {
+        id -> Int4,
+        body -> Text,
+        language -> Nullable<Varchar>,
+        approval_mode -> Nullable<Varchar>,
+        created_by -> Int8,
+        created_at -> Timestamptz,
+        started_at -> Nullable<Timestamptz>,
+        finished_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    deliveries (broadcast_id, chat_id) {
+        broadcast_id -> Int4,
+        chat_id -> Int8,
+        status -> Varchar,
+        error -> Nullable<Text>,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    dead_letters (id) {
+        id -> Int4,
+        update_id -> Int8,
+        payload -> Text,
+        error -> Text,
+        attempts -> Int4,
+        status -> Varchar,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+joinable!(chat_templates -> chats (chat_id));
+joinable!(nominations -> chats (chat_id));
+joinable!(votes -> nominations (nomination_id));
+joinable!(deliveries -> broadcasts (broadcast_id));
+joinable!(deliveries -> chats (chat_id));
+allow_tables_to_appear_in_same_query!(
+    chats,
+    chat_templates,
+    nominations,
+    votes,
+    broadcasts,
+    deliveries,
+    dead_letters
+);
@@ -33,6 +33,416 @@ impl TelegramBot {
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Database {
     pub url: String,
+
+    /// Most connections open at once.
+    #[serde(default = "Database::default_max_connections")]
+    pub max_connections: usize,
+
+    /// Seconds to wait for the database to accept a connection.
+    #[serde(default = "Database::default_connect_timeout")]
+    pub connect_timeout: u64,
+
+    /// Seconds a connection may sit unused before it's closed.
+    #[serde(default = "Database::default_idle_timeout")]
+    pub idle_timeout: u64,
+
+    /// Seconds an update waits for a connection when all of them are in use,
+    /// before giving up.
+    #[serde(default = "Database::default_checkout_timeout")]
+    pub checkout_timeout: u64,
+
+    /// Seconds a connection may sit unused before it's checked again on
+    /// checkout. Connections used more recently are assumed to still work.
+    #[serde(default = "Database::default_check_after")]
+    pub check_after: u64,
+}
+
+impl Database {
+    fn default_max_connections() -> usize {
+        8
+    }
+
+    fn default_connect_timeout() -> u64 {
+        5
+    }
+
+    fn default_idle_timeout() -> u64 {
+        300
+    }
+
+    fn default_checkout_timeout() -> u64 {
+        10
+    }
+
+    fn default_check_after() -> u64 {
+        30
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Templates {
+    #[serde(default)]
+    pub path: Option<String>,
+
+    #[serde(default = "Templates::default_reload_interval")]
+    pub reload_interval: u64,
+}
+
+impl Templates {
+    fn default_reload_interval() -> u64 {
+        5
+    }
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            path: None,
+            reload_interval: Self::default_reload_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Permissions {
+    #[serde(default)]
+    pub owner_ids: Vec<i64>,
+
+    #[serde(default)]
+    pub moderator_ids: Vec<i64>,
+
+    #[serde(default = "Permissions::default_cache_ttl")]
+    pub cache_ttl: u64,
+}
+
+impl Permissions {
+    fn default_cache_ttl() -> u64 {
+        60
+    }
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions {
+            owner_ids: Vec::new(),
+            moderator_ids: Vec::new(),
+            cache_ttl: Self::default_cache_ttl(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Middleware {
+    /// Names of the middleware layers to run updates through, outermost
+    /// first.
+    #[serde(default = "Middleware::default_layers")]
+    pub layers: Vec<String>,
+
+    #[serde(default = "Middleware::default_throttle_limit")]
+    pub throttle_limit: u32,
+
+    #[serde(default = "Middleware::default_throttle_window")]
+    pub throttle_window: u64,
+}
+
+impl Middleware {
+    fn default_layers() -> Vec<String> {
+        vec!["logging".to_owned(), "metrics".to_owned(), "throttle".to_owned()]
+    }
+
+    fn default_throttle_limit() -> u32 {
+        30
+    }
+
+    fn default_throttle_window() -> u64 {
+        60
+    }
+}
+
+impl Default for Middleware {
+    fn default() -> Self {
+        Middleware {
+            layers: Self::default_layers(),
+            throttle_limit: Self::default_throttle_limit(),
+            throttle_window: Self::default_throttle_window(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Nominations {
+    /// Seconds a nomination stays open before it expires.
+    #[serde(default = "Nominations::default_ttl")]
+    pub ttl: u64,
+
+    /// Seconds between checks for expired nominations.
+    #[serde(default = "Nominations::default_expire_interval")]
+    pub expire_interval: u64,
+}
+
+impl Nominations {
+    fn default_ttl() -> u64 {
+        7 * 24 * 60 * 60
+    }
+
+    fn default_expire_interval() -> u64 {
+        60
+    }
+}
+
+impl Default for Nominations {
+    fn default() -> Self {
+        Nominations {
+            ttl: Self::default_ttl(),
+            expire_interval: Self::default_expire_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Broadcasts {
+    /// Most broadcast messages sent per second, across all chats.
+    #[serde(default = "Broadcasts::default_rate")]
+    pub rate: u32,
+
+    /// Fewest seconds between two broadcast messages to the same chat.
+    #[serde(default = "Broadcasts::default_chat_interval")]
+    pub chat_interval: u64,
+
+    /// Most messages waiting in the outbox before broadcasts hold off.
+    #[serde(default = "Broadcasts::default_max_queued")]
+    pub max_queued: usize,
+}
+
+impl Broadcasts {
+    fn default_rate() -> u32 {
+        20
+    }
+
+    fn default_chat_interval() -> u64 {
+        3
+    }
+
+    fn default_max_queued() -> usize {
+        100
+    }
+}
+
+impl Default for Broadcasts {
+    fn default() -> Self {
+        Broadcasts {
+            rate: Self::default_rate(),
+            chat_interval: Self::default_chat_interval(),
+            max_queued: Self::default_max_queued(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Supervisor {
+    /// Seconds to wait before the first restart after the event loop fails.
+    /// Each restart in a row waits twice as long as the last.
+    #[serde(default = "Supervisor::default_initial_backoff")]
+    pub initial_backoff: u64,
+
+    /// Longest wait between restarts, in seconds.
+    #[serde(default = "Supervisor::default_max_backoff")]
+    pub max_backoff: u64,
+
+    /// Most restarts allowed within `restart_window` before giving up.
+    #[serde(default = "Supervisor::default_max_restarts")]
+    pub max_restarts: u32,
+
+    /// Seconds over which restarts are counted.
+    #[serde(default = "Supervisor::default_restart_window")]
+    pub restart_window: u64,
+}
+
+impl Supervisor {
+    fn default_initial_backoff() -> u64 {
+        1
+    }
+
+    fn default_max_backoff() -> u64 {
+        300
+    }
+
+    fn default_max_restarts() -> u32 {
+        5
+    }
+
+    fn default_restart_window() -> u64 {
+        3600
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Supervisor {
+            initial_backoff: Self::default_initial_backoff(),
+            max_backoff: Self::default_max_backoff(),
+            max_restarts: Self::default_max_restarts(),
+            restart_window: Self::default_restart_window(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Startup {
+    /// Seconds to wait before asking Telegram about the bot again, after the
+    /// first attempt fails. Each retry waits twice as long as the last.
+    #[serde(default = "Startup::default_initial_backoff")]
+    pub initial_backoff: u64,
+
+    /// Longest wait between attempts, in seconds.
+    #[serde(default = "Startup::default_max_backoff")]
+    pub max_backoff: u64,
+
+    /// Most updates kept while starting up. Once full, the oldest are
+    /// dropped to make room.
+    #[serde(default = "Startup::default_pending")]
+    pub pending: usize,
+}
+
+impl Startup {
+    fn default_initial_backoff() -> u64 {
+        1
+    }
+
+    fn default_max_backoff() -> u64 {
+        60
+    }
+
+    fn default_pending() -> usize {
+        1000
+    }
+}
+
+impl Default for Startup {
+    fn default() -> Self {
+        Startup {
+            initial_backoff: Self::default_initial_backoff(),
+            max_backoff: Self::default_max_backoff(),
+            pending: Self::default_pending(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DeadLetters {
+    /// How many times an update is handled before giving up and storing it
+    /// as a dead letter, when it fails for a reason that may pass, like
+    /// losing the database connection. Other failures are stored straight
+    /// away.
+    #[serde(default = "DeadLetters::default_attempts")]
+    pub attempts: u32,
+}
+
+impl DeadLetters {
+    fn default_attempts() -> u32 {
+        3
+    }
+}
+
+impl Default for DeadLetters {
+    fn default() -> Self {
+        DeadLetters {
+            attempts: Self::default_attempts(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Outbox {
+    /// Most messages sent per second, across all chats.
+    #[serde(default = "Outbox::default_global_per_second")]
+    pub global_per_second: u32,
+
+    /// Most messages sent to one chat per minute, once its burst is used up.
+    #[serde(default = "Outbox::default_chat_per_minute")]
+    pub chat_per_minute: u32,
+
+    /// Messages that can be sent to one chat in quick succession.
+    #[serde(default = "Outbox::default_chat_burst")]
+    pub chat_burst: u32,
+}
+
+impl Outbox {
+    fn default_global_per_second() -> u32 {
+        30
+    }
+
+    fn default_chat_per_minute() -> u32 {
+        20
+    }
+
+    fn default_chat_burst() -> u32 {
+        3
+    }
+
+    /// Check that every limit lets messages through at all.
+    fn validate(&self) -> Result<()> {
+        let limits = [
+            ("global_per_second", self.global_per_second),
+            ("chat_per_minute", self.chat_per_minute),
+            ("chat_burst", self.chat_burst),
+        ];
+
+        for &(name, value) in limits.iter() {
+            if value == 0 {
+                bail!("outbox.{} must be greater than 0", name);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Outbox {
+            global_per_second: Self::default_global_per_second(),
+            chat_per_minute: Self::default_chat_per_minute(),
+            chat_burst: Self::default_chat_burst(),
+        }
+    }
+}
+
+/// Who may add the bot to a group.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinPolicy {
+    /// Any admin of a group.
+    Open,
+    /// Only the bot's owners and moderators.
+    OwnerApproved,
+    /// Only the users and groups listed in `allowlist`.
+    Allowlist,
+}
+
+impl Default for JoinPolicy {
+    fn default() -> Self {
+        JoinPolicy::Open
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Join {
+    #[serde(default)]
+    pub policy: JoinPolicy,
+
+    /// User and chat ids let in under the `allowlist` policy.
+    #[serde(default)]
+    pub allowlist: Vec<i64>,
+}
+
+impl Default for Join {
+    fn default() -> Self {
+        Join {
+            policy: JoinPolicy::default(),
+            allowlist: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -40,11 +450,43 @@ pub struct Settings {
     pub telegram_bot: TelegramBot,
     pub telegram_client: TelegramClient,
     pub database: Database,
+
+    #[serde(default)]
+    pub templates: Templates,
+
+    #[serde(default)]
+    pub permissions: Permissions,
+
+    #[serde(default)]
+    pub middleware: Middleware,
+
+    #[serde(default)]
+    pub nominations: Nominations,
+
+    #[serde(default)]
+    pub join: Join,
+
+    #[serde(default)]
+    pub broadcasts: Broadcasts,
+
+    #[serde(default)]
+    pub outbox: Outbox,
+
+    #[serde(default)]
+    pub dead_letters: DeadLetters,
+
+    #[serde(default)]
+    pub supervisor: Supervisor,
+
+    #[serde(default)]
+    pub startup: Startup,
 }
 
 impl Settings {
     pub fn try_fetch() -> Result<Self> {
-        Ok(SETTINGS.read().unwrap().clone().try_into::<Settings>()?)
+        let settings: Settings = SETTINGS.read().unwrap().clone().try_into()?;
+        settings.outbox.validate()?;
+        Ok(settings)
     }
 
     pub fn add_file(name: &str) -> Result<()> {
@@ -0,0 +1,386 @@
+use args;
+use conversation::{Conversations, Step};
+use entice::Context;
+use errors::*;
+use format::Rendered;
+use membership;
+use models::approval;
+use templates::{self, Templates};
+
+use diesel;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+
+use outbox::{Outbox, Priority, Sent};
+
+use serde_json::Value;
+
+use telebot::functions::FunctionGetChatMember;
+
+use futures::{future, Future};
+
+use std::rc::Rc;
+
+/// Prefix of the `/start` payload that sets up a group, followed by the
+/// group's chat id, as in `/start setup_-1001234`.
+pub const START_PREFIX: &'static str = "setup_";
+
+/// The questions asked while setting up a group, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    ApprovalMode,
+    Ttl,
+    Welcome,
+    NotifyChat,
+}
+
+/// What happened to an answer to one of the questions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Invalid,
+    Next,
+    Done,
+}
+
+/// Answers given so far while setting up a group. Nothing is saved until the
+/// last question has been answered, so abandoning the setup changes nothing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setup {
+    chat_id: i64,
+    stage: Stage,
+    approval_mode: &'static str,
+    ttl: Option<(i64, String)>,
+    welcome: Option<String>,
+    notify_chat_id: Option<i64>,
+}
+
+impl Setup {
+    fn new(chat_id: i64) -> Setup {
+        Setup {
+            chat_id: chat_id,
+            stage: Stage::ApprovalMode,
+            approval_mode: approval::ADMIN,
+            ttl: None,
+            welcome: None,
+            notify_chat_id: None,
+        }
+    }
+
+    fn template(&self) -> &'static str {
+        match self.stage {
+            Stage::ApprovalMode => templates::SETUP_APPROVAL_MODE,
+            Stage::Ttl => templates::SETUP_TTL,
+            Stage::Welcome => templates::SETUP_WELCOME,
+            Stage::NotifyChat => templates::SETUP_NOTIFY_CHAT,
+        }
+    }
+
+    /// Apply the answer to the current question, moving on to the next one
+    /// if it was understood. Every question but the first can be skipped.
+    fn apply(&mut self, answer: &str) -> Outcome {
+        let answer = answer.trim();
+        let skip = answer == "-" || answer.eq_ignore_ascii_case("skip");
+
+        match self.stage {
+            Stage::ApprovalMode => {
+                self.approval_mode = match answer.to_lowercase().as_str() {
+                    approval::ADMIN => approval::ADMIN,
+                    approval::AUTO => approval::AUTO,
+                    _ => return Outcome::Invalid,
+                };
+                self.stage = Stage::Ttl;
+            }
+            Stage::Ttl => {
+                if !skip {
+                    let ttl = match args::parse_duration(answer) {
+                        Some(x) => x,
+                        None => return Outcome::Invalid,
+                    };
+                    self.ttl = Some((ttl.as_secs() as i64, answer.to_owned()));
+                }
+                self.stage = Stage::Welcome;
+            }
+            Stage::Welcome => {
+                if answer.is_empty() {
+                    return Outcome::Invalid;
+                }
+                if !skip {
+                    self.welcome = Some(answer.to_owned());
+                }
+                self.stage = Stage::NotifyChat;
+            }
+            Stage::NotifyChat => {
+                if !skip {
+                    match answer.parse() {
+                        Ok(x) => self.notify_chat_id = Some(x),
+                        Err(_) => return Outcome::Invalid,
+                    }
+                }
+                return Outcome::Done;
+            }
+        }
+
+        Outcome::Next
+    }
+
+    fn data(&self, group: &str, invalid: bool) -> Value {
+        json!({
+            "group": group,
+            "invalid": invalid,
+            "approval_mode": self.approval_mode,
+            "ttl": self.ttl.as_ref().map(|&(_, ref x)| x),
+            "welcome": self.welcome,
+            "notify_chat_id": self.notify_chat_id,
+        })
+    }
+
+    fn save(&self, db: &PgConnection, user_id: i64) -> Result<()> {
+        use schema::chats::dsl;
+
+        diesel::update(dsl::chats.find(self.chat_id))
+            .set((
+                dsl::approval_mode.eq(self.approval_mode),
+                dsl::nomination_ttl.eq(self.ttl.as_ref().map(|&(x, _)| x)),
+                dsl::welcome.eq(self.welcome.as_ref().map(String::as_str)),
+                dsl::notify_chat_id.eq(self.notify_chat_id),
+            ))
+            .execute(db)?;
+
+        membership::configured(db, self.chat_id, user_id)
+    }
+}
+
+fn group(db: &PgConnection, chat_id: i64) -> Result<String> {
+    use schema::chats::dsl;
+
+    Ok(dsl::chats
+        .find(chat_id)
+        .select(dsl::title)
+        .first(db)?)
+}
+
+/// Whether `user_id` administers `chat_id` and the bot is in it, so approval
+/// requests can be posted there. Chats that can't be looked up don't count.
+fn verify(
+    outbox: &Outbox,
+    chat_id: i64,
+    user_id: i64,
+    bot_id: i64,
+) -> Box<Future<Item = bool, Error = Error>> {
+    let user = outbox.call(None, Priority::Interactive, move |tg| {
+        Box::from(
+            tg.get_chat_member(chat_id, user_id)
+                .send()
+                .map(|(_, x)| membership::is_admin(&x.status)),
+        )
+    });
+    let bot = outbox.call(None, Priority::Interactive, move |tg| {
+        Box::from(
+            tg.get_chat_member(chat_id, bot_id)
+                .send()
+                .map(|(_, x)| membership::is_member(&x.status)),
+        )
+    });
+
+    Box::from(
+        user.join(bot)
+            .map(|(x, y)| x && y)
+            .or_else(|_| -> Result<bool> { Ok(false) }),
+    )
+}
+
+/// Start setting up `chat_id`, asking the first question in a private chat
+/// with `user_id`. Fails if the user has never started a chat with the bot,
+/// in which case no conversation is started.
+pub fn begin(
+    outbox: &Outbox,
+    tpl: &Templates,
+    db: &PgConnection,
+    conversations: &Rc<Conversations>,
+    user_id: i64,
+    chat_id: i64,
+    language: &str,
+) -> Sent {
+    let setup = Setup::new(chat_id);
+
+    let text = group(db, chat_id)
+        .and_then(|x| {
+            tpl.render_in(setup.template(), language, &setup.data(&x, false))
+        })
+        .unwrap_or_else(|e| tpl.fallback(e));
+
+    let conversations = conversations.clone();
+    Box::from(text.send(outbox, user_id).map(move |_| {
+        // In a private chat, the chat id is the user id.
+        conversations.start(user_id, user_id, Step::Setup(setup));
+    }))
+}
+
+/// Ask the current question, and wait for the answer.
+fn ask(
+    tpl: &Templates,
+    conversations: &Conversations,
+    chat_id: i64,
+    user_id: i64,
+    setup: Setup,
+    data: &Value,
+    language: &str,
+) -> Result<Rendered> {
+    let reply = tpl.render_in(setup.template(), language, data);
+
+    conversations.start(chat_id, user_id, Step::Setup(setup));
+
+    reply
+}
+
+/// Save every answer, and sum them up.
+fn finish(
+    tpl: &Templates,
+    db: &PgConnection,
+    setup: &Setup,
+    group: &str,
+    user_id: i64,
+    language: &str,
+) -> Result<Rendered> {
+    setup.save(db, user_id)?;
+
+    tpl.render_in(templates::SETUP_DONE, language, &setup.data(group, false))
+}
+
+/// Handle the answer to the current question, replying with the next one,
+/// the same one again if the answer wasn't understood, or a summary once
+/// everything has been saved.
+///
+/// A chat given for approval requests is only accepted if the user
+/// administers it and the bot is in it.
+pub fn answer(
+    ctx: &Context,
+    chat_id: i64,
+    user_id: i64,
+    mut setup: Setup,
+    text: &str,
+    language: &str,
+) -> Box<Future<Item = Rendered, Error = Error>> {
+    let tpl = &ctx.templates;
+
+    let db = match ctx.db.get() {
+        Ok(x) => x,
+        Err(e) => return Box::from(future::err(e)),
+    };
+    let group = match group(&db, setup.chat_id) {
+        Ok(x) => x,
+        Err(e) => return Box::from(future::err(e)),
+    };
+
+    let invalid = match setup.apply(text) {
+        Outcome::Invalid => true,
+        Outcome::Next => false,
+        Outcome::Done => {
+            let target = match setup.notify_chat_id {
+                Some(x) if x != setup.chat_id => x,
+                _ => {
+                    return Box::from(future::result(finish(
+                        tpl,
+                        &db,
+                        &setup,
+                        &group,
+                        user_id,
+                        language,
+                    )))
+                }
+            };
+
+            let tpl = tpl.clone();
+            let pool = ctx.db.clone();
+            let conversations = ctx.conversations.clone();
+            let language = language.to_owned();
+
+            let verified = verify(&ctx.outbox, target, user_id, ctx.user.id);
+            return Box::from(verified.and_then(move |verified| {
+                if verified {
+                    let conn = pool.get()?;
+                    return finish(&tpl, &conn, &setup, &group, user_id,
+                                  &language);
+                }
+
+                // Ask again rather than save a chat where approval requests
+                // couldn't be posted.
+                setup.notify_chat_id = None;
+                let mut data = setup.data(&group, false);
+                data["unverified"] = json!(true);
+
+                ask(&tpl, &conversations, chat_id, user_id, setup, &data,
+                    &language)
+            }));
+        }
+    };
+
+    let data = setup.data(&group, invalid);
+    Box::from(future::result(ask(
+        tpl,
+        &ctx.conversations,
+        chat_id,
+        user_id,
+        setup,
+        &data,
+        language,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asks_every_question_in_order() {
+        let mut setup = Setup::new(-1);
+
+        assert_eq!(setup.apply(" Auto "), Outcome::Next);
+        assert_eq!(setup.stage, Stage::Ttl);
+        assert_eq!(setup.apply("3d"), Outcome::Next);
+        assert_eq!(setup.stage, Stage::Welcome);
+        assert_eq!(setup.apply("Hi there"), Outcome::Next);
+        assert_eq!(setup.stage, Stage::NotifyChat);
+        assert_eq!(setup.apply("-100"), Outcome::Done);
+
+        assert_eq!(setup.approval_mode, approval::AUTO);
+        assert_eq!(setup.ttl, Some((3 * 24 * 60 * 60, "3d".to_owned())));
+        assert_eq!(setup.welcome, Some("Hi there".to_owned()));
+        assert_eq!(setup.notify_chat_id, Some(-100));
+    }
+
+    #[test]
+    fn skips_optional_questions() {
+        let mut setup = Setup::new(-1);
+
+        assert_eq!(setup.apply("admin"), Outcome::Next);
+        assert_eq!(setup.apply("skip"), Outcome::Next);
+        assert_eq!(setup.apply("-"), Outcome::Next);
+        assert_eq!(setup.apply("SKIP"), Outcome::Done);
+
+        assert_eq!(setup.ttl, None);
+        assert_eq!(setup.welcome, None);
+        assert_eq!(setup.notify_chat_id, None);
+    }
+
+    #[test]
+    fn repeats_questions_it_did_not_understand() {
+        let mut setup = Setup::new(-1);
+
+        assert_eq!(setup.apply("skip"), Outcome::Invalid);
+        assert_eq!(setup.apply("sometimes"), Outcome::Invalid);
+        assert_eq!(setup.stage, Stage::ApprovalMode);
+
+        setup.apply("admin");
+        assert_eq!(setup.apply("soon"), Outcome::Invalid);
+        assert_eq!(setup.stage, Stage::Ttl);
+
+        setup.apply("1h");
+        assert_eq!(setup.apply("  "), Outcome::Invalid);
+        assert_eq!(setup.stage, Stage::Welcome);
+
+        setup.apply("Hi");
+        assert_eq!(setup.apply("the other group"), Outcome::Invalid);
+        assert_eq!(setup.stage, Stage::NotifyChat);
+    }
+}
@@ -1,59 +1,83 @@
 use slog;
 use diesel;
-use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use diesel::prelude::*;
 use templates;
+use locale;
 use entice::Context;
 use errors::*;
-use telebot::bot;
 use telebot::objects::*;
 use telebot::functions::*;
 
 use models::{Chat as EnticeChat, NewChat as NewEnticeChat};
+use models::NewChatTemplate;
+use conversation::Step;
+use commands::Commands;
+use callbacks::{Action, Callbacks};
+use nominations;
+use membership;
+use setup;
+use format::{self, Rendered};
+use outbox::Priority;
+use permissions::{Level, Permissions};
+use settings;
 
 use erased_serde::Serialize;
 
 use futures::{future, Future};
 
-const QUERY_REPLY_TEXT: &'static str = "I'm nominating you for invitation to \
-                                        {group}.\n\nAfter pressing the button \
-                                        below, you must also press the Start \
-                                        button.";
+use std::rc::Rc;
+
+/// A chat to offer a nomination into from an inline query: its id, title,
+/// and the message that would be sent.
+type Offer = (i64, String, Rendered);
 
 pub struct Handler {
     logger: slog::Logger,
-    tg: bot::RcBot,
+    commands: Rc<Commands>,
+    callbacks: Rc<Callbacks>,
+    perms: Rc<Permissions>,
+    join: settings::Join,
 }
 
 impl Handler {
-    pub fn new(logger: slog::Logger, tg: bot::RcBot) -> Handler {
+    pub fn new(
+        logger: slog::Logger,
+        commands: Rc<Commands>,
+        callbacks: Rc<Callbacks>,
+        perms: Rc<Permissions>,
+        join: settings::Join,
+    ) -> Handler {
         Handler {
             logger: logger,
-            tg: tg,
+            commands: commands,
+            callbacks: callbacks,
+            perms: perms,
+            join: join,
         }
     }
 
     pub fn dispatch(
         &self,
-        ctx: &Option<Context>,
+        ctx: &Context,
         upd: Update,
     ) -> Box<Future<Item = (), Error = Error>> {
-        let ctx = match ctx {
-            &None => {
-                warn!(self.logger, "no context yet");
-                return Box::from(future::ok(()));
-            }
-            &Some(ref x) => x,
-        };
-
         if let Some(inline) = upd.inline_query {
             debug!(self.logger, "inline: {:?}", inline);
             return self.handle_inline_query(inline, ctx);
         }
 
+        if let Some(result) = upd.chosen_inline_result {
+            debug!(self.logger, "chosen inline result: {:?}", result);
+            return self.handle_chosen_inline_result(result, ctx);
+        }
+
+        if let Some(update) = upd.my_chat_member {
+            return self.handle_my_chat_member(update, ctx);
+        }
+
         if let Some(query) = upd.callback_query {
             debug!(self.logger, "callback: {:?}", query);
-            return self.handle_callback_query(query);
+            return self.callbacks.dispatch(ctx, query);
         }
 
         if let Some(msg) = upd.message {
@@ -70,6 +94,11 @@ impl Handler {
         msg: ::telebot::objects::Message,
         ctx: &Context,
     ) -> Box<Future<Item = (), Error = Error>> {
+        let msg = match self.commands.dispatch(ctx, msg) {
+            Ok(reply) => return reply,
+            Err(msg) => msg,
+        };
+
         let matches = if let Some(ref user) = msg.new_chat_member {
             user.id == ctx.user.id
         } else {
@@ -90,24 +119,135 @@ impl Handler {
             return self.handle_left_chat(msg, ctx);
         }
 
+        let step = match msg.from {
+            Some(ref user) => ctx.conversations.take(msg.chat.id, user.id),
+            None => None,
+        };
+
+        if let Some(step) = step {
+            return self.handle_step(step, msg, ctx);
+        }
+
         Box::from(future::ok(()))
     }
 
-    fn handle_left_chat(
+    /// Complete a prompt started by an earlier command, using `msg` as the
+    /// user's answer.
+    fn handle_step(
         &self,
+        step: Step,
         msg: ::telebot::objects::Message,
         ctx: &Context,
     ) -> Box<Future<Item = (), Error = Error>> {
-        use schema::chats::dsl::*;
+        debug!(self.logger, "Conversation step {:?}: {:?}", step, msg);
+
+        // Steps are only ever taken for messages with a sender.
+        let user_id = match msg.from {
+            Some(ref x) => x.id,
+            None => return Box::from(future::ok(())),
+        };
+
+        let language = ctx.db.get().and_then(|db| {
+            locale::resolve(&db, msg.from.as_ref(), Some(msg.chat.id))
+        });
+
+        let body = msg.text.as_ref().map(String::as_str).unwrap_or("");
+
+        let text: Box<Future<Item = Rendered, Error = Error>> = match step {
+            Step::Setup(x) => match language {
+                Ok(language) => setup::answer(
+                    ctx,
+                    msg.chat.id,
+                    user_id,
+                    x,
+                    body,
+                    &language,
+                ),
+                Err(e) => Box::from(future::err(e)),
+            },
+            Step::SetTemplate { name } => {
+                Box::from(future::result(language.and_then(|language| {
+                    self.set_template(
+                        msg.chat.id,
+                        user_id,
+                        &name,
+                        body,
+                        &language,
+                        ctx,
+                    )
+                })))
+            }
+        };
+
+        let tpl = ctx.templates.clone();
+        let outbox = ctx.outbox.clone();
+        let chat_id = msg.chat.id;
+        Box::from(text.then(move |text| {
+            text.unwrap_or_else(|e| tpl.fallback(e)).send(&outbox, chat_id)
+        }))
+    }
+
+    fn set_template(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        name: &str,
+        body: &str,
+        language: &str,
+        ctx: &Context,
+    ) -> Result<Rendered> {
+        use schema::chat_templates::dsl;
+
+        if let Err(e) = ctx.templates.validate(body) {
+            return ctx.templates.render_in(
+                templates::REPLY_TEMPLATE_INVALID,
+                language,
+                &json!({ "error": e.to_string() }),
+            );
+        }
+
+        let new_template = NewChatTemplate {
+            chat_id: chat_id,
+            name: name,
+            body: body,
+        };
+
+        let db = ctx.db.get()?;
+
+        diesel::insert_into(dsl::chat_templates)
+            .values(&new_template)
+            .on_conflict((dsl::chat_id, dsl::name))
+            .do_update()
+            .set(dsl::body.eq(body))
+            .execute(&*db)?;
 
+        membership::configured(&db, chat_id, user_id)?;
+
+        info!(self.logger, "Set template {} for chat {}", name, chat_id);
+
+        ctx.templates.render_in(
+            templates::REPLY_TEMPLATE_SAVED,
+            language,
+            &json!({ "name": name }),
+        )
+    }
+
+    fn handle_left_chat(
+        &self,
+        msg: ::telebot::objects::Message,
+        ctx: &Context,
+    ) -> Box<Future<Item = (), Error = Error>> {
         debug!(self.logger, "Left Chat: {:?}", msg);
 
+        // Keep the chat around, so its settings and nominations survive the
+        // bot being added back.
         let chat = &msg.chat;
-        let result =
-            diesel::delete(chats.filter(id.eq(chat.id))).execute(&ctx.db);
+        let result = ctx.db
+            .get()
+            .and_then(|db| membership::set_active(&db, chat.id, false));
 
         if let Err(x) = result {
-            error!(self.logger, "Unable to delete chat: {}", x);
+            error!(self.logger, "Unable to deactivate chat: {}", x);
         } else {
             let chat_title = match chat.title {
                 Some(ref x) => x.as_str(),
@@ -119,6 +259,77 @@ impl Handler {
         Box::from(future::ok(()))
     }
 
+    /// Follow changes to the bot's own membership of a group. Losing
+    /// administrator rights, or being removed without a service message,
+    /// deactivates the chat and tells whoever last configured it; getting
+    /// them back resumes it.
+    fn handle_my_chat_member(
+        &self,
+        update: ChatMemberUpdated,
+        ctx: &Context,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        // In a private chat, this is a user blocking or unblocking the bot.
+        if update.chat.kind == "private" {
+            return Box::from(future::ok(()));
+        }
+
+        let old_status = update.old_chat_member.status.as_str();
+        let new_status = update.new_chat_member.status.as_str();
+        let was_active = membership::is_admin(old_status);
+        let active = membership::is_admin(new_status);
+
+        let updated = ctx.db
+            .get()
+            .and_then(|db| membership::set_active(&db, update.chat.id, active));
+        let chat = match updated {
+            Ok(Some(x)) => x,
+            Ok(None) => return Box::from(future::ok(())),
+            Err(e) => return Box::from(future::err(e)),
+        };
+
+        info!(self.logger, "Membership changed: {} -> {}",
+              old_status, new_status;
+              "chat_id" => chat.id, "active" => active);
+
+        if active || !was_active {
+            return Box::from(future::ok(()));
+        }
+
+        let user_id = match chat.configured_by {
+            Some(x) => x,
+            None => return Box::from(future::ok(())),
+        };
+
+        let language = ctx.db
+            .get()
+            .and_then(|db| locale::resolve(&db, None, Some(chat.id)))
+            .unwrap_or_else(|_| locale::DEFAULT.to_owned());
+
+        let removed = match new_status {
+            "left" | "kicked" => true,
+            _ => false,
+        };
+
+        let text = ctx.templates
+            .render_in(templates::REPLY_CHAT_DEACTIVATED, &language, &json!({
+                "group": chat.title,
+                "removed": removed,
+            }))
+            .unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        let logger = self.logger.clone();
+        Box::from(text.send(&ctx.outbox, user_id).or_else(move |e| {
+            // They may never have started a chat with the bot.
+            warn!(logger, "unable to notify {}: {}", user_id, e);
+            Ok(())
+        }))
+    }
+
+    /// Check that the bot may stay in a group it was just added to, leaving
+    /// it straight away if not.
+    ///
+    /// Whoever added the bot must administer the group, and be let in by the
+    /// configured join policy.
     fn handle_join_chat(
         &self,
         msg: ::telebot::objects::Message,
@@ -126,65 +337,248 @@ impl Handler {
     ) -> Box<Future<Item = (), Error = Error>> {
         debug!(self.logger, "Join Chat: {:?}", msg);
 
+        let chat_id = msg.chat.id;
+        let adder = match msg.from {
+            Some(ref x) => x.id,
+            None => return Self::leave(chat_id, ctx),
+        };
+
+        if !membership::permitted(&self.join, &self.perms, adder, chat_id) {
+            info!(self.logger, "Join policy refused chat {}", chat_id;
+                  "added_by" => adder);
+            return Self::leave(chat_id, ctx);
+        }
+
+        let check = self.perms.check(
+            &ctx.outbox,
+            chat_id,
+            false,
+            Some(adder),
+            Level::ChatAdmin,
+        );
+
+        let logger = self.logger.clone();
+        let ctx = ctx.clone();
+        Box::from(check.and_then(move |allowed| {
+            if allowed {
+                Self::admit(&logger, msg, &ctx)
+            } else {
+                info!(logger, "Added to {} by a non-admin", chat_id;
+                      "added_by" => adder);
+                Self::leave(chat_id, &ctx)
+            }
+        }))
+    }
+
+    /// Explain why the bot can't stay, then leave.
+    fn leave(
+        chat_id: i64,
+        ctx: &Context,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let language = ctx.db
+            .get()
+            .and_then(|db| locale::resolve(&db, None, Some(chat_id)))
+            .unwrap_or_else(|_| locale::DEFAULT.to_owned());
+
+        let text = ctx.templates
+            .render_in(templates::REPLY_JOIN_REFUSED, &language, &json!({}))
+            .unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        let outbox = ctx.outbox.clone();
+        Box::from(
+            text.send(&ctx.outbox, chat_id)
+                .then(move |_| membership::leave(&outbox, chat_id)),
+        )
+    }
+
+    /// Register a group the bot has been allowed to join, introduce the bot,
+    /// and start setting it up.
+    ///
+    /// The chat is only active if the bot was made an administrator. That's
+    /// looked up rather than assumed, since the bot's own membership update
+    /// may arrive before or after this message.
+    fn admit(
+        logger: &slog::Logger,
+        msg: ::telebot::objects::Message,
+        ctx: &Context,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let chat_id = msg.chat.id;
+        let bot_id = ctx.user.id;
+        let member = ctx.outbox.call(None, Priority::Interactive, move |tg| {
+            Box::from(tg.get_chat_member(chat_id, bot_id).send().map(|x| x.1))
+        });
+
+        let logger = logger.clone();
+        let ctx = ctx.clone();
+        Box::from(member.and_then(move |member| {
+            let active = membership::is_admin(&member.status);
+            Self::register(&logger, msg, active, &ctx)
+        }))
+    }
+
+    fn register(
+        logger: &slog::Logger,
+        msg: ::telebot::objects::Message,
+        active: bool,
+        ctx: &Context,
+    ) -> Box<Future<Item = (), Error = Error>> {
         let title = msg.chat.title.unwrap_or(String::default());
         let new_chat = NewEnticeChat {
             id: msg.chat.id,
             title: title.as_str(),
             description: "", // TODO: Get description
+            active: active,
+        };
+
+        let db = match ctx.db.get() {
+            Ok(x) => x,
+            Err(e) => return Box::from(future::err(e)),
         };
 
         {
-            use schema::chats;
-            let chat: EnticeChat = match diesel::insert_into(chats::table)
+            use schema::chats::dsl;
+
+            // The bot may be rejoining a group it was removed from, so bring
+            // the existing row up to date rather than failing on it.
+            let chat: EnticeChat = match diesel::insert_into(dsl::chats)
                 .values(&new_chat)
-                .get_result(&ctx.db)
+                .on_conflict(dsl::id)
+                .do_update()
+                .set((dsl::title.eq(new_chat.title), dsl::active.eq(active)))
+                .get_result(&*db)
             {
                 Ok(x) => x,
-                Err(DieselError::DatabaseError(
-                    DatabaseErrorKind::UniqueViolation,
-                    _,
-                )) => {
-                    error!(
-                        self.logger,
-                        "Aready joined chat: {} ({})",
-                        new_chat.title,
-                        new_chat.id
-                    );
-                    // TODO: Query and provide the chat, instead of stopping.
-                    return Box::from(future::ok(()));
-                }
                 Err(e) => return Box::from(future::err(e.into())),
             };
 
-            info!(self.logger, "Joined Chat: {} ({})", chat.title, chat.id);
+            info!(logger, "Joined Chat: {} ({})", chat.title, chat.id);
 
             if msg.chat.kind == "private" {
                 return Box::from(future::ok(()));
             }
+        }
+
+        let language = match locale::resolve(&db, None, Some(msg.chat.id)) {
+            Ok(x) => x,
+            Err(e) => return Box::from(future::err(e)),
+        };
 
+        let text = ctx.templates.render_for_chat(
+            &db,
+            msg.chat.id,
+            templates::JOIN,
+            &language,
+            &json!({ "username": ctx.user.username }),
+        ).unwrap_or_else(|e| ctx.templates.fallback(e));
+
+        let joined = text.send(&ctx.outbox, msg.chat.id);
+
+        match msg.from {
+            Some(ref x) => {
+                let onboard =
+                    Self::onboard(logger, x, msg.chat.id, &language, ctx);
+                Box::from(joined.and_then(move |_| onboard))
+            }
+            None => Box::from(joined),
         }
+    }
 
-        let text = ctx.templates.render(templates::JOIN, &json!({
-            "username": ctx.user.username,
-        })).unwrap();
+    /// Walk whoever added the bot to a group through setting it up, in a
+    /// private chat. If they've never started a chat with the bot, post a
+    /// button in the group that starts one instead.
+    fn onboard(
+        logger: &slog::Logger,
+        adder: &User,
+        chat_id: i64,
+        language: &str,
+        ctx: &Context,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        let db = match ctx.db.get() {
+            Ok(x) => x,
+            Err(e) => return Box::from(future::err(e)),
+        };
 
-        Box::from(self.tg.message(msg.chat.id, text).send().map(|_| ()).from_err())
+        let user_language = locale::resolve(&db, Some(adder), None)
+            .unwrap_or_else(|_| language.to_owned());
+
+        let begun = setup::begin(
+            &ctx.outbox,
+            &ctx.templates,
+            &db,
+            &ctx.conversations,
+            adder.id,
+            chat_id,
+            &user_language,
+        );
+
+        let text = ctx.templates
+            .render_in(templates::REPLY_SETUP_BUTTON, language, &json!({}))
+            .unwrap_or_else(|e| ctx.templates.fallback(e));
+        let button = ctx.templates
+            .render_in(templates::BUTTON_CONFIGURE, language, &json!({}))
+            .unwrap_or_else(|e| ctx.templates.fallback(e))
+            .text;
+
+        let url = format!(
+            "https://t.me/{}?start={}{}",
+            ctx.user.username.as_ref().map(String::as_str).unwrap_or(""),
+            setup::START_PREFIX,
+            chat_id
+        );
+        let keyboard = InlineKeyboardMarkup::new(vec![
+            vec![InlineKeyboardButton::new(button).url(url)],
+        ]);
+
+        let outbox = ctx.outbox.clone();
+        let logger = logger.clone();
+        Box::from(begun.or_else(move |e| {
+            debug!(logger, "Unable to start setup in private: {}", e);
+            text.send_with_keyboard(&outbox, chat_id, keyboard)
+        }))
     }
 
-    fn handle_callback_query(
+    /// Record a nomination once the nominator has picked a chat and sent it.
+    /// Requires inline feedback to be enabled for the bot with @BotFather;
+    /// queries that are never sent leave nothing behind.
+    fn handle_chosen_inline_result(
         &self,
-        query: CallbackQuery,
+        result: ChosenInlineResult,
+        ctx: &Context,
     ) -> Box<Future<Item = (), Error = Error>> {
-        debug!(self.logger, "Got callback_query");
+        let chat_id: i64 = match result.result_id.parse() {
+            Ok(x) => x,
+            Err(_) => {
+                warn!(self.logger, "Unknown inline result: {}",
+                      result.result_id);
+                return Box::from(future::ok(()));
+            }
+        };
 
-        Box::from(
-            self.tg
-                .answer_callback_query(query.id)
-                .url("t.me/EnticeBot?start=hello")
-                .send()
-                .map(|_| ())
-                .from_err(),
-        )
+        // Only results with a keyboard get an inline message id, and every
+        // nomination has one.
+        let inline_message_id = match result.inline_message_id {
+            Some(ref x) => x.as_str(),
+            None => return Box::from(future::ok(())),
+        };
+
+        let recorded = ctx.db.get().and_then(|db| {
+            nominations::record_sent(
+                &db,
+                chat_id,
+                result.from.id,
+                &result.query,
+                inline_message_id,
+            )
+        });
+
+        match recorded {
+            Ok(()) => {
+                info!(self.logger, "Nomination sent";
+                      "chat_id" => chat_id, "nominator_id" => result.from.id);
+                Box::from(future::ok(()))
+            }
+            Err(e) => Box::from(future::err(e)),
+        }
     }
 
     fn handle_inline_query(
@@ -192,9 +586,17 @@ impl Handler {
         query: InlineQuery,
         ctx: &Context,
     ) -> Box<Future<Item = (), Error = Error>> {
+        let db = match ctx.db.get() {
+            Ok(x) => x,
+            Err(e) => return Box::from(future::err(e)),
+        };
+
         let chats = {
             use schema::chats::dsl::*;
-            chats.load::<EnticeChat>(&ctx.db)
+            chats
+                .filter(active.eq(true))
+                .filter(disabled.eq(false))
+                .load::<EnticeChat>(&*db)
         };
 
         let chats = match chats {
@@ -205,56 +607,133 @@ impl Handler {
             }
         };
 
+        let nominator = format::user(&query.from);
+
+        let user_language =
+            match locale::resolve(&db, Some(&query.from), None) {
+                Ok(x) => x,
+                Err(e) => return Box::from(future::err(e)),
+            };
+
+        let button = ctx.templates
+            .render_in(templates::BUTTON_ACCEPT_NOMINATION,
+                       &user_language,
+                       &json!({}))
+            .unwrap_or_else(|e| ctx.templates.fallback(e))
+            .text;
+
         let logger = self.logger.clone();
-        let tg = self.tg.clone();
-        let tg2 = self.tg.clone();
-        let query_id = query.id.clone();
-        Box::from(
-            future::join_all(chats.into_iter().map(move |chat| {
-                // TODO: Swallow errors so they don't cancel all futures
-                let title = chat.title.clone();
-                tg.get_chat_member(chat.id, query.from.id)
-                    .send()
-                    .map(|(tg, mem)| (tg, mem, title))
-            })).and_then(move |results| {
-                let mut articles: Vec<Box<Serialize>> = Vec::new();
-
-                for &(_, ref result, ref title) in results.iter() {
-                    match result.status.as_str() {
-                        "creator" | "administrator" | "member" => (),
-                        _ => continue,
-                    }
-                    debug!(logger, "Got chat member: {:?}", result);
-
-                    let article = Box::new(
-                        InlineQueryResultArticle::new(
-                            title.clone(),
-                            Box::new(InputMessageContent::Text::new(
-                                QUERY_REPLY_TEXT.into(),
-                            )),
-                        ).reply_markup(
-                            InlineKeyboardMarkup::new(vec![
-                                vec![
-                                    InlineKeyboardButton::new(
-                                        "Accept Nomination".into(),
-                                    ).callback_data("http://wikipedia.org"),
-                                ],
-                            ]),
-                        ),
-                    );
-                    articles.push(article);
-                }
+        let nominator_id = query.from.id;
+
+        // A chat whose membership can't be checked is left out, rather than
+        // failing the whole answer.
+        let checked: Vec<_> = chats
+            .into_iter()
+            .map(|chat| {
+                let language = chat.language.clone().unwrap_or_else(|| {
+                    user_language.clone()
+                });
+                let text = ctx.templates.render_for_chat(
+                    &db,
+                    chat.id,
+                    templates::QUERY_REPLY,
+                    &language,
+                    &json!({
+                        "group": chat.title,
+                        "nominator": nominator,
+                        "reason": query.query,
+                    }),
+                ).unwrap_or_else(|e| ctx.templates.fallback(e));
+
+                let chat_id = chat.id;
+                let title = chat.title;
                 let logger = logger.clone();
-                Box::from(
-                    tg2.answer_inline_query(query_id, articles)
-                    .is_personal(true)
-                    .cache_time(0) // TODO: Can probably set this higher
-                    .send()
-                    .map(move |_| debug!(logger, "Sent answer_inline_query"))
-                    .from_err(),
-                )
+                ctx.outbox
+                    .call(None, Priority::Interactive, move |tg| {
+                        Box::from(
+                            tg.get_chat_member(chat_id, nominator_id)
+                                .send()
+                                .map(|(_, x)| x),
+                        )
+                    })
+                    .then(move |result| -> Result<Option<Offer>> {
+                        let member = match result {
+                            Ok(x) => x,
+                            Err(e) => {
+                                warn!(logger, "unable to check chat {}: {}",
+                                      chat_id, e);
+                                return Ok(None);
+                            }
+                        };
+
+                        debug!(logger, "Got chat member: {:?}", member);
+
+                        match member.status.as_str() {
+                            "creator" | "administrator" | "member" => {
+                                Ok(Some((chat_id, title, text)))
+                            }
+                            _ => Ok(None),
+                        }
+                    })
             })
-                .or_else(|e| Err(Error::from(e))),
-        )
+            .collect();
+
+        let outbox = ctx.outbox.clone();
+        let query_id = query.id;
+        Box::from(future::join_all(checked).and_then(move |results| {
+            let offers: Vec<Offer> =
+                results.into_iter().flat_map(|x| x).collect();
+
+            outbox
+                .call(None, Priority::Interactive, move |tg| {
+                    let articles =
+                        Self::articles(&offers, nominator_id, &button);
+
+                    Box::from(
+                        tg.answer_inline_query(query_id.clone(), articles)
+                            .is_personal(true)
+                            .cache_time(0) // TODO: Can probably set this higher
+                            .send()
+                            .map(|_| ()),
+                    )
+                })
+                .map(move |_| debug!(logger, "Sent answer_inline_query"))
+        }))
+    }
+
+    /// The inline results offering to nominate into each chat in `offers`.
+    fn articles(
+        offers: &[Offer],
+        nominator_id: i64,
+        button: &str,
+    ) -> Vec<Box<Serialize>> {
+        let mut articles: Vec<Box<Serialize>> = Vec::new();
+
+        for &(chat_id, ref title, ref text) in offers {
+            let mut content = InputMessageContent::Text::new(text.text.clone());
+            if let Some(mode) = text.parse_mode.telegram_name() {
+                content = content.parse_mode(mode);
+            }
+
+            let keyboard = InlineKeyboardMarkup::new(vec![
+                vec![
+                    Action::Accept {
+                        chat_id: chat_id,
+                        nominator_id: nominator_id,
+                    }.button(button.to_owned()),
+                ],
+            ]);
+
+            // The result id identifies the chat, so the nomination can be
+            // recorded once it's actually sent.
+            let article = Box::new(
+                InlineQueryResultArticle::new(title.clone(), Box::new(content))
+                    .id(chat_id.to_string())
+                    .reply_markup(keyboard),
+            );
+            articles.push(article);
+        }
+
+        articles
     }
 }
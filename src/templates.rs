@@ -2,6 +2,25 @@ use errors::*;
 
 use handlebars::Handlebars;
 
+use format::{self, ParseMode, Rendered};
+
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+
+use serde::Serialize;
+
+use slog;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde_json::Value;
+
 pub const JOIN: &'static str = "join";
 const TPL_JOIN: &'static str =
     "Hey! I'm @{{username}}.\n\n\
@@ -16,9 +35,1016 @@ const TPL_REPLY_START: &'static str =
      I help manage inviting new users to groups. If you'd like to use me in \
      your groups, add me as an administrator to get started!";
 
-pub fn register_all(handlebars: &mut Handlebars) -> Result<()> {
-    handlebars.register_template_string(REPLY_START, TPL_REPLY_START)?;
-    handlebars.register_template_string(JOIN, TPL_JOIN)?;
+pub const QUERY_REPLY: &'static str = "query_reply";
+const TPL_QUERY_REPLY: &'static str =
+    "{{!-- parse_mode: html --}}\
+     {{mention nominator}} is nominating you for invitation to \
+     <b>{{group}}</b>.\n\n\
+     \
+     {{#if reason}}Reason: {{reason}}\n\n{{/if}}\
+     \
+     After pressing the button below, you must also press the Start button.";
+
+pub const BUTTON_ACCEPT_NOMINATION: &'static str = "button_accept_nomination";
+const TPL_BUTTON_ACCEPT_NOMINATION: &'static str = "Accept Nomination";
+
+pub const NOMINATION_ACCEPTED: &'static str = "nomination_accepted";
+const TPL_NOMINATION_ACCEPTED: &'static str =
+    "{{!-- parse_mode: html --}}\
+     Nomination to <b>{{group}}</b> accepted! Waiting for the group to \
+     decide.";
+
+pub const NOMINATION_APPROVED: &'static str = "nomination_approved";
+const TPL_NOMINATION_APPROVED: &'static str =
+    "{{!-- parse_mode: html --}}\
+     Nomination to <b>{{group}}</b> approved! An invite is on its way.";
+
+pub const NOMINATION_REJECTED: &'static str = "nomination_rejected";
+const TPL_NOMINATION_REJECTED: &'static str =
+    "{{!-- parse_mode: html --}}\
+     Nomination to <b>{{group}}</b> wasn't approved this time.";
+
+pub const NOMINATION_EXPIRED: &'static str = "nomination_expired";
+const TPL_NOMINATION_EXPIRED: &'static str =
+    "{{!-- parse_mode: html --}}\
+     Nomination to <b>{{group}}</b> expired.";
+
+pub const APPROVAL_REQUEST: &'static str = "approval_request";
+const TPL_APPROVAL_REQUEST: &'static str =
+    "{{!-- parse_mode: html --}}\
+     {{mention nominator}} nominated {{mention nominee}} to join \
+     <b>{{group}}</b>, and they accepted.\n\n\
+     \
+     {{#if reason}}Reason: {{reason}}\n\n{{/if}}\
+     \
+     Members can vote below. An administrator has the final say.";
+
+pub const BUTTON_APPROVE: &'static str = "button_approve";
+const TPL_BUTTON_APPROVE: &'static str = "Approve";
+
+pub const BUTTON_REJECT: &'static str = "button_reject";
+const TPL_BUTTON_REJECT: &'static str = "Reject";
+
+pub const BUTTON_VOTE_YES: &'static str = "button_vote_yes";
+const TPL_BUTTON_VOTE_YES: &'static str = "\u{1F44D}";
+
+pub const BUTTON_VOTE_NO: &'static str = "button_vote_no";
+const TPL_BUTTON_VOTE_NO: &'static str = "\u{1F44E}";
+
+pub const ANSWER_OWN_NOMINATION: &'static str = "answer_own_nomination";
+const TPL_ANSWER_OWN_NOMINATION: &'static str =
+    "That's your own nomination! Send it to the person you'd like to invite.";
+
+pub const ANSWER_VOTED: &'static str = "answer_voted";
+const TPL_ANSWER_VOTED: &'static str =
+    "Thanks for voting! So far: {{yes}} for, {{no}} against.";
+
+pub const ANSWER_APPROVED: &'static str = "answer_approved";
+const TPL_ANSWER_APPROVED: &'static str =
+    "Approved! I'll send them an invite.";
+
+pub const ANSWER_REJECTED: &'static str = "answer_rejected";
+const TPL_ANSWER_REJECTED: &'static str = "Rejected.";
+
+pub const ANSWER_EXPIRED: &'static str = "answer_expired";
+const TPL_ANSWER_EXPIRED: &'static str =
+    "This nomination isn't open anymore.";
+
+pub const ANSWER_PAUSED: &'static str = "answer_paused";
+const TPL_ANSWER_PAUSED: &'static str =
+    "Nominations to this group are paused until I'm an administrator there \
+     again.";
+
+pub const ANSWER_UNKNOWN: &'static str = "answer_unknown";
+const TPL_ANSWER_UNKNOWN: &'static str =
+    "Sorry, this button doesn't work anymore.";
+
+pub const ANSWER_FAILED: &'static str = "answer_failed";
+const TPL_ANSWER_FAILED: &'static str =
+    "Something went wrong on my end. Please try again later.";
+
+pub const REPLY_CHAT_DEACTIVATED: &'static str = "reply_chat_deactivated";
+const TPL_REPLY_CHAT_DEACTIVATED: &'static str =
+    "{{#if removed}}I was removed from {{group}}.\
+     {{else}}I'm no longer an administrator of {{group}}.{{/if}} \
+     Nominations there are paused until I'm added back as an administrator.";
+
+pub const REPLY_JOIN_REFUSED: &'static str = "reply_join_refused";
+const TPL_REPLY_JOIN_REFUSED: &'static str =
+    "Sorry, I can only be added to a group by one of its administrators, \
+     and only if my owner allows it. Goodbye!";
+
+pub const REPLY_INVITE: &'static str = "reply_invite";
+const TPL_REPLY_INVITE: &'static str =
+    "Good news! You've been approved to join {{group}}:\n\n{{link}}\
+     {{#if welcome}}\n\n{{welcome}}{{/if}}";
+
+pub const REPLY_NOMINATION_REJECTED: &'static str =
+    "reply_nomination_rejected";
+const TPL_REPLY_NOMINATION_REJECTED: &'static str =
+    "Sorry, the administrators of {{group}} decided not to invite you this \
+     time.";
+
+pub const REPLY_LANGUAGE_SET: &'static str = "reply_language_set";
+const TPL_REPLY_LANGUAGE_SET: &'static str =
+    "Okay! I'll use {{language}} from now on.";
+
+pub const REPLY_LANGUAGE_CLEARED: &'static str = "reply_language_cleared";
+const TPL_REPLY_LANGUAGE_CLEARED: &'static str =
+    "Okay! I'll go back to choosing a language automatically.";
+
+pub const REPLY_LANGUAGE_INVALID: &'static str = "reply_language_invalid";
+const TPL_REPLY_LANGUAGE_INVALID: &'static str =
+    "\"{{language}}\" doesn't look like a language code. Try something like \
+     en or pt-br.";
+
+pub const REPLY_ERROR: &'static str = "reply_error";
+const TPL_REPLY_ERROR: &'static str =
+    "Something went wrong on my end. Please try again later.";
+
+pub const REPLY_FORBIDDEN: &'static str = "reply_forbidden";
+const TPL_REPLY_FORBIDDEN: &'static str =
+    "{{#if chat_member}}Only members of this group can do that.{{/if}}\
+     {{#if chat_admin}}Only administrators of this group can do that.{{/if}}\
+     {{#if bot_moderator}}Only my moderators can do that.{{/if}}\
+     {{#if bot_owner}}Only my owner can do that.{{/if}}";
+
+pub const REPLY_GROUP_ONLY: &'static str = "reply_group_only";
+const TPL_REPLY_GROUP_ONLY: &'static str =
+    "That only works in a group. Send it there instead!";
+
+pub const REPLY_TEMPLATE_UNKNOWN: &'static str = "reply_template_unknown";
+const TPL_REPLY_TEMPLATE_UNKNOWN: &'static str =
+    "{{#if name}}I don't have a message called \"{{name}}\". {{/if}}\
+     You can change any of these:\n\n\
+     \
+     {{#each names}}{{this}}\n{{/each}}";
+
+pub const REPLY_TEMPLATE_PROMPT: &'static str = "reply_template_prompt";
+const TPL_REPLY_TEMPLATE_PROMPT: &'static str =
+    "Okay, reply to this message with the new text for \"{{name}}\".";
+
+pub const REPLY_TEMPLATE_INVALID: &'static str = "reply_template_invalid";
+const TPL_REPLY_TEMPLATE_INVALID: &'static str =
+    "I couldn't understand that template, so I kept the old one:\n\n{{error}}";
+
+pub const REPLY_TEMPLATE_SAVED: &'static str = "reply_template_saved";
+const TPL_REPLY_TEMPLATE_SAVED: &'static str =
+    "Done! This group will now use your text for \"{{name}}\".";
+
+pub const REPLY_TEMPLATE_RESET: &'static str = "reply_template_reset";
+const TPL_REPLY_TEMPLATE_RESET: &'static str =
+    "Done! This group is back to the default text for \"{{name}}\".";
+
+pub const REPLY_USAGE: &'static str = "reply_usage";
+const TPL_REPLY_USAGE: &'static str =
+    "{{#if missing}}You need to give me {{param}}.\
+     {{else}}I couldn't understand {{param}}.{{/if}}\n\n\
+     \
+     Usage: {{usage}}";
+
+pub const REPLY_HELP: &'static str = "reply_help";
+const TPL_REPLY_HELP: &'static str =
+    "Here's what I can do:\n\n\
+     \
+     {{#each commands}}{{usage}}\n{{description}}\n\n{{/each}}";
+
+pub const SETUP_APPROVAL_MODE: &'static str = "setup_approval_mode";
+const TPL_SETUP_APPROVAL_MODE: &'static str =
+    "{{#if invalid}}Sorry, I didn't understand that.\n\n{{/if}}\
+     Let's set me up for {{group}}.\n\n\
+     \
+     How should nominations be approved once they're accepted? Reply admin \
+     to have an administrator decide each one, or auto to approve them \
+     straight away.";
+
+pub const SETUP_TTL: &'static str = "setup_ttl";
+const TPL_SETUP_TTL: &'static str =
+    "{{#if invalid}}Sorry, I didn't understand that.\n\n{{/if}}\
+     How long should nominations stay open? Reply with something like 3d or \
+     12h, or skip to use the default.";
+
+pub const SETUP_WELCOME: &'static str = "setup_welcome";
+const TPL_SETUP_WELCOME: &'static str =
+    "{{#if invalid}}Sorry, I didn't understand that.\n\n{{/if}}\
+     What should I say to new members along with their invite? Reply with \
+     the text, or skip to leave it out.";
+
+pub const SETUP_NOTIFY_CHAT: &'static str = "setup_notify_chat";
+const TPL_SETUP_NOTIFY_CHAT: &'static str =
+    "{{#if invalid}}Sorry, I didn't understand that.\n\n{{/if}}\
+     {{#if unverified}}I can only post there if I'm in that chat and you \
+     administer it.\n\n{{/if}}\
+     Where should I ask administrators to approve nominations? Reply with \
+     the id of a chat I'm in, or skip to ask in {{group}} itself.";
+
+pub const SETUP_DONE: &'static str = "setup_done";
+const TPL_SETUP_DONE: &'static str =
+    "All set! Here's how I'll work in {{group}}:\n\n\
+     \
+     Approval: {{approval_mode}}\n\
+     Nominations expire after: {{#if ttl}}{{ttl}}{{else}}the default{{/if}}\n\
+     Welcome text: {{#if welcome}}{{welcome}}{{else}}none{{/if}}\n\
+     Approval requests go to: \
+     {{#if notify_chat_id}}{{notify_chat_id}}{{else}}{{group}}{{/if}}";
+
+pub const REPLY_SETUP_BUTTON: &'static str = "reply_setup_button";
+const TPL_REPLY_SETUP_BUTTON: &'static str =
+    "An administrator can press the button below to set me up.";
+
+pub const BUTTON_CONFIGURE: &'static str = "button_configure";
+const TPL_BUTTON_CONFIGURE: &'static str = "Configure me";
+
+pub const MENU_MAIN: &'static str = "menu_main";
+const TPL_MENU_MAIN: &'static str =
+    "Settings for {{group}}. What would you like to change?";
+
+pub const MENU_LANGUAGE: &'static str = "menu_language";
+const TPL_MENU_LANGUAGE: &'static str =
+    "{{#if language}}This group uses {{language}}.\
+     {{else}}I choose a language for each member automatically.{{/if}}\n\n\
+     \
+     Send /language followed by a code like en or pt-br to change it, or \
+     /language on its own to go back to choosing automatically.";
+
+pub const MENU_TEMPLATES: &'static str = "menu_templates";
+const TPL_MENU_TEMPLATES: &'static str =
+    "{{#if customized}}This group has its own text for:\n\n\
+     {{#each customized}}{{this}}\n{{/each}}\n\
+     {{else}}This group uses the default text for all my messages.\n\n{{/if}}\
+     \
+     Use /settemplate to change a message, or /resettemplate to go back to \
+     the default.";
+
+pub const MENU_CLOSED: &'static str = "menu_closed";
+const TPL_MENU_CLOSED: &'static str = "Settings closed.";
+
+pub const BUTTON_MENU_LANGUAGE: &'static str = "button_menu_language";
+const TPL_BUTTON_MENU_LANGUAGE: &'static str = "Language";
+
+pub const BUTTON_MENU_TEMPLATES: &'static str = "button_menu_templates";
+const TPL_BUTTON_MENU_TEMPLATES: &'static str = "Messages";
+
+pub const BUTTON_MENU_BACK: &'static str = "button_menu_back";
+const TPL_BUTTON_MENU_BACK: &'static str = "Back";
+
+pub const BUTTON_MENU_CLOSE: &'static str = "button_menu_close";
+const TPL_BUTTON_MENU_CLOSE: &'static str = "Close";
+
+pub const REPLY_CHATS: &'static str = "reply_chats";
+const TPL_REPLY_CHATS: &'static str =
+    "{{#if chats}}I'm in {{total}} chats, {{active}} of them active:\n\n\
+     {{#each chats}}{{title}} ({{id}})\
+     {{#if disabled}} [disabled]{{else}}{{#unless active}} [paused]{{/unless}}\
+     {{/if}}\n\
+     {{nominations}} nominations, {{approved}} approved\n\n{{/each}}\
+     {{#if more}}...and {{more}} more.{{/if}}\
+     {{else}}I'm not in any chats yet.{{/if}}";
+
+pub const REPLY_CHAT_LEFT: &'static str = "reply_chat_left";
+const TPL_REPLY_CHAT_LEFT: &'static str = "Left {{chat_id}}.";
+
+pub const REPLY_CHAT_DISABLED: &'static str = "reply_chat_disabled";
+const TPL_REPLY_CHAT_DISABLED: &'static str =
+    "Paused {{group}} ({{chat_id}}). Nominations there are on hold, and it \
+     won't be offered to nominators.";
+
+pub const REPLY_CHAT_ENABLED: &'static str = "reply_chat_enabled";
+const TPL_REPLY_CHAT_ENABLED: &'static str =
+    "Resumed {{group}} ({{chat_id}}). Nominations there can go ahead again.";
+
+pub const REPLY_CHAT_UNKNOWN: &'static str = "reply_chat_unknown";
+const TPL_REPLY_CHAT_UNKNOWN: &'static str =
+    "I couldn't find a chat with the id {{chat_id}}.\
+     {{#if error}}\n\n{{error}}{{/if}}";
+
+pub const REPLY_STATUS: &'static str = "reply_status";
+const TPL_REPLY_STATUS: &'static str =
+    "Up for {{uptime}}\
+     {{#if restarts}}, restarted {{restarts}} times{{/if}}.\n\n\
+     \
+     Database: {{#if database}}OK{{else}}unreachable ({{error}}){{/if}}\n\
+     Connections: {{connections}} open, {{idle}} idle\n\
+     Chats: {{total}}, {{active}} active\n\
+     Broadcast messages waiting: {{pending}}\n\
+     Outgoing queue: {{queue}}";
+
+pub const BUTTON_BROADCAST_SEND: &'static str = "button_broadcast_send";
+const TPL_BUTTON_BROADCAST_SEND: &'static str = "Send to {{count}} chats";
+
+pub const BUTTON_BROADCAST_CANCEL: &'static str = "button_broadcast_cancel";
+const TPL_BUTTON_BROADCAST_CANCEL: &'static str = "Cancel";
+
+pub const ANSWER_BROADCAST_SENDING: &'static str = "answer_broadcast_sending";
+const TPL_ANSWER_BROADCAST_SENDING: &'static str =
+    "Sending to {{count}} chats...";
+
+pub const ANSWER_BROADCAST_CANCELLED: &'static str =
+    "answer_broadcast_cancelled";
+const TPL_ANSWER_BROADCAST_CANCELLED: &'static str = "Broadcast cancelled.";
+
+pub const REPLY_BROADCAST_INVALID: &'static str = "reply_broadcast_invalid";
+const TPL_REPLY_BROADCAST_INVALID: &'static str =
+    "I couldn't understand that announcement:\n\n{{error}}";
+
+pub const ANSWER_BROADCAST_CLOSED: &'static str = "answer_broadcast_closed";
+const TPL_ANSWER_BROADCAST_CLOSED: &'static str =
+    "This broadcast was already sent or cancelled.";
+
+pub const REPLY_BROADCAST_DONE: &'static str = "reply_broadcast_done";
+const TPL_REPLY_BROADCAST_DONE: &'static str =
+    "Broadcast finished: sent to {{sent}} chats\
+     {{#if failed}}, {{failed}} failed{{/if}}.";
+
+pub const REPLY_DEAD_LETTERS: &'static str = "reply_dead_letters";
+const TPL_REPLY_DEAD_LETTERS: &'static str =
+    "{{#if letters}}Updates that kept failing:\n\n\
+     {{#each letters}}#{{id}}: update {{update_id}}, {{status}}, \
+     tried {{attempts}} times, {{datetime created_at}}\n{{error}}\n\n\
+     {{/each}}Use /replay <id> to handle one again.\
+     {{else}}No updates have failed.{{/if}}";
+
+pub const REPLY_PANIC: &'static str = "reply_panic";
+const TPL_REPLY_PANIC: &'static str =
+    "I hit a bug while handling update {{update_id}}:\n\n{{error}}\n\n\
+     The update has been kept, see /failed.";
+
+pub const REPLY_REPLAY_QUEUED: &'static str = "reply_replay_queued";
+const TPL_REPLY_REPLAY_QUEUED: &'static str =
+    "Update #{{id}} will be handled again shortly.";
+
+pub const REPLY_REPLAY_UNKNOWN: &'static str = "reply_replay_unknown";
+const TPL_REPLY_REPLAY_UNKNOWN: &'static str =
+    "There's no failed update #{{id}} to replay.";
+
+pub const HELP_START: &'static str = "help_start";
+const TPL_HELP_START: &'static str = "Introduce myself";
+
+pub const HELP_HELP: &'static str = "help_help";
+const TPL_HELP_HELP: &'static str = "List the commands you can use here";
+
+pub const HELP_LANGUAGE: &'static str = "help_language";
+const TPL_HELP_LANGUAGE: &'static str =
+    "Choose the language I use (for the whole group, if used in one)";
+
+pub const HELP_SETTEMPLATE: &'static str = "help_settemplate";
+const TPL_HELP_SETTEMPLATE: &'static str =
+    "Change one of the messages I send in this group";
+
+pub const HELP_RESETTEMPLATE: &'static str = "help_resettemplate";
+const TPL_HELP_RESETTEMPLATE: &'static str =
+    "Go back to the default text for one of my messages";
+
+pub const HELP_SETTINGS: &'static str = "help_settings";
+const TPL_HELP_SETTINGS: &'static str = "Change how I behave in this group";
+
+pub const HELP_CHATS: &'static str = "help_chats";
+const TPL_HELP_CHATS: &'static str = "List the chats I'm in";
+
+pub const HELP_LEAVE: &'static str = "help_leave";
+const TPL_HELP_LEAVE: &'static str = "Make me leave a chat";
+
+pub const HELP_DISABLE: &'static str = "help_disable";
+const TPL_HELP_DISABLE: &'static str = "Pause nominations to a chat";
+
+pub const HELP_ENABLE: &'static str = "help_enable";
+const TPL_HELP_ENABLE: &'static str = "Resume nominations to a paused chat";
+
+pub const HELP_BROADCAST: &'static str = "help_broadcast";
+const TPL_HELP_BROADCAST: &'static str =
+    "Send an announcement to every active chat, or only those matching \
+     language=<language> or approval=<admin|auto>";
+
+pub const HELP_STATUS: &'static str = "help_status";
+const TPL_HELP_STATUS: &'static str = "Show how I'm doing";
+
+pub const HELP_FAILED: &'static str = "help_failed";
+const TPL_HELP_FAILED: &'static str = "List updates that kept failing";
+
+pub const HELP_REPLAY: &'static str = "help_replay";
+const TPL_HELP_REPLAY: &'static str = "Handle a failed update again";
+
+const BUILTIN: &'static [(&'static str, &'static str)] = &[
+    (JOIN, TPL_JOIN),
+    (REPLY_START, TPL_REPLY_START),
+    (QUERY_REPLY, TPL_QUERY_REPLY),
+    (BUTTON_ACCEPT_NOMINATION, TPL_BUTTON_ACCEPT_NOMINATION),
+    (NOMINATION_ACCEPTED, TPL_NOMINATION_ACCEPTED),
+    (NOMINATION_APPROVED, TPL_NOMINATION_APPROVED),
+    (NOMINATION_REJECTED, TPL_NOMINATION_REJECTED),
+    (NOMINATION_EXPIRED, TPL_NOMINATION_EXPIRED),
+    (APPROVAL_REQUEST, TPL_APPROVAL_REQUEST),
+    (BUTTON_APPROVE, TPL_BUTTON_APPROVE),
+    (BUTTON_REJECT, TPL_BUTTON_REJECT),
+    (BUTTON_VOTE_YES, TPL_BUTTON_VOTE_YES),
+    (BUTTON_VOTE_NO, TPL_BUTTON_VOTE_NO),
+    (ANSWER_OWN_NOMINATION, TPL_ANSWER_OWN_NOMINATION),
+    (ANSWER_VOTED, TPL_ANSWER_VOTED),
+    (ANSWER_APPROVED, TPL_ANSWER_APPROVED),
+    (ANSWER_REJECTED, TPL_ANSWER_REJECTED),
+    (ANSWER_EXPIRED, TPL_ANSWER_EXPIRED),
+    (ANSWER_PAUSED, TPL_ANSWER_PAUSED),
+    (ANSWER_UNKNOWN, TPL_ANSWER_UNKNOWN),
+    (ANSWER_FAILED, TPL_ANSWER_FAILED),
+    (REPLY_CHAT_DEACTIVATED, TPL_REPLY_CHAT_DEACTIVATED),
+    (REPLY_JOIN_REFUSED, TPL_REPLY_JOIN_REFUSED),
+    (REPLY_INVITE, TPL_REPLY_INVITE),
+    (REPLY_NOMINATION_REJECTED, TPL_REPLY_NOMINATION_REJECTED),
+    (REPLY_LANGUAGE_SET, TPL_REPLY_LANGUAGE_SET),
+    (REPLY_LANGUAGE_CLEARED, TPL_REPLY_LANGUAGE_CLEARED),
+    (REPLY_LANGUAGE_INVALID, TPL_REPLY_LANGUAGE_INVALID),
+    (REPLY_ERROR, TPL_REPLY_ERROR),
+    (REPLY_FORBIDDEN, TPL_REPLY_FORBIDDEN),
+    (REPLY_GROUP_ONLY, TPL_REPLY_GROUP_ONLY),
+    (REPLY_TEMPLATE_UNKNOWN, TPL_REPLY_TEMPLATE_UNKNOWN),
+    (REPLY_TEMPLATE_PROMPT, TPL_REPLY_TEMPLATE_PROMPT),
+    (REPLY_TEMPLATE_INVALID, TPL_REPLY_TEMPLATE_INVALID),
+    (REPLY_TEMPLATE_SAVED, TPL_REPLY_TEMPLATE_SAVED),
+    (REPLY_TEMPLATE_RESET, TPL_REPLY_TEMPLATE_RESET),
+    (REPLY_USAGE, TPL_REPLY_USAGE),
+    (REPLY_HELP, TPL_REPLY_HELP),
+    (SETUP_APPROVAL_MODE, TPL_SETUP_APPROVAL_MODE),
+    (SETUP_TTL, TPL_SETUP_TTL),
+    (SETUP_WELCOME, TPL_SETUP_WELCOME),
+    (SETUP_NOTIFY_CHAT, TPL_SETUP_NOTIFY_CHAT),
+    (SETUP_DONE, TPL_SETUP_DONE),
+    (REPLY_SETUP_BUTTON, TPL_REPLY_SETUP_BUTTON),
+    (BUTTON_CONFIGURE, TPL_BUTTON_CONFIGURE),
+    (MENU_MAIN, TPL_MENU_MAIN),
+    (MENU_LANGUAGE, TPL_MENU_LANGUAGE),
+    (MENU_TEMPLATES, TPL_MENU_TEMPLATES),
+    (MENU_CLOSED, TPL_MENU_CLOSED),
+    (BUTTON_MENU_LANGUAGE, TPL_BUTTON_MENU_LANGUAGE),
+    (BUTTON_MENU_TEMPLATES, TPL_BUTTON_MENU_TEMPLATES),
+    (BUTTON_MENU_BACK, TPL_BUTTON_MENU_BACK),
+    (BUTTON_MENU_CLOSE, TPL_BUTTON_MENU_CLOSE),
+    (REPLY_CHATS, TPL_REPLY_CHATS),
+    (REPLY_CHAT_LEFT, TPL_REPLY_CHAT_LEFT),
+    (REPLY_CHAT_DISABLED, TPL_REPLY_CHAT_DISABLED),
+    (REPLY_CHAT_ENABLED, TPL_REPLY_CHAT_ENABLED),
+    (REPLY_CHAT_UNKNOWN, TPL_REPLY_CHAT_UNKNOWN),
+    (REPLY_STATUS, TPL_REPLY_STATUS),
+    (BUTTON_BROADCAST_SEND, TPL_BUTTON_BROADCAST_SEND),
+    (BUTTON_BROADCAST_CANCEL, TPL_BUTTON_BROADCAST_CANCEL),
+    (ANSWER_BROADCAST_SENDING, TPL_ANSWER_BROADCAST_SENDING),
+    (ANSWER_BROADCAST_CANCELLED, TPL_ANSWER_BROADCAST_CANCELLED),
+    (REPLY_BROADCAST_INVALID, TPL_REPLY_BROADCAST_INVALID),
+    (ANSWER_BROADCAST_CLOSED, TPL_ANSWER_BROADCAST_CLOSED),
+    (REPLY_BROADCAST_DONE, TPL_REPLY_BROADCAST_DONE),
+    (REPLY_DEAD_LETTERS, TPL_REPLY_DEAD_LETTERS),
+    (REPLY_PANIC, TPL_REPLY_PANIC),
+    (REPLY_REPLAY_QUEUED, TPL_REPLY_REPLAY_QUEUED),
+    (REPLY_REPLAY_UNKNOWN, TPL_REPLY_REPLAY_UNKNOWN),
+    (HELP_START, TPL_HELP_START),
+    (HELP_HELP, TPL_HELP_HELP),
+    (HELP_LANGUAGE, TPL_HELP_LANGUAGE),
+    (HELP_SETTEMPLATE, TPL_HELP_SETTEMPLATE),
+    (HELP_RESETTEMPLATE, TPL_HELP_RESETTEMPLATE),
+    (HELP_SETTINGS, TPL_HELP_SETTINGS),
+    (HELP_CHATS, TPL_HELP_CHATS),
+    (HELP_LEAVE, TPL_HELP_LEAVE),
+    (HELP_DISABLE, TPL_HELP_DISABLE),
+    (HELP_ENABLE, TPL_HELP_ENABLE),
+    (HELP_BROADCAST, TPL_HELP_BROADCAST),
+    (HELP_STATUS, TPL_HELP_STATUS),
+    (HELP_FAILED, TPL_HELP_FAILED),
+    (HELP_REPLAY, TPL_HELP_REPLAY),
+];
+
+const EXTENSION: &'static str = "hbs";
+
+/// Sent in place of a template that failed to render. This is deliberately
+/// the built-in error reply's source rather than a template itself, so it
+/// can't fail the same way.
+const FALLBACK: &'static str = TPL_REPLY_ERROR;
+
+/// Data covering every variable used by the built-in templates, used to check
+/// that templates render before they are put into use.
+fn sample() -> Value {
+    json!({
+        "username": "EnticeBot",
+        "group": "Example Group",
+        "nominator": {
+            "id": 1,
+            "first_name": "Example",
+            "last_name": "User",
+            "username": "example",
+        },
+        "nominee": {
+            "id": 2,
+            "first_name": "Another",
+            "last_name": "User",
+            "username": "another",
+        },
+        "reason": "Example reason",
+        "link": "https://t.me/joinchat/example",
+        "yes": 2,
+        "no": 1,
+        "customized": [JOIN],
+        "removed": true,
+        "invalid": true,
+        "unverified": true,
+        "approval_mode": "admin",
+        "ttl": "3d",
+        "welcome": "Example welcome",
+        "notify_chat_id": -1,
+        "chat_id": -1,
+        "chats": [
+            {
+                "id": -1,
+                "title": "Example Group",
+                "active": true,
+                "disabled": false,
+                "nominations": 3,
+                "approved": 1,
+            },
+        ],
+        "letters": [
+            {
+                "id": 1,
+                "update_id": 100,
+                "status": "failed",
+                "attempts": 3,
+                "created_at": 0,
+                "error": "Example error",
+            },
+        ],
+        "id": 1,
+        "update_id": 100,
+        "total": 2,
+        "active": 1,
+        "more": 1,
+        "uptime": "1d2h",
+        "restarts": 1,
+        "database": true,
+        "connections": 2,
+        "idle": 1,
+        "pending": 0,
+        "queue": 0,
+        "count": 2,
+        "sent": 1,
+        "failed": 1,
+        "language": "en",
+        "name": JOIN,
+        "names": OVERRIDABLE,
+        "error": "Example error",
+        "usage": "/example <name>",
+        "param": "name",
+        "missing": true,
+        "commands": [
+            { "usage": "/example <name>", "description": "Example command" },
+        ],
+    })
+}
+
+fn builtin(name: &str) -> Option<&'static str> {
+    BUILTIN.iter().find(|&&(n, _)| n == name).map(|&(_, tpl)| tpl)
+}
+
+/// Names of the templates a chat's administrators can replace with
+/// `/settemplate`. These are the ones rendered with `render_for_chat`, so
+/// replacing any other would have no effect.
+pub const OVERRIDABLE: &'static [&'static str] = &[
+    JOIN,
+    QUERY_REPLY,
+    APPROVAL_REQUEST,
+    NOMINATION_ACCEPTED,
+    NOMINATION_APPROVED,
+    NOMINATION_REJECTED,
+    NOMINATION_EXPIRED,
+];
+
+/// A Handlebars registry for each parse mode, each set up once with
+/// `ParseMode::prepare` and holding every template.
+struct Registries {
+    plain: Handlebars,
+    html: Handlebars,
+    markdown: Handlebars,
+}
+
+impl Registries {
+    fn new() -> Registries {
+        Registries {
+            plain: Self::prepared(ParseMode::Plain),
+            html: Self::prepared(ParseMode::Html),
+            markdown: Self::prepared(ParseMode::Markdown),
+        }
+    }
+
+    fn prepared(mode: ParseMode) -> Handlebars {
+        let mut registry = Handlebars::new();
+        format::register_helpers(&mut registry);
+        mode.prepare(&mut registry);
+        registry
+    }
+
+    fn get(&self, mode: ParseMode) -> &Handlebars {
+        match mode {
+            ParseMode::Plain => &self.plain,
+            ParseMode::Html => &self.html,
+            ParseMode::Markdown => &self.markdown,
+        }
+    }
+
+    fn all(&mut self) -> [&mut Handlebars; 3] {
+        [&mut self.plain, &mut self.html, &mut self.markdown]
+    }
+}
+
+/// Registry of every template the bot renders.
+///
+/// The built-in templates are always registered. If a template directory is
+/// configured, any `<name>.hbs` file in it replaces the built-in of the same
+/// name, and `reload` picks up changes to those files while running.
+///
+/// Translations are named `<name>.<language>.hbs`, for example
+/// `reply_start.de.hbs` or `reply_start.pt-br.hbs`.
+pub struct Templates {
+    logger: slog::Logger,
+    path: Option<PathBuf>,
+    registries: RefCell<Registries>,
+    modes: RefCell<HashMap<String, ParseMode>>,
+    loaded: RefCell<HashMap<String, SystemTime>>,
+}
+
+impl Templates {
+    pub fn new(logger: slog::Logger, path: Option<&str>) -> Result<Templates> {
+        let templates = Templates {
+            logger: logger,
+            path: path.map(PathBuf::from),
+            registries: RefCell::from(Registries::new()),
+            modes: RefCell::from(HashMap::new()),
+            loaded: RefCell::from(HashMap::new()),
+        };
+
+        for &(name, tpl) in BUILTIN {
+            templates.register(name, tpl)?;
+        }
+
+        if let Some(ref path) = templates.path {
+            if !path.is_dir() {
+                bail!("template path `{}` is not a directory", path.display());
+            }
+        }
+
+        templates.reload();
+
+        Ok(templates)
+    }
+
+    /// Register (or replace) a template, remembering the parse mode it asks
+    /// for.
+    fn register(&self, name: &str, source: &str) -> Result<()> {
+        // `register_template_string` only replaces the existing template once
+        // the new one has compiled successfully, and it compiles the same way
+        // in every registry.
+        for registry in self.registries.borrow_mut().all().iter_mut() {
+            registry.register_template_string(name, source)?;
+        }
+
+        self.modes
+            .borrow_mut()
+            .insert(name.to_owned(), ParseMode::from_source(source));
+
+        Ok(())
+    }
+
+    fn unregister(&self, name: &str) {
+        for registry in self.registries.borrow_mut().all().iter_mut() {
+            registry.unregister_template(name);
+        }
+        self.modes.borrow_mut().remove(name);
+    }
+
+    fn mode_of(&self, name: &str) -> ParseMode {
+        self.modes
+            .borrow()
+            .get(name)
+            .cloned()
+            .unwrap_or(ParseMode::Plain)
+    }
+
+    pub fn render<T: Serialize>(
+        &self,
+        name: &str,
+        data: &T,
+    ) -> Result<Rendered> {
+        let mode = self.mode_of(name);
+
+        let text = self.registries
+            .borrow()
+            .get(mode)
+            .render(name, data)
+            .chain_err(|| ErrorKind::RenderTemplate(name.to_owned()))?;
+
+        Ok(Rendered {
+            text: text,
+            parse_mode: mode,
+        })
+    }
+
+    /// Render a template that isn't registered, such as a chat's own version
+    /// of one of the built-ins.
+    pub fn render_source<T: Serialize>(
+        &self,
+        source: &str,
+        data: &T,
+    ) -> Result<Rendered> {
+        let mode = ParseMode::from_source(source);
+
+        Ok(Rendered {
+            text: self.registries
+                .borrow()
+                .get(mode)
+                .render_template(source, data)?,
+            parse_mode: mode,
+        })
+    }
+
+    /// Log a render error and return the text to send in its place.
+    pub fn fallback(&self, err: Error) -> Rendered {
+        error!(self.logger, "{}", err);
+        for cause in err.iter().skip(1) {
+            error!(self.logger, "caused by: {}", cause);
+        }
+
+        Rendered::plain(FALLBACK.to_owned())
+    }
+
+    /// Check that `source` parses and renders against sample data, without
+    /// registering it.
+    pub fn validate(&self, source: &str) -> Result<()> {
+        self.render_source(source, &sample())?;
+        Ok(())
+    }
+
+    /// Render every registered template against sample data, logging each
+    /// one that fails.
+    pub fn self_test(&self) -> Result<()> {
+        let data = sample();
+
+        let mut names: Vec<String> =
+            self.modes.borrow().keys().cloned().collect();
+        names.sort();
+
+        let mut failed = Vec::new();
+        for name in names {
+            if let Err(e) = self.render(&name, &data) {
+                error!(self.logger, "Template {} failed to render: {}",
+                       name, e);
+                failed.push(name);
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            bail!(ErrorKind::TemplateSelfTest(failed))
+        }
+    }
+
+    /// Render the best available translation of a template, trying the full
+    /// language tag (`pt-br`), then its primary subtag (`pt`), and finally
+    /// the untranslated template.
+    pub fn render_in<T: Serialize>(
+        &self,
+        name: &str,
+        language: &str,
+        data: &T,
+    ) -> Result<Rendered> {
+        let name = self.localized_name(name, language);
+        self.render(&name, data)
+    }
+
+    /// Render a template for a specific chat, using the chat's own version
+    /// from the database if an administrator has set one.
+    pub fn render_for_chat<T: Serialize>(
+        &self,
+        db: &PgConnection,
+        chat_id: i64,
+        name: &str,
+        language: &str,
+        data: &T,
+    ) -> Result<Rendered> {
+        let custom: Option<String> = {
+            use schema::chat_templates::dsl;
+
+            dsl::chat_templates
+                .find((chat_id, name))
+                .select(dsl::body)
+                .first(db)
+                .optional()?
+        };
+
+        let custom = custom.as_ref().map(String::as_str);
+        self.render_override(name, custom, language, data)
+    }
+
+    /// Render a chat's own version of a template if it has one, or the best
+    /// available translation of the shared one otherwise.
+    fn render_override<T: Serialize>(
+        &self,
+        name: &str,
+        custom: Option<&str>,
+        language: &str,
+        data: &T,
+    ) -> Result<Rendered> {
+        match custom {
+            Some(body) => self.render_source(body, data)
+                .chain_err(|| ErrorKind::RenderTemplate(name.to_owned())),
+            None => self.render_in(name, language, data),
+        }
+    }
+
+    fn localized_name(&self, name: &str, language: &str) -> String {
+        let modes = self.modes.borrow();
+        let language = language.to_lowercase();
+
+        let mut candidates = vec![format!("{}.{}", name, language)];
+        if let Some(idx) = language.find('-') {
+            candidates.push(format!("{}.{}", name, &language[..idx]));
+        }
+
+        candidates
+            .into_iter()
+            .find(|x| modes.contains_key(x))
+            .unwrap_or_else(|| name.to_owned())
+    }
+
+    /// Scan the template directory, registering new or modified files and
+    /// falling back to the built-in version of any file that was removed.
+    ///
+    /// Files that fail to parse are logged and skipped, leaving whichever
+    /// version was previously registered in place.
+    pub fn reload(&self) {
+        let path = match self.path {
+            Some(ref x) => x,
+            None => return,
+        };
+
+        let entries = match fs::read_dir(path) {
+            Ok(x) => x,
+            Err(e) => {
+                error!(self.logger, "unable to read template path: {}", e);
+                return;
+            }
+        };
+
+        let mut seen = Vec::new();
+
+        for entry in entries {
+            let entry_path = match entry {
+                Ok(x) => x.path(),
+                Err(e) => {
+                    error!(self.logger, "unable to read template entry: {}", e);
+                    continue;
+                }
+            };
+
+            if entry_path.extension() != Some(OsStr::new(EXTENSION)) {
+                continue;
+            }
+
+            let name = match entry_path.file_stem().and_then(OsStr::to_str) {
+                Some(x) => x.to_owned(),
+                None => continue,
+            };
+
+            let modified = match fs::metadata(&entry_path)
+                .and_then(|x| x.modified())
+            {
+                Ok(x) => x,
+                Err(e) => {
+                    error!(self.logger, "unable to stat template {}: {}",
+                           entry_path.display(), e);
+                    continue;
+                }
+            };
+
+            seen.push(name.clone());
+
+            if self.loaded.borrow().get(&name) == Some(&modified) {
+                continue;
+            }
+
+            // Record the attempt even on failure, so a broken file is only
+            // reported once per modification.
+            self.loaded.borrow_mut().insert(name.clone(), modified);
+
+            match self.load_file(&name, &entry_path) {
+                Ok(()) => info!(self.logger, "Loaded template: {}", name),
+                Err(e) => error!(self.logger, "Rejected template {}: {}",
+                                 entry_path.display(), e),
+            }
+        }
+
+        let removed: Vec<String> = self.loaded
+            .borrow()
+            .keys()
+            .filter(|x| !seen.contains(x))
+            .cloned()
+            .collect();
+
+        for name in removed {
+            self.loaded.borrow_mut().remove(&name);
+
+            match builtin(&name) {
+                Some(tpl) => {
+                    self.register(&name, tpl).ok();
+                }
+                None => self.unregister(&name),
+            }
+
+            info!(self.logger, "Unloaded template: {}", name);
+        }
+    }
+
+    fn load_file(&self, name: &str, path: &Path) -> Result<()> {
+        let mut source = String::new();
+        fs::File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut source))
+            .chain_err(|| "unable to read template")?;
+
+        self.validate(&source)?;
+        self.register(name, &source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates() -> Templates {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        Templates::new(logger, None).unwrap()
+    }
+
+    #[test]
+    fn renders_the_closest_translation() {
+        let tpl = templates();
+        tpl.register("reply_start.pt", "Olá!").unwrap();
+        tpl.register("reply_start.pt-br", "Oi!").unwrap();
+        tpl.register("reply_start.de", "Hallo!").unwrap();
+
+        let text = |language| {
+            tpl.render_in(REPLY_START, language, &sample()).unwrap().text
+        };
+
+        assert_eq!(text("pt-BR"), "Oi!");
+        assert_eq!(text("pt-PT"), "Olá!");
+        assert_eq!(text("de-AT"), "Hallo!");
+        assert!(text("fr").starts_with("Hello!"));
+    }
+
+    #[test]
+    fn prefers_a_chats_own_version() {
+        let tpl = templates();
+        let custom = Some("{{!-- parse_mode: html --}}<b>{{group}}</b>");
+
+        let text = tpl.render_override(JOIN, custom, "en", &sample())
+            .unwrap();
+        assert_eq!(text.text, "<b>Example Group</b>");
+        assert_eq!(text.parse_mode, ParseMode::Html);
+
+        let text = tpl.render_override(JOIN, None, "en", &sample()).unwrap();
+        assert!(text.text.starts_with("Hey!"));
+    }
+
+    #[test]
+    fn names_the_template_a_chats_version_broke() {
+        let tpl = templates();
+        let custom = Some("{{#if group}}unclosed");
+
+        let err = tpl.render_override(JOIN, custom, "en", &sample())
+            .unwrap_err();
+        match *err.kind() {
+            ErrorKind::RenderTemplate(ref x) => assert_eq!(x, JOIN),
+            ref x => panic!("unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn builtins_pass_the_self_test() {
+        assert!(templates().self_test().is_ok());
+    }
+
+    #[test]
+    fn falls_back_to_the_error_reply() {
+        let tpl = templates();
+
+        let err = tpl.render("missing", &sample()).unwrap_err();
+        let text = tpl.fallback(err);
+
+        assert_eq!(text.text, FALLBACK);
+        assert_eq!(text.parse_mode, ParseMode::Plain);
+    }
+
+    #[test]
+    fn rejects_templates_that_do_not_render() {
+        let tpl = templates();
 
-    Ok(())
+        assert!(tpl.validate("{{group}} {{#each chats}}{{title}}{{/each}}")
+            .is_ok());
+        assert!(tpl.validate("{{#if group}}unclosed").is_err());
+    }
 }
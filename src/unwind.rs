@@ -0,0 +1,49 @@
+use errors::*;
+
+use futures::{Future, Poll};
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+/// The message a panic was started with, if it had one.
+fn message(payload: &Box<Any + Send>) -> String {
+    if let Some(x) = payload.downcast_ref::<&'static str>() {
+        return (*x).to_owned();
+    }
+
+    if let Some(x) = payload.downcast_ref::<String>() {
+        return x.clone();
+    }
+
+    "no message".to_owned()
+}
+
+/// Run `f`, turning a panic into an `ErrorKind::Panicked` error.
+///
+/// Everything here runs on one thread, and a panicking handler only leaves
+/// behind state belonging to the update it was handling, so unwind safety is
+/// asserted rather than required.
+pub fn call<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T,
+{
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .map_err(|x| ErrorKind::Panicked(message(&x)).into())
+}
+
+/// Fails with `ErrorKind::Panicked` if polling the wrapped future panics.
+pub struct Guarded<F>(F);
+
+pub fn guard<F: Future<Error = Error>>(future: F) -> Guarded<F> {
+    Guarded(future)
+}
+
+impl<F: Future<Error = Error>> Future for Guarded<F> {
+    type Item = F::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<F::Item, Error> {
+        let inner = &mut self.0;
+        call(move || inner.poll()).and_then(|x| x)
+    }
+}